@@ -0,0 +1,120 @@
+//! Throughput of `get_everything`, the account with its liked and watched
+//! videos that `GET /user/{id}` answers, under concurrent requests:
+//! `db_actions::get_everything` through the blocking pool and r2d2, and
+//! `db_async::get_everything` through diesel-async.
+//!
+//! ```bash
+//! DATABASE_URL=postgres://localhost/actix_test cargo bench --features async-db
+//! ```
+//!
+//! The data lives in a schema of its own, migrated from scratch and dropped
+//! afterwards, like the integration tests. The pools get half of
+//! `database.pool_size` each, as in the server, and run on one
+//! current-thread runtime, like a single server worker.
+
+use std::env;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use diesel::prelude::*;
+use dotenv::dotenv;
+use futures_util::future::join_all;
+use tokio::runtime::{Builder, Runtime};
+use actix_diesel::{
+    DbPool, connect,
+    auth::Credentials,
+    db_actions,
+    db_async::{self, AsyncDbPool},
+    metrics::block,
+    migrations::run_migrations,
+    models::{VideoForm, VideoList, WatchProgress},
+    settings::DatabaseSettings,
+    ultils::utils::generate_key,
+};
+
+/// Requests in flight at once.
+const CONCURRENCY: usize = 64;
+const VIDEOS: usize = 20;
+
+async fn through_r2d2(pool: &DbPool, id: i32) -> usize {
+    let pool = pool.clone();
+    let data = block(move || db_actions::get_everything(&mut *pool.get()?, id))
+        .await
+        .unwrap()
+        .unwrap();
+    data.liked_videos.len() + data.watched_videos.len()
+}
+
+async fn through_diesel_async(pool: &AsyncDbPool, id: i32) -> usize {
+    let data = db_async::get_everything(&mut pool.get().await.unwrap(), id).await.unwrap();
+    data.liked_videos.len() + data.watched_videos.len()
+}
+
+/// An account that liked half of `VIDEOS` new videos and started the rest.
+fn seed(conn: &mut PgConnection) -> i32 {
+    let creds = Credentials {
+        email: String::from("bench@example.com"),
+        password: String::from("bench password"),
+    };
+    let user = db_actions::create_user(conn, creds, 4).unwrap();
+    for index in 0..VIDEOS {
+        let video = db_actions::create_video(conn, VideoForm {
+            title: format!("Bench video {}", index),
+            description: String::new(),
+            duration: 600,
+            release_year: None,
+            maturity_rating: None,
+        }).unwrap();
+        if index % 2 == 0 {
+            db_actions::create_liked_videos(conn, user.id, video.id, VideoList::Liked).unwrap();
+        } else {
+            db_actions::upsert_watch_progress(conn, user.id, video.id, WatchProgress { position: 60, duration: 600 }).unwrap();
+        }
+    }
+    user.id
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().expect("Failed to build runtime")
+}
+
+fn bench_get_everything(c: &mut Criterion) {
+    dotenv().ok();
+    let Ok(database_url) = env::var("DATABASE_URL") else {
+        eprintln!("DATABASE_URL is not set, skipping the get_everything benchmark");
+        return;
+    };
+    let schema = format!("bench_{}", generate_key().to_lowercase());
+    let mut admin = PgConnection::establish(&database_url).expect("Failed to connect to DATABASE_URL");
+    diesel::sql_query(format!("CREATE SCHEMA {}", schema))
+        .execute(&mut admin)
+        .expect("Failed to create bench schema");
+
+    // Unqualified names, the migrations' included, resolve to the schema.
+    let separator = if database_url.contains('?') { '&' } else { '?' };
+    let settings = DatabaseSettings {
+        url: format!("{}{}options=-csearch_path%3D{}", database_url, separator, schema),
+        ..DatabaseSettings::default()
+    };
+    let pool = connect(&settings);
+    run_migrations(&mut pool.get().unwrap()).expect("Failed to migrate bench schema");
+    let user_id = seed(&mut pool.get().unwrap());
+    let async_pool = db_async::connect(&settings);
+
+    let mut group = c.benchmark_group("get_everything");
+    group.throughput(Throughput::Elements(CONCURRENCY as u64));
+    group.bench_function(BenchmarkId::new("r2d2", CONCURRENCY), |b| {
+        b.to_async(runtime()).iter(|| join_all((0..CONCURRENCY).map(|_| through_r2d2(&pool, user_id))));
+    });
+    group.bench_function(BenchmarkId::new("diesel-async", CONCURRENCY), |b| {
+        b.to_async(runtime()).iter(|| join_all((0..CONCURRENCY).map(|_| through_diesel_async(&async_pool, user_id))));
+    });
+    group.finish();
+
+    drop(pool);
+    drop(async_pool);
+    diesel::sql_query(format!("DROP SCHEMA {} CASCADE", schema))
+        .execute(&mut admin)
+        .expect("Failed to drop bench schema");
+}
+
+criterion_group!(benches, bench_get_everything);
+criterion_main!(benches);
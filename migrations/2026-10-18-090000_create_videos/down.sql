@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE liked_videos
+    DROP CONSTRAINT liked_videos_video_id_fkey,
+    ADD COLUMN title TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE watched_videos
+    DROP CONSTRAINT watched_videos_video_id_fkey,
+    ADD COLUMN title TEXT NOT NULL DEFAULT '';
+
+UPDATE liked_videos SET title = videos.title FROM videos WHERE videos.id = liked_videos.video_id;
+UPDATE watched_videos SET title = videos.title FROM videos WHERE videos.id = watched_videos.video_id;
+
+ALTER TABLE liked_videos ALTER COLUMN title DROP DEFAULT;
+ALTER TABLE watched_videos ALTER COLUMN title DROP DEFAULT;
+
+DROP TABLE videos;
@@ -0,0 +1,36 @@
+-- Your SQL goes here
+
+CREATE TABLE videos (
+    id SERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    duration INT NOT NULL DEFAULT 0,
+    release_year INT,
+    maturity_rating VARCHAR(10),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Seed the catalog from the titles already stored on the join tables,
+-- keeping the video ids they reference.
+INSERT INTO videos (id, title)
+SELECT DISTINCT ON (video_id) video_id, title
+FROM (
+    SELECT video_id, title FROM liked_videos
+    UNION ALL
+    SELECT video_id, title FROM watched_videos
+) existing
+ORDER BY video_id;
+
+SELECT setval(
+    pg_get_serial_sequence('videos', 'id'),
+    COALESCE((SELECT MAX(id) FROM videos), 0) + 1,
+    false
+);
+
+ALTER TABLE liked_videos
+    DROP COLUMN title,
+    ADD FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE;
+
+ALTER TABLE watched_videos
+    DROP COLUMN title,
+    ADD FOREIGN KEY (video_id) REFERENCES videos(id) ON DELETE CASCADE;
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX watched_videos_user_id_last_watched_at_idx;
+
+ALTER TABLE watched_videos
+    DROP CONSTRAINT watched_videos_user_id_video_id_key,
+    DROP COLUMN position,
+    DROP COLUMN duration,
+    DROP COLUMN completed,
+    DROP COLUMN last_watched_at;
@@ -0,0 +1,26 @@
+-- Your SQL goes here
+
+-- Progress is tracked once per user and video, so collapse duplicates first.
+DELETE FROM watched_videos a
+USING watched_videos b
+WHERE a.user_id = b.user_id
+  AND a.video_id = b.video_id
+  AND a.id < b.id;
+
+ALTER TABLE watched_videos
+    ADD COLUMN position INT NOT NULL DEFAULT 0,
+    ADD COLUMN duration INT NOT NULL DEFAULT 0,
+    ADD COLUMN completed BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN last_watched_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    ADD CONSTRAINT watched_videos_user_id_video_id_key UNIQUE (user_id, video_id);
+
+-- Rows recorded before progress tracking only meant "watched".
+UPDATE watched_videos
+SET completed = TRUE,
+    duration = videos.duration,
+    position = videos.duration
+FROM videos
+WHERE videos.id = watched_videos.video_id;
+
+CREATE INDEX watched_videos_user_id_last_watched_at_idx
+    ON watched_videos (user_id, last_watched_at DESC);
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE password_reset_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    token_hash CHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+     FOREIGN KEY (user_id) REFERENCES users(Id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+-- Addresses are stored trimmed and lowercased from now on. If two accounts
+-- only differ by case this fails, and they have to be merged by hand first.
+UPDATE users SET email = LOWER(TRIM(email));
+
+CREATE UNIQUE INDEX users_email_lower_key ON users (LOWER(email));
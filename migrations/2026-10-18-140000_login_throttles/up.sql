@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE login_throttles (
+    scope VARCHAR(16) NOT NULL,
+    subject TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP,
+    last_attempt_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (scope, subject)
+);
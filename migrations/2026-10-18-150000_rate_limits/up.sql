@@ -0,0 +1,8 @@
+-- Your SQL goes here
+
+CREATE TABLE rate_limits (
+    key TEXT PRIMARY KEY,
+    value DOUBLE PRECISION NOT NULL,
+    previous DOUBLE PRECISION NOT NULL,
+    stamp_ms BIGINT NOT NULL
+);
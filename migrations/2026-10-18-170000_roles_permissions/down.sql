@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE users ADD COLUMN role VARCHAR(5) DEFAULT 'User';
+UPDATE users SET role = 'ADMIN' WHERE role_id = (SELECT id FROM roles WHERE name = 'admin');
+ALTER TABLE users DROP COLUMN role_id;
+
+DROP TABLE role_permissions;
+DROP TABLE permissions;
+DROP TABLE roles;
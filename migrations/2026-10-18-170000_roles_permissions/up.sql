@@ -0,0 +1,43 @@
+-- Your SQL goes here
+
+-- A role has every permission of its parent, so admin > moderator > user.
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(32) NOT NULL UNIQUE,
+    parent_id INT REFERENCES roles(id)
+);
+
+CREATE TABLE permissions (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(64) NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE role_permissions (
+    role_id INT NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    permission_id INT NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+-- New accounts default to the `user` role, so it keeps id 1.
+INSERT INTO roles (id, name, parent_id) VALUES
+    (1, 'user', NULL),
+    (2, 'moderator', 1),
+    (3, 'admin', 2);
+SELECT setval('roles_id_seq', (SELECT MAX(id) FROM roles));
+
+INSERT INTO permissions (name, description) VALUES
+    ('videos:read', 'List the video catalog'),
+    ('videos:write', 'Add, change and remove catalog entries'),
+    ('users:read', 'Look up any account'),
+    ('users:write', 'Unlock accounts'),
+    ('roles:assign', 'List roles and assign them to accounts');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT 2, id FROM permissions WHERE name IN ('videos:read', 'videos:write');
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT 3, id FROM permissions WHERE name IN ('users:read', 'users:write', 'roles:assign');
+
+ALTER TABLE users ADD COLUMN role_id INT NOT NULL DEFAULT 1 REFERENCES roles(id);
+UPDATE users SET role_id = 3 WHERE role = 'ADMIN';
+ALTER TABLE users DROP COLUMN role;
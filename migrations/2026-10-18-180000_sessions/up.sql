@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    key_hash CHAR(64) NOT NULL UNIQUE,
+    user_id INT,
+    state TEXT NOT NULL,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP NOT NULL,
+     FOREIGN KEY (user_id) REFERENCES users(Id) ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
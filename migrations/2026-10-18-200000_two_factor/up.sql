@@ -0,0 +1,23 @@
+-- Your SQL goes here
+
+-- The secret is kept in the clear, since codes are derived from it. It only
+-- counts once confirmed_at is set.
+CREATE TABLE totp_secrets (
+    user_id INT PRIMARY KEY,
+    secret VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    confirmed_at TIMESTAMP,
+    -- Time step of the last accepted code, so a code cannot be replayed
+    last_used_step BIGINT,
+     FOREIGN KEY (user_id) REFERENCES users(Id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INT NOT NULL,
+    code_hash CHAR(64) NOT NULL,
+    used_at TIMESTAMP,
+     FOREIGN KEY (user_id) REFERENCES users(Id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+DELETE FROM permissions WHERE name = 'system:status';
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+INSERT INTO permissions (name, description) VALUES
+    ('system:status', 'See the pool, version and uptime of the server');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT 3, id FROM permissions WHERE name = 'system:status';
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX rate_limits_expires_ms_idx;
+ALTER TABLE rate_limits DROP COLUMN expires_ms;
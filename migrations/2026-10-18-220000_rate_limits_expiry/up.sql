@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+-- Counters are worthless once two windows have passed without hits. Rows
+-- from before this column get 0 and are swept right away.
+ALTER TABLE rate_limits ADD COLUMN expires_ms BIGINT NOT NULL DEFAULT 0;
+CREATE INDEX rate_limits_expires_ms_idx ON rate_limits (expires_ms);
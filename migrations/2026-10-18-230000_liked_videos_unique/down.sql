@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE liked_videos DROP CONSTRAINT liked_videos_user_id_video_id_key;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+-- A video is liked once per user, so collapse duplicates first.
+DELETE FROM liked_videos a
+USING liked_videos b
+WHERE a.user_id = b.user_id
+  AND a.video_id = b.video_id
+  AND a.id < b.id;
+
+ALTER TABLE liked_videos
+    ADD CONSTRAINT liked_videos_user_id_video_id_key UNIQUE (user_id, video_id);
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use actix_web::{
+    HttpResponse, web, get, post, delete,
+};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::AuthenticatedUser;
+use crate::models::{API_KEY_SCOPES, CreateApiKey, NewApiKey};
+use crate::ultils::utils::{generate_key, hash_token};
+
+/// Characters of a key kept in the clear so users can tell their keys apart.
+const KEY_PREFIX_LENGTH: usize = 8;
+
+
+#[utoipa::path(
+    request_body = CreateApiKey,
+    responses(
+        (
+            status = 201,
+            description = "Creates an API key, the key itself is only shown in this response",
+            body = NewApiKey
+        ),
+        (
+            status = 400,
+            description = "Missing name, unknown scope or bad expiry",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Unknown scope")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[post("/me/api-keys")]
+pub async fn create_api_key(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    form: web::Json<CreateApiKey>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let CreateApiKey { name, scopes, expires_in_days } = form.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(AppError::Validation(String::from("API key name is required")));
+    }
+    if let Some(scope) = scopes.iter().find(|scope| !API_KEY_SCOPES.contains(&scope.as_str())) {
+        return Err(AppError::Validation(format!("Unknown scope: {}", scope)));
+    }
+    if expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(AppError::Validation(String::from("expires_in_days must be positive")));
+    }
+
+    let key = generate_key();
+    let key_prefix = key[..KEY_PREFIX_LENGTH].to_string();
+    let key_hash = hash_token(&key);
+    let api_key = state.api_keys.create(user_id, &name, &key_prefix, &key_hash, &scopes, expires_in_days).await?;
+
+    Ok(HttpResponse::Created().json(NewApiKey { key, api_key }))
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Fetches the API keys of the logged in user that are not revoked",
+            body = [UserApiKey]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[get("/me/api-keys")]
+pub async fn list_api_keys(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let keys = state.api_keys.list(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 204,
+            description = "Revokes an API key of the logged in user",
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 404,
+            description = "API key Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("API key not found")).problem())
+        ),
+    )
+)]
+#[delete("/me/api-keys/{id}")]
+pub async fn revoke_api_key(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let key_id = path.into_inner();
+    let revoked = state.api_keys.revoke(user_id, key_id).await?;
+
+    if revoked == 0 {
+        Err(AppError::NotFound(String::from("API key not found")))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
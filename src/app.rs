@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use actix_cors::Cors;
+use actix_identity::IdentityMiddleware;
+use actix_session::{
+    config::{PersistentSession, CookieContentSecurity},
+    SessionMiddleware
+};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Key, SameSite},
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    web, App, Error,
+};
+use cookie::time::Duration;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::{
+    AppState,
+    api_keys::{self, create_api_key, list_api_keys, revoke_api_key},
+    auth::{self, sign_up, login, logout, forgot_password, reset_password, verify_email, resend_verification},
+    errors,
+    health::{self, healthz, readyz, status},
+    metrics::{self, RequestMetrics, metrics_endpoint},
+    migrations::{self, get_migration_status},
+    models,
+    rate_limit::RateLimiter,
+    roles::{self, list_roles, assign_role},
+    session_store::RepositorySessionStore,
+    sessions::{self, list_sessions, revoke_session, revoke_all_sessions},
+    telemetry::RequestTracing,
+    tokens::{self, token, revoke_token},
+    two_factor::{self, login_second_factor, enroll_two_factor, confirm_two_factor, disable_second_factor},
+    users::{self, user_data, unlock_user},
+    videos::{
+        self,
+        like_video, liked_videos, unlike_video,
+        watch_video, watched_videos, unwatch_video,
+        update_watch_progress, continue_watching,
+        list_videos, create_video, update_video, delete_video
+    }
+};
+
+/// Registers the `X-API-Key` header scheme referenced by handlers that take
+/// API keys, and the bearer scheme of the token mode.
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+            );
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        auth::sign_up,
+        auth::login,
+        auth::logout,
+        auth::forgot_password,
+        auth::reset_password,
+        auth::verify_email,
+        auth::resend_verification,
+        two_factor::login_second_factor,
+        two_factor::enroll_two_factor,
+        two_factor::confirm_two_factor,
+        two_factor::disable_second_factor,
+        tokens::token,
+        tokens::revoke_token,
+        api_keys::create_api_key,
+        api_keys::list_api_keys,
+        api_keys::revoke_api_key,
+        sessions::list_sessions,
+        sessions::revoke_session,
+        sessions::revoke_all_sessions,
+        users::user_data,
+        users::unlock_user,
+        migrations::get_migration_status,
+        health::healthz,
+        health::readyz,
+        health::status,
+        metrics::metrics_endpoint,
+        roles::list_roles,
+        roles::assign_role,
+        videos::like_video,
+        videos::liked_videos,
+        videos::unlike_video,
+        videos::watch_video,
+        videos::watched_videos,
+        videos::unwatch_video,
+        videos::update_watch_progress,
+        videos::continue_watching,
+        videos::list_videos,
+        videos::create_video,
+        videos::update_video,
+        videos::delete_video
+    ),
+    components (
+        schemas(
+            models::LikedVideos,
+            models::WatchedVideos,
+            models::UserWithVideos,
+            models::User,
+            models::Role,
+            models::RoleWithPermissions,
+            models::AssignRole,
+            models::Video,
+            models::VideoForm,
+            models::WatchProgress,
+            models::ContinueWatching,
+            models::VideoRef,
+            models::UserApiKey,
+            models::CreateApiKey,
+            models::NewApiKey,
+            models::UserSession,
+            errors::ProblemDetails,
+            migrations::MigrationStatus,
+            health::Health,
+            health::PoolStatus,
+            health::ServerStatus,
+            auth::Credentials,
+            auth::ForgotPassword,
+            auth::ResetPassword,
+            auth::ResendVerification,
+            tokens::TokenRequest,
+            tokens::TokenResponse,
+            tokens::RevokeToken,
+            two_factor::TwoFactorCode,
+            two_factor::TotpEnrollment,
+            two_factor::RecoveryCodes
+        )
+    ),
+    modifiers(&SecurityAddon)
+)]
+pub struct ApiDoc;
+
+/// Registers the state, the error handlers of the extractors, every route
+/// and the Swagger UI.
+pub fn configure_app(cfg: &mut web::ServiceConfig, state: Arc<AppState>) {
+    cfg.app_data(web::Data::new(state))
+        .app_data(web::JsonConfig::default().error_handler(errors::validation_error))
+        .app_data(web::QueryConfig::default().error_handler(errors::validation_error))
+        .app_data(web::PathConfig::default().error_handler(errors::validation_error))
+        .service(sign_up)
+        .service(login)
+        .service(logout)
+        .service(forgot_password)
+        .service(reset_password)
+        .service(verify_email)
+        .service(resend_verification)
+        .service(login_second_factor)
+        .service(enroll_two_factor)
+        .service(confirm_two_factor)
+        .service(disable_second_factor)
+        .service(token)
+        .service(revoke_token)
+        .service(create_api_key)
+        .service(list_api_keys)
+        .service(revoke_api_key)
+        .service(list_sessions)
+        .service(revoke_session)
+        .service(revoke_all_sessions)
+        .service(user_data)
+        .service(unlock_user)
+        .service(get_migration_status)
+        .service(healthz)
+        .service(readyz)
+        .service(status)
+        .service(metrics_endpoint)
+        .service(list_roles)
+        .service(assign_role)
+        .service(like_video)
+        .service(liked_videos)
+        .service(unlike_video)
+        .service(watch_video)
+        .service(watched_videos)
+        .service(unwatch_video)
+        .service(update_watch_progress)
+        .service(continue_watching)
+        .service(list_videos)
+        .service(create_video)
+        .service(update_video)
+        .service(delete_video)
+        .service(
+            SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-doc/openapi.json", ApiDoc::openapi()),
+        );
+}
+
+/// The application the server runs, one per worker: `configure_app` behind
+/// the rate limiter, identity, session, CORS, metrics and tracing
+/// middleware. The integration tests build it too, so they go through the
+/// same stack as production requests.
+pub fn build_app(
+    state: Arc<AppState>,
+    key: Key,
+    rate_limiter: RateLimiter
+)
+-> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    let cors = state.settings.cors.allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        })
+        .allow_any_method()
+        .expose_any_header();
+    let session_ttl = Duration::hours(state.settings.session.ttl_hours);
+
+    App::new()
+        .wrap(rate_limiter)
+        .wrap(IdentityMiddleware::default())
+        .wrap(
+            SessionMiddleware::builder(RepositorySessionStore::new(state.sessions.clone()), key)
+                .cookie_http_only(true)
+                .cookie_content_security(CookieContentSecurity::Private)
+                .cookie_same_site(SameSite::Strict)
+                .session_lifecycle(PersistentSession::default().session_ttl(session_ttl))
+                .build(),
+        )
+        .wrap(cors)
+        .wrap(RequestMetrics)
+        .wrap(RequestTracing)
+        .configure(|cfg| configure_app(cfg, state))
+}
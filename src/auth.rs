@@ -1,12 +1,26 @@
 use std::sync::Arc;
 use actix_identity::Identity;
 use actix_session::Session;
-use actix_web::{HttpResponse, web, Responder, Result, HttpRequest, HttpMessage, post};
+use actix_web::{HttpResponse, web, HttpRequest, HttpMessage, get, post};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 use crate::AppState;
-use crate::db_actions::{authenticate, create_user, validate_email};
-use crate::models::SwaggerErrorResponse;
+use crate::db_actions::normalize_email;
+use crate::errors::AppError;
+use crate::metrics::{block, metrics};
+use crate::session_store::remember_device;
+use crate::models::User;
+use crate::repository::UserRepository;
+use crate::throttle::{ThrottleScope, client_ip, too_many_requests};
+use crate::two_factor::begin_second_factor;
+use crate::ultils::utils::{generate_key, hash_token};
+
+/// How long a password reset token stays valid.
+const RESET_TOKEN_TTL_MINUTES: i32 = 60;
+/// How long an email verification token stays valid.
+const VERIFY_TOKEN_TTL_MINUTES: i32 = 60 * 24;
+/// Minimum wait between two verification mails for the same account.
+const VERIFY_RESEND_COOLDOWN_MINUTES: i32 = 5;
 
 
 #[derive(Debug, Deserialize,Serialize,Clone, ToSchema)]
@@ -15,6 +29,97 @@ pub struct Credentials {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize,Serialize,Clone, ToSchema)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize,Serialize,Clone, ToSchema)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize,Serialize,Clone, ToSchema)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+pub(crate) enum LoginOutcome {
+    Success(User),
+    /// The password was right, but the account also needs a TOTP or recovery code
+    SecondFactorRequired(User),
+    Failed,
+    Throttled(i64),
+}
+
+/// Checks credentials against the account and address throttles, recording
+/// failures and clearing the account counter on success. `creds.email` must
+/// already be normalized.
+pub(crate) async fn check_login(
+    users: &dyn UserRepository,
+    creds: Credentials,
+    ip: &str,
+    bcrypt_cost: u32
+)
+-> Result<LoginOutcome, anyhow::Error> {
+    let locked = [
+        users.throttle_remaining(ThrottleScope::Account, &creds.email).await?,
+        users.throttle_remaining(ThrottleScope::Ip, ip).await?,
+    ]
+    .into_iter()
+    .flatten()
+    .max();
+    if let Some(retry_after) = locked {
+        return Ok(LoginOutcome::Throttled(retry_after));
+    }
+
+    let email = creds.email.clone();
+    match users.authenticate(creds, bcrypt_cost).await? {
+        Some(user) => {
+            users.clear_throttle(ThrottleScope::Account, &email).await?;
+            if users.has_two_factor(user.id).await? {
+                Ok(LoginOutcome::SecondFactorRequired(user))
+            } else {
+                Ok(LoginOutcome::Success(user))
+            }
+        }
+        None => {
+            users.record_attempt(ThrottleScope::Account, &email).await?;
+            users.record_attempt(ThrottleScope::Ip, ip).await?;
+            metrics().failed_logins.inc();
+            Ok(LoginOutcome::Failed)
+        }
+    }
+}
+
+async fn send_verification_mail(
+    state: &Arc<AppState>,
+    user: &User
+)
+-> Result<(), anyhow::Error> {
+    let token = generate_key();
+    state.users.create_email_verification(user.id, &hash_token(&token), VERIFY_TOKEN_TTL_MINUTES).await?;
+    let state = state.clone();
+    let email = user.email.clone();
+    block(move || {
+        state.mailer.send(
+            &email,
+            "Verify your email",
+            &format!(
+                "Open /verify-email?token={} to verify your email.\nThe link expires in {} hours.",
+                token, VERIFY_TOKEN_TTL_MINUTES / 60
+            ),
+        )
+    })
+    .await?
+}
+
 #[utoipa::path(
     request_body = Credentials,
     responses(
@@ -26,34 +131,48 @@ pub struct Credentials {
         (
             status = 406,
             description = "Email Provided is not valid",
-            body = SwaggerErrorResponse,
-            example = json!(SwaggerErrorResponse::Conflict(String::from("Please Double check email, make sure it's valid")))
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::InvalidEmail.problem())
+        ),
+        (
+            status = 409,
+            description = "An account already uses this email",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Conflict(String::from("An account with this email already exists")).problem())
+        ),
+        (
+            status = 429,
+            description = "Too many sign ups from this address, see the Retry-After header",
         )
     )
 )]
 #[post("/signup")]
 pub async fn sign_up(
     creds: web::Json<Credentials>,
-    session: Session,
     state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
 )
--> Result<impl Responder> {
-    let creds = creds.into_inner();
+-> Result<HttpResponse, AppError> {
+    let mut creds = creds.into_inner();
+    creds.email = normalize_email(&creds.email)?;
 
+    let ip = client_ip(&req);
+    if let Some(retry_after) = state.users.throttle_remaining(ThrottleScope::Signup, &ip).await? {
+        return Err(too_many_requests(retry_after));
+    }
+    state.users.record_attempt(ThrottleScope::Signup, &ip).await?;
 
-    let resp = web::block(move || {
-        let mut conn = state.pool.get()?;
-        create_user(&mut conn, creds)
-    })
-    .await?;
+    let user = state.users.create(creds, state.settings.auth.bcrypt_cost).await
+        .map_err(|err| match AppError::from(err) {
+            AppError::Conflict(_) => AppError::Conflict(String::from("An account with this email already exists")),
+            err => err,
+        })?;
+    send_verification_mail(&state, &user).await?;
 
-    if let Ok(user) = resp {
-        session.insert("user", user.id.clone()).unwrap();
-        session.insert("role", user.role.clone()).unwrap();
-        Ok(HttpResponse::Created().json(user))
-    } else {
-        Ok(HttpResponse::InternalServerError().body("Internal Server Error!"))
-    }
+    metrics().signups.inc();
+    Ok(HttpResponse::Created().json(user))
 }
 
 #[utoipa::path(
@@ -64,10 +183,33 @@ pub async fn sign_up(
             description = "Log in a user",
         ),
         (
-            status = 404,
-            description = "User Not Found",
-            body = SwaggerErrorResponse,
-            example = json!(SwaggerErrorResponse::NotFound(String::from("User Not Found")))
+            status = 202,
+            description = "Password is right, finish logging in with a second factor at /login/2fa",
+        ),
+        (
+            status = 401,
+            description = "Email or password is wrong",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Invalid email or password")).problem())
+        ),
+        (
+            status = 403,
+            description = "Email address is not verified yet",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 406,
+            description = "Email Provided is not valid",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 429,
+            description = "Too many failed logins for this account or address, see the Retry-After header",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
         ),
     )
 )]
@@ -78,40 +220,32 @@ pub async fn login(
     req: HttpRequest,
     session: Session
 )
--> Result<impl Responder> {
-    let creds = creds.into_inner();
-    let cred_two = creds.clone();
-    if validate_email(&creds.email).is_ok() {
-        let resp = web::block(move || {
-            let mut conn = state.pool.get()?;
-            authenticate(creds, &mut conn)
-        })
-        .await?;
-        
-        if let Ok(user) = resp {
-            let hash_check = bcrypt::verify(cred_two.password, &user.password_hash);
-            match hash_check.is_ok() {
-                true => {
-                    Identity::login(&req.extensions(), user.email.into()).unwrap();
-                    // let cookie = CookieBuilder::new("role", user.role.unwrap())
-                    //     .secure(true)
-                    //     .http_only(true)
-                    //     .finish();
-                    session.insert("role", user.role).unwrap();
-                    Ok(HttpResponse::Ok().body("Back In Action!"))
-
-                },
-                false => {
-                    Ok(HttpResponse::NotFound().body("User not found, please double check credintails"))
-                }
-            }
-        } else {
-            Ok(HttpResponse::NotFound().body("User not found, please double check credintails"))
+-> Result<HttpResponse, AppError> {
+    let mut creds = creds.into_inner();
+    creds.email = normalize_email(&creds.email)?;
+    let require_verified = state.settings.auth.require_email_verification;
+    let ip = client_ip(&req);
+
+    let outcome = check_login(state.users.as_ref(), creds, &ip, state.settings.auth.bcrypt_cost).await?;
+
+    match outcome {
+        LoginOutcome::Success(user) | LoginOutcome::SecondFactorRequired(user)
+            if require_verified && user.email_verified_at.is_none() => {
+            Err(AppError::Forbidden(String::from("Please verify your email before logging in")))
         }
-    } else {
-        Ok(HttpResponse::NotAcceptable().body("Email Provided was invalid!"))
+        LoginOutcome::Success(user) => {
+            Identity::login(&req.extensions(), user.email)?;
+            remember_device(&session, &req, user.id)?;
+            metrics().logins.inc();
+            Ok(HttpResponse::Ok().body("Back In Action!"))
+        }
+        LoginOutcome::SecondFactorRequired(user) => {
+            begin_second_factor(&session, user.id)?;
+            Ok(HttpResponse::Accepted().body("Second factor required, send a code to /login/2fa"))
+        }
+        LoginOutcome::Failed => Err(AppError::Unauthorized(String::from("Invalid email or password"))),
+        LoginOutcome::Throttled(retry_after) => Err(too_many_requests(retry_after)),
     }
-
 }
 
 #[utoipa::path(
@@ -121,19 +255,340 @@ pub async fn login(
             description = "Successfully Logged Out User",
         ),
         (
-            status = 404,
-            description = "Session Not Found",
-            body = SwaggerErrorResponse,
-            example = json!(SwaggerErrorResponse::NotFound(String::from("Session Not Found")))
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
         ),
     )
 )]
 #[post("/logout")]
 pub async fn logout(
-    user: Identity
+    user: Option<Identity>
 )
--> Result<impl Responder> {
+-> Result<HttpResponse, AppError> {
+    let user = user.ok_or_else(|| AppError::Unauthorized(String::from("Not logged in")))?;
     user.logout();
     Ok(HttpResponse::Ok().body("Successfully Loged Out"))
 }
 
+#[utoipa::path(
+    request_body = ForgotPassword,
+    responses(
+        (
+            status = 202,
+            description = "A reset token was mailed if the account exists",
+        ),
+        (
+            status = 406,
+            description = "Email Provided is not valid",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    body: web::Json<ForgotPassword>,
+    state: web::Data<Arc<AppState>>,
+)
+-> Result<HttpResponse, AppError> {
+    let email = normalize_email(&body.into_inner().email)?;
+
+    if let Some(user) = state.users.find_by_email(&email).await? {
+        let token = generate_key();
+        state.users.create_password_reset(user.id, &hash_token(&token), RESET_TOKEN_TTL_MINUTES).await?;
+        block(move || {
+            state.mailer.send(
+                &user.email,
+                "Reset your password",
+                &format!(
+                    "Use this token to reset your password: {}\nIt expires in {} minutes.",
+                    token, RESET_TOKEN_TTL_MINUTES
+                ),
+            )
+        })
+        .await??;
+    }
+
+    Ok(HttpResponse::Accepted().body("If the account exists, a reset token has been sent"))
+}
+
+#[utoipa::path(
+    request_body = ResetPassword,
+    responses(
+        (
+            status = 200,
+            description = "Password was changed",
+        ),
+        (
+            status = 400,
+            description = "Token is invalid, expired or already used",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/password/reset")]
+pub async fn reset_password(
+    body: web::Json<ResetPassword>,
+    state: web::Data<Arc<AppState>>,
+)
+-> Result<HttpResponse, AppError> {
+    let body = body.into_inner();
+    if body.password.is_empty() {
+        return Err(AppError::Validation(String::from("Password must not be empty")));
+    }
+
+    let updated = state.users.reset_password(&hash_token(&body.token), &body.password, state.settings.auth.bcrypt_cost).await?;
+
+    if updated {
+        Ok(HttpResponse::Ok().body("Password updated"))
+    } else {
+        Err(AppError::Validation(String::from("Reset token is invalid or expired")))
+    }
+}
+
+#[utoipa::path(
+    params(
+        ("token" = String, Query, description = "Token from the verification mail")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Email address was verified",
+        ),
+        (
+            status = 400,
+            description = "Token is invalid or expired",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[get("/verify-email")]
+pub async fn verify_email(
+    query: web::Query<VerifyEmail>,
+    state: web::Data<Arc<AppState>>,
+)
+-> Result<HttpResponse, AppError> {
+    let token = query.into_inner().token;
+
+    let verified = state.users.verify_email(&hash_token(&token)).await?;
+
+    if verified {
+        Ok(HttpResponse::Ok().body("Email verified"))
+    } else {
+        Err(AppError::Validation(String::from("Verification token is invalid or expired")))
+    }
+}
+
+#[utoipa::path(
+    request_body = ResendVerification,
+    responses(
+        (
+            status = 202,
+            description = "A verification mail was sent if the account exists and is unverified",
+        ),
+        (
+            status = 406,
+            description = "Email Provided is not valid",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 429,
+            description = "A verification mail was sent too recently",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/verify-email/resend")]
+pub async fn resend_verification(
+    body: web::Json<ResendVerification>,
+    state: web::Data<Arc<AppState>>,
+)
+-> Result<HttpResponse, AppError> {
+    let email = normalize_email(&body.into_inner().email)?;
+
+    let unverified = state.users.find_by_email(&email).await?
+        .filter(|user| user.email_verified_at.is_none());
+    if let Some(user) = unverified {
+        if state.users.verification_sent_recently(user.id, VERIFY_RESEND_COOLDOWN_MINUTES).await? {
+            return Err(AppError::TooManyRequests {
+                detail: String::from("Verification mail was sent recently, please wait before retrying"),
+                retry_after: i64::from(VERIFY_RESEND_COOLDOWN_MINUTES * 60),
+            });
+        }
+        send_verification_mail(&state, &user).await?;
+    }
+
+    Ok(HttpResponse::Accepted().body("If the account needs verifying, a new mail has been sent"))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web};
+    use crate::models::DEFAULT_ROLE;
+    use crate::repository::UserRepository;
+    use crate::test_util::{call, Reply, TestState, PASSWORD};
+    use crate::throttle::ThrottleScope;
+    use crate::ultils::utils::hash_token;
+    use super::{forgot_password, login, reset_password, sign_up, verify_email};
+
+    const EMAIL: &str = "login@example.com";
+
+    fn routes(cfg: &mut web::ServiceConfig) {
+        cfg.service(sign_up)
+            .service(login)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(verify_email);
+    }
+
+    /// Creates the `EMAIL` account, with its address confirmed or not.
+    async fn insert_user(test: &TestState, verified: bool) {
+        let user = test.user(EMAIL, DEFAULT_ROLE).await;
+        if verified {
+            test.users.create_email_verification(user.id, &hash_token("verified"), 5).await.unwrap();
+            assert!(test.users.verify_email(&hash_token("verified")).await.unwrap());
+        }
+    }
+
+    async fn post(test: &TestState, uri: &str, body: serde_json::Value) -> Reply {
+        let app = test.app(routes).await;
+        call(&app, test::TestRequest::post().uri(uri).set_json(body)).await
+    }
+
+    async fn credentials(test: &TestState, uri: &str, email: &str, password: &str) -> Reply {
+        post(test, uri, serde_json::json!({ "email": email, "password": password })).await
+    }
+
+    fn requiring_verification() -> TestState {
+        TestState::with_settings(|settings| settings.auth.require_email_verification = true)
+    }
+
+    #[actix_web::test]
+    async fn login_accepts_correct_password() {
+        let test = requiring_verification();
+        insert_user(&test, true).await;
+
+        let reply = credentials(&test, "/login", &EMAIL.to_uppercase(), PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn login_rejects_wrong_password() {
+        let test = requiring_verification();
+        insert_user(&test, true).await;
+
+        let reply = credentials(&test, "/login", EMAIL, "wrong password").await;
+
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(reply.json()["detail"], "Invalid email or password");
+    }
+
+    #[actix_web::test]
+    async fn login_rejects_unknown_email_like_wrong_password() {
+        let test = requiring_verification();
+
+        let reply = credentials(&test, "/login", "missing@example.com", PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(reply.json()["detail"], "Invalid email or password");
+    }
+
+    #[actix_web::test]
+    async fn login_rejects_malformed_email() {
+        let test = requiring_verification();
+
+        let reply = credentials(&test, "/login", "not-an-email", PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[actix_web::test]
+    async fn login_blocks_unverified_account_when_required() {
+        let test = requiring_verification();
+        insert_user(&test, false).await;
+
+        let reply = credentials(&test, "/login", EMAIL, PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn login_allows_unverified_account_when_not_required() {
+        let test = TestState::new();
+        insert_user(&test, false).await;
+
+        let reply = credentials(&test, "/login", EMAIL, PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn login_waits_for_the_mailed_verification_token() {
+        let test = requiring_verification();
+        credentials(&test, "/signup", "verify@example.com", PASSWORD).await;
+
+        let reply = credentials(&test, "/login", "verify@example.com", PASSWORD).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+        let token = test.outbox.last_token();
+        let app = test.app(routes).await;
+        let verify = || test::TestRequest::get().uri(&format!("/verify-email?token={}", token));
+        assert_eq!(call(&app, verify()).await.status, StatusCode::OK);
+        assert_eq!(call(&app, verify()).await.status, StatusCode::BAD_REQUEST);
+
+        let reply = credentials(&test, "/login", "verify@example.com", PASSWORD).await;
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn login_throttles_the_account_whatever_the_address() {
+        let test = TestState::new();
+        insert_user(&test, false).await;
+
+        for _ in 0..=ThrottleScope::Account.policy().free_attempts {
+            let reply = credentials(&test, "/login", EMAIL, "wrong password").await;
+            assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        }
+        let reply = credentials(&test, "/login", EMAIL, PASSWORD).await;
+
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = reply.headers.get("Retry-After").unwrap().to_str().unwrap();
+        assert!(retry_after.parse::<i64>().unwrap() >= 1);
+    }
+
+    #[actix_web::test]
+    async fn reset_token_changes_the_password_once() {
+        let test = TestState::new();
+        insert_user(&test, false).await;
+
+        let reply = post(&test, "/password/forgot", serde_json::json!({ "email": EMAIL })).await;
+        assert_eq!(reply.status, StatusCode::ACCEPTED);
+
+        let reset = serde_json::json!({ "token": test.outbox.last_token(), "password": "new password" });
+        assert_eq!(post(&test, "/password/reset", reset.clone()).await.status, StatusCode::OK);
+        assert_eq!(post(&test, "/password/reset", reset).await.status, StatusCode::BAD_REQUEST);
+
+        let reply = credentials(&test, "/login", EMAIL, PASSWORD).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        let reply = credentials(&test, "/login", EMAIL, "new password").await;
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn forgot_password_says_nothing_about_unknown_accounts() {
+        let test = TestState::new();
+
+        let reply = post(&test, "/password/forgot", serde_json::json!({ "email": "nobody@example.com" })).await;
+
+        assert_eq!(reply.status, StatusCode::ACCEPTED);
+        assert!(test.outbox.is_empty());
+    }
+}
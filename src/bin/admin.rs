@@ -0,0 +1,255 @@
+//! Maintenance commands run against `DATABASE_URL`, for example
+//! `cargo run --bin admin -- create-admin root@example.com`.
+
+use std::io::{self, BufRead, Write};
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use diesel::{Connection, PgConnection};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use dotenv::dotenv;
+use actix_diesel::connect;
+use actix_diesel::auth::Credentials;
+use actix_diesel::db_actions::{
+    normalize_email,
+    create_user,
+    find_user_by_email,
+    find_role,
+    set_user_role,
+    set_password,
+    mark_email_verified,
+    get_users,
+    get_videos,
+    create_video,
+    create_liked_videos,
+    upsert_watch_progress,
+    clear_throttle
+};
+use actix_diesel::migrations::run_migrations;
+use actix_diesel::models::{ADMIN_ROLE, DEFAULT_ROLE, User, VideoForm, VideoList, WatchProgress};
+use actix_diesel::settings::Settings;
+use actix_diesel::throttle::ThrottleScope;
+
+const DEMO_EMAIL: &str = "demo@example.com";
+const DEMO_PASSWORD: &str = "demo-password";
+
+
+#[derive(Parser)]
+#[command(name = "admin", about = "Administers the users and data of the API")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates a verified account with the admin role
+    CreateAdmin {
+        email: String,
+        /// Read from stdin when left out, so it stays out of the shell history
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Gives a user a role, admin unless --role names another
+    Promote {
+        email: String,
+        #[arg(long, default_value = ADMIN_ROLE)]
+        role: String,
+    },
+    /// Puts a user back on the default role
+    Demote {
+        email: String,
+    },
+    /// Sets a new password, unlocks the account and ends its sessions
+    ResetPassword {
+        email: String,
+        /// Read from stdin when left out
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Lists every account with its role
+    ListUsers,
+    /// Applies the migrations the database is missing, waiting for any
+    /// server instance migrating at the same time
+    Migrate,
+    /// Fills a database without videos with sample videos and a demo account
+    Seed,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    dotenv().ok();
+    let cli = Cli::parse();
+    let settings = Settings::load()?;
+    let bcrypt_cost = settings.auth.bcrypt_cost;
+    let pool = connect(&settings.database);
+    let mut conn = pool.get()?;
+
+    match cli.command {
+        Command::CreateAdmin { email, password } => create_admin(&mut conn, &email, password, bcrypt_cost),
+        Command::Promote { email, role } => change_role(&mut conn, &email, &role),
+        Command::Demote { email } => change_role(&mut conn, &email, DEFAULT_ROLE),
+        Command::ResetPassword { email, password } => reset_password(&mut conn, &email, password, bcrypt_cost),
+        Command::ListUsers => list_users(&mut conn),
+        Command::Migrate => migrate(&mut conn),
+        Command::Seed => seed(&mut conn, bcrypt_cost),
+    }
+}
+
+fn normalized(email: &str) -> Result<String, anyhow::Error> {
+    normalize_email(email).map_err(|_| anyhow!("{} is not a valid email", email))
+}
+
+fn find_user(conn: &mut PgConnection, email: &str) -> Result<User, anyhow::Error> {
+    let email = normalized(email)?;
+    find_user_by_email(conn, &email).map_err(|err| {
+        match err.downcast_ref::<DieselError>() {
+            Some(DieselError::NotFound) => anyhow!("No user with email {}", email),
+            _ => err,
+        }
+    })
+}
+
+fn password_or_prompt(password: Option<String>) -> Result<String, anyhow::Error> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            print!("Password: ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        return Err(anyhow!("Password must not be empty"));
+    }
+    Ok(password)
+}
+
+fn create_admin(
+    conn: &mut PgConnection,
+    email: &str,
+    password: Option<String>,
+    bcrypt_cost: u32
+)
+-> Result<(), anyhow::Error> {
+    let creds = Credentials {
+        email: normalized(email)?,
+        password: password_or_prompt(password)?,
+    };
+    let admin = find_role(conn, ADMIN_ROLE)?
+        .ok_or_else(|| anyhow!("Role {} is missing, run the migrations first", ADMIN_ROLE))?;
+
+    let user = conn.transaction(|conn| {
+        let user = create_user(conn, creds, bcrypt_cost)?;
+        mark_email_verified(conn, user.id)?;
+        set_user_role(conn, user.id, admin.id)?;
+        Ok::<_, anyhow::Error>(user)
+    })
+    .map_err(|err| match err.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            anyhow!("An account with this email already exists, promote it instead")
+        }
+        _ => err,
+    })?;
+
+    println!("Created admin {} with id {}", user.email, user.id);
+    Ok(())
+}
+
+fn change_role(
+    conn: &mut PgConnection,
+    email: &str,
+    role: &str
+)
+-> Result<(), anyhow::Error> {
+    let user = find_user(conn, email)?;
+    let role = find_role(conn, role)?
+        .ok_or_else(|| anyhow!("Unknown role {}", role))?;
+    set_user_role(conn, user.id, role.id)?;
+
+    println!("{} now has the {} role", user.email, role.name);
+    Ok(())
+}
+
+fn reset_password(
+    conn: &mut PgConnection,
+    email: &str,
+    password: Option<String>,
+    bcrypt_cost: u32
+)
+-> Result<(), anyhow::Error> {
+    let user = find_user(conn, email)?;
+    let password = password_or_prompt(password)?;
+    set_password(conn, user.id, &password, bcrypt_cost)?;
+    clear_throttle(conn, ThrottleScope::Account, &user.email)?;
+
+    println!("Password of {} was reset and its sessions ended", user.email);
+    Ok(())
+}
+
+fn list_users(conn: &mut PgConnection) -> Result<(), anyhow::Error> {
+    println!("{:>6}  {:<10}  {:<8}  email", "id", "role", "verified");
+    for (user, role) in get_users(conn)? {
+        let verified = if user.email_verified_at.is_some() { "yes" } else { "no" };
+        println!("{:>6}  {:<10}  {:<8}  {}", user.id, role, verified, user.email);
+    }
+    Ok(())
+}
+
+fn migrate(conn: &mut PgConnection) -> Result<(), anyhow::Error> {
+    let applied = run_migrations(conn)?;
+    if applied.is_empty() {
+        println!("Database is up to date");
+    }
+    for version in applied {
+        println!("Applied {}", version);
+    }
+    Ok(())
+}
+
+fn sample_videos() -> Vec<VideoForm> {
+    let video = |title: &str, description: &str, duration: i32, release_year: i32, rating: &str| VideoForm {
+        title: title.to_string(),
+        description: description.to_string(),
+        duration,
+        release_year: Some(release_year),
+        maturity_rating: Some(rating.to_string()),
+    };
+    vec![
+        video("Night Shift", "A nurse keeps a small hospital running through a winter storm.", 5880, 2019, "PG-13"),
+        video("Tide Lines", "Three generations of a fishing family argue over the family boat.", 6420, 2021, "PG"),
+        video("Signal Lost", "A radio astronomer picks up a message nobody else can hear.", 7260, 2017, "PG-13"),
+        video("The Long Table", "A documentary about a village that eats dinner together every night.", 4980, 2022, "G"),
+        video("Overpass", "Two strangers are stuck on a closed highway for a weekend.", 5520, 2020, "R"),
+    ]
+}
+
+fn seed(conn: &mut PgConnection, bcrypt_cost: u32) -> Result<(), anyhow::Error> {
+    if !get_videos(conn)?.is_empty() {
+        println!("Database already has videos, nothing to seed");
+        return Ok(());
+    }
+
+    conn.transaction(|conn| {
+        let videos = sample_videos()
+            .into_iter()
+            .map(|form| create_video(conn, form))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let demo = create_user(conn, Credentials {
+            email: DEMO_EMAIL.to_string(),
+            password: DEMO_PASSWORD.to_string(),
+        }, bcrypt_cost)?;
+        mark_email_verified(conn, demo.id)?;
+        create_liked_videos(conn, demo.id, videos[0].id, VideoList::Liked)?;
+        create_liked_videos(conn, demo.id, videos[2].id, VideoList::Liked)?;
+        create_liked_videos(conn, demo.id, videos[1].id, VideoList::Watched)?;
+        upsert_watch_progress(conn, demo.id, videos[3].id, WatchProgress {
+            position: videos[3].duration / 3,
+            duration: videos[3].duration,
+        })?;
+
+        println!("Added {} videos and the account {} / {}", videos.len(), DEMO_EMAIL, DEMO_PASSWORD);
+        Ok(())
+    })
+}
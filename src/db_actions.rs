@@ -1,144 +1,1035 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
 use diesel::prelude::*;
+use diesel::dsl::{now, IntervalDsl};
+use diesel::upsert::excluded;
+use chrono::NaiveDateTime;
 use diesel::PgConnection;
+use email_address::EmailAddress;
 use crate::auth::Credentials;
-use crate::models::User;
-use crate::models::VideoType;
-use crate::models::VideoTypeResult;
-use crate::schema::liked_videos;
+use crate::throttle::ThrottleScope;
+use crate::queries;
+use crate::rate_limit::{Decision, RatePolicy, RateState};
+use crate::models::{DEFAULT_ROLE, RefreshOutcome, Role, SessionMeta, TotpSecret, User, UserSession};
+use crate::models::VideoList;
+use crate::models::VideoListEntry;
+use crate::schema::api_keys;
+use crate::schema::rate_limits;
+use crate::schema::recovery_codes;
+use crate::schema::refresh_tokens;
+use crate::schema::roles;
+use crate::schema::sessions;
+use crate::schema::totp_secrets;
 use crate::schema::users;
-use crate::schema::watched_videos;
 use crate::models::{
+    WatchedVideos,
+    WatchProgress,
+    ContinueWatching,
+    UserApiKey,
     UserWithVideos,
-    LikedVideos,
-    WatchedVideos
+    Video,
+    VideoForm
 };
-use actix_web::HttpResponse;
+use crate::errors::AppError;
 
 
 
-pub fn validate_email(email: &str) -> Result<(), HttpResponse> {
-    if !email.contains(".com") || !email.contains("@") {
-        return Err(HttpResponse::NotAcceptable().body("Email provided is invalid! please check email"))
+/// Longest address that fits an SMTP path (RFC 5321).
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+/// Trims and lowercases an address, converting an internationalized domain to
+/// its ASCII form, and rejects anything that is not a valid RFC 5322 address.
+/// Addresses are stored and looked up in this form.
+pub fn normalize_email(email: &str) -> Result<String, AppError> {
+    let (local, domain) = email.trim().rsplit_once('@').ok_or(AppError::InvalidEmail)?;
+    let domain = idna::domain_to_ascii(domain).map_err(|_| AppError::InvalidEmail)?;
+    let local = local.to_lowercase();
+    let normalized = format!("{}@{}", local, domain);
+
+    if local.is_empty()
+        || local.len() > MAX_LOCAL_PART_LENGTH
+        || normalized.len() > MAX_EMAIL_LENGTH
+        || !domain.contains('.')
+        || !EmailAddress::is_valid(&normalized)
+    {
+        return Err(AppError::InvalidEmail);
     }
-    Ok(())
+    Ok(normalized)
 }
 
-fn hash_password(password: &str) -> String {
-    bcrypt::hash(password, bcrypt::DEFAULT_COST)
-        .expect("Failed to hash password!")
+pub(crate) fn hash_password(password: &str, cost: u32) -> Result<String, bcrypt::BcryptError> {
+    bcrypt::hash(password, cost)
 }
 
+/// Hash checked when no account matches, so unknown emails take as long to
+/// reject as wrong passwords. The cost only changes with a restart, so the
+/// hash made with the first one is kept.
+pub(crate) fn dummy_hash(cost: u32) -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not-a-real-password", cost).expect("Failed to hash password!"))
+}
+
+/// Returns the account only when the email exists and the password matches.
 pub fn authenticate(
     creds: Credentials,
-    conn: &mut PgConnection
+    conn: &mut PgConnection,
+    bcrypt_cost: u32
 ) 
+-> Result<Option<User>, anyhow::Error> {
+    let user: Option<User> = queries::user_by_email(&creds.email).get_result(conn).optional()?;
+
+    let hash = user
+        .as_ref()
+        .map_or(dummy_hash(bcrypt_cost), |user| user.password_hash.as_str());
+    let verified = bcrypt::verify(&creds.password, hash)?;
+
+    Ok(user.filter(|_| verified))
+}
+
+pub fn find_user_by_email(
+    conn: &mut PgConnection,
+    email: &str
+)
 -> Result<User, anyhow::Error> {
-    let user: User = users::table
-        .filter(users::email.eq(&creds.email))
-        .select(User::as_select())
-        .get_result(conn)?;
+    let user = queries::user_by_email(email).get_result(conn)?;
 
     Ok(user)
 }
 
+/// The account with its liked videos and every video it has a watch row
+/// for, what `GET /user/{id}` answers.
 pub fn get_everything(
     conn: &mut PgConnection,
     id: i32
 )
 -> Result<UserWithVideos, anyhow::Error>  {
-    let user: User = users::table
-        .filter(users::id.eq(id))
-        .select(User::as_select())
-        .get_result(conn)?;
-
-    let liked_vids: Vec<LikedVideos> = LikedVideos::belonging_to(&user)
-        .select(LikedVideos::as_select())
-        .load(conn)?;
-
-    let watched_vids: Vec<WatchedVideos> = WatchedVideos::belonging_to(&user)
-        .select(WatchedVideos::as_select())
-        .load(conn)?;
+    let user: User = queries::user(id).get_result(conn)?;
+    let liked_videos = queries::liked_videos(user.id).load(conn)?;
+    let watched_videos = queries::watch_history(user.id).load(conn)?;
 
     let data = UserWithVideos {
-         user,
-        liked_videos: liked_vids,
-        watched_videos: watched_vids
+        user,
+        liked_videos,
+        watched_videos
     };
 
     Ok(data)
 }
 
+/// Every video the user has a watch row for, finished or not.
+pub fn get_watch_history(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::watch_history(id).load(conn)?;
+
+    Ok(videos)
+}
+
 pub fn get_user_info(
     conn: &mut PgConnection,
     id: i32
 )
--> Result<Vec<LikedVideos>, anyhow::Error> {
-    let user: User = users::table
-        .filter(users::id.eq(id))
-        .select(User::as_select())
-        .get_result(conn)?;
+-> Result<Vec<Video>, anyhow::Error> {
+    let user: User = queries::user(id).get_result(conn)?;
+    let videos = queries::liked_videos(user.id).load(conn)?;
 
-    let videos: Vec<LikedVideos> = LikedVideos::belonging_to(&user)
-        .select(LikedVideos::as_select())
-        .load(conn)?;
+    Ok(videos)
+}
+
+pub fn get_watched_videos(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::watched_videos(id).load(conn)?;
 
     Ok(videos)
 }
 
+pub fn get_continue_watching(
+    conn: &mut PgConnection,
+    id: i32,
+    limit: i64
+)
+-> Result<Vec<ContinueWatching>, anyhow::Error> {
+    let rows: Vec<(WatchedVideos, Video)> = queries::continue_watching(id, limit).load(conn)?;
+    let videos = rows
+        .into_iter()
+        .map(|(progress, video)| ContinueWatching { video, progress })
+        .collect();
+
+    Ok(videos)
+}
+
+pub fn upsert_watch_progress(
+    conn: &mut PgConnection,
+    id: i32,
+    vid_id: i32,
+    progress: WatchProgress
+)
+-> Result<WatchedVideos, anyhow::Error> {
+    let watched = queries::save_progress(id, vid_id, progress).get_result(conn)?;
+
+    Ok(watched)
+}
+
 pub fn create_user(
     conn: &mut PgConnection,
-    creds: Credentials
+    creds: Credentials,
+    bcrypt_cost: u32
 )
 -> Result<User, anyhow::Error> {
-    let mut role: String = String::from("User");
-    if creds.email == std::env::var("ADMIN_KEY").unwrap() && creds.password == std::env::var("ADMIN_SECOND_KEY").unwrap() {
-        role = "ADMIN".to_string();
-    } 
-    let hashed_password = hash_password(&creds.password);
-    let user = diesel::insert_into(users::table)
+    let role = find_role(conn, DEFAULT_ROLE)?
+        .ok_or_else(|| anyhow::Error::msg(format!("Role {} is missing", DEFAULT_ROLE)))?;
+    let hashed_password = hash_password(&creds.password, bcrypt_cost)?;
+    let user = queries::insert_user(creds.email, hashed_password, role.id).get_result(conn)?;
+
+    Ok(user)
+}
+
+/// Every account with the name of its role, oldest first.
+pub fn get_users(
+    conn: &mut PgConnection
+)
+-> Result<Vec<(User, String)>, anyhow::Error> {
+    let users = users::table
+        .inner_join(roles::table)
+        .order(users::id)
+        .select((User::as_select(), roles::name))
+        .load(conn)?;
+
+    Ok(users)
+}
+
+/// Sets a new password and signs the account out everywhere, ending its
+/// sessions and refresh tokens.
+pub fn set_password(
+    conn: &mut PgConnection,
+    id: i32,
+    password: &str,
+    bcrypt_cost: u32
+)
+-> Result<(), anyhow::Error> {
+    conn.transaction(|conn| {
+        queries::set_password_hash(id, hash_password(password, bcrypt_cost)?).execute(conn)?;
+        sign_out_everywhere(conn, id)
+    })
+}
+
+/// Ends every session of the account and revokes its refresh tokens.
+fn sign_out_everywhere(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<(), anyhow::Error> {
+    queries::delete_sessions(id).execute(conn)?;
+    queries::revoke_refresh_tokens(id).execute(conn)?;
+
+    Ok(())
+}
+
+pub fn mark_email_verified(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<(), anyhow::Error> {
+    queries::mark_email_verified(id).execute(conn)?;
+
+    Ok(())
+}
+
+pub fn get_roles(
+    conn: &mut PgConnection
+)
+-> Result<Vec<Role>, anyhow::Error> {
+    let roles = queries::all_roles().load(conn)?;
+
+    Ok(roles)
+}
+
+pub fn find_role(
+    conn: &mut PgConnection,
+    name: &str
+)
+-> Result<Option<Role>, anyhow::Error> {
+    let role = queries::role_by_name(name).get_result(conn).optional()?;
+
+    Ok(role)
+}
+
+/// Loads a role with the names of all its permissions, including the ones
+/// inherited from its ancestors.
+pub fn get_role_permissions(
+    conn: &mut PgConnection,
+    role_id: i32
+)
+-> Result<(Role, HashSet<String>), anyhow::Error> {
+    // The role tree is tiny, so walking it in memory beats a recursive query.
+    let roles = get_roles(conn)?;
+    let role = roles.iter()
+        .find(|role| role.id == role_id)
+        .cloned()
+        .ok_or(diesel::result::Error::NotFound)?;
+
+    let mut chain = vec![role.id];
+    let mut parent = role.parent_id;
+    while let Some(id) = parent.filter(|id| !chain.contains(id)) {
+        chain.push(id);
+        parent = roles.iter().find(|role| role.id == id).and_then(|role| role.parent_id);
+    }
+
+    let permissions: Vec<String> = queries::permission_names(chain).load(conn)?;
+
+    Ok((role, permissions.into_iter().collect()))
+}
+
+pub fn set_user_role(
+    conn: &mut PgConnection,
+    id: i32,
+    role_id: i32
+)
+-> Result<Option<User>, anyhow::Error> {
+    let user = queries::set_user_role(id, role_id).get_result(conn).optional()?;
+
+    Ok(user)
+}
+
+/// Stores a reset token for the user, dropping any token issued before it so
+/// only the latest mail works.
+pub fn create_password_reset(
+    conn: &mut PgConnection,
+    id: i32,
+    token_hash: &str,
+    ttl_minutes: i32
+)
+-> Result<(), anyhow::Error> {
+    conn.transaction(|conn| {
+        queries::delete_password_resets(id).execute(conn)?;
+        queries::insert_password_reset(id, token_hash, ttl_minutes).execute(conn)?;
+
+        Ok(())
+    })
+}
+
+/// Consumes an unused, unexpired reset token and sets the new password,
+/// signing the account out everywhere and lifting its login lockout.
+/// Returns `false` when the token is unknown, expired or already used.
+pub fn reset_password(
+    conn: &mut PgConnection,
+    token_hash: &str,
+    password: &str,
+    bcrypt_cost: u32
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction(|conn| {
+        let user_id: Option<i32> = queries::use_password_reset(token_hash).get_result(conn).optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        let email: String = queries::set_password_hash(user_id, hash_password(password, bcrypt_cost)?).get_result(conn)?;
+        sign_out_everywhere(conn, user_id)?;
+        clear_throttle(conn, ThrottleScope::Account, &email)?;
+
+        Ok(true)
+    })
+}
+
+/// Stores a verification token for the user, replacing any earlier one.
+pub fn create_email_verification(
+    conn: &mut PgConnection,
+    id: i32,
+    token_hash: &str,
+    ttl_minutes: i32
+)
+-> Result<(), anyhow::Error> {
+    conn.transaction(|conn| {
+        queries::delete_email_verifications(id).execute(conn)?;
+        queries::insert_email_verification(id, token_hash, ttl_minutes).execute(conn)?;
+
+        Ok(())
+    })
+}
+
+/// True when a verification mail went out to the user within the cooldown.
+pub fn verification_sent_recently(
+    conn: &mut PgConnection,
+    id: i32,
+    cooldown_minutes: i32
+)
+-> Result<bool, anyhow::Error> {
+    let sent = queries::verification_sent_recently(id, cooldown_minutes).get_result(conn)?;
+
+    Ok(sent)
+}
+
+/// Consumes an unexpired verification token and marks the email verified.
+/// Returns `false` when the token is unknown or expired.
+pub fn verify_email(
+    conn: &mut PgConnection,
+    token_hash: &str
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction(|conn| {
+        let user_id: Option<i32> = queries::use_email_verification(token_hash).get_result(conn).optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        mark_email_verified(conn, user_id)?;
+
+        Ok(true)
+    })
+}
+
+/// Stores a new API key for the user. Only the hash of the key is kept.
+pub fn create_api_key(
+    conn: &mut PgConnection,
+    id: i32,
+    name: &str,
+    key_prefix: &str,
+    key_hash: &str,
+    scopes: &[String],
+    expires_in_days: Option<i32>
+)
+-> Result<UserApiKey, anyhow::Error> {
+    let expires_at: Option<NaiveDateTime> = match expires_in_days {
+        Some(days) => Some(diesel::select(now + days.days()).get_result(conn)?),
+        None => None,
+    };
+
+    let api_key = diesel::insert_into(api_keys::table)
         .values((
-            users::email.eq(creds.email),
-            users::password_hash.eq(hashed_password),
-            users::role.eq(role)
+            api_keys::user_id.eq(id),
+            api_keys::name.eq(name),
+            api_keys::key_prefix.eq(key_prefix),
+            api_keys::key_hash.eq(key_hash),
+            api_keys::scopes.eq(scopes),
+            api_keys::expires_at.eq(expires_at),
         ))
-        .returning(User::as_returning())
+        .returning(UserApiKey::as_returning())
         .get_result(conn)?;
 
+    Ok(api_key)
+}
+
+/// Keys of the user that have not been revoked, newest first.
+pub fn get_api_keys(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Vec<UserApiKey>, anyhow::Error> {
+    let keys = api_keys::table
+        .filter(api_keys::user_id.eq(id))
+        .filter(api_keys::revoked_at.is_null())
+        .order(api_keys::created_at.desc())
+        .select(UserApiKey::as_select())
+        .load(conn)?;
+
+    Ok(keys)
+}
+
+/// Revokes one of the user's keys, returning how many rows changed.
+pub fn revoke_api_key(
+    conn: &mut PgConnection,
+    id: i32,
+    key_id: i32
+)
+-> Result<usize, anyhow::Error> {
+    let revoked = diesel::update(api_keys::table
+        .filter(api_keys::id.eq(key_id))
+        .filter(api_keys::user_id.eq(id))
+        .filter(api_keys::revoked_at.is_null()))
+        .set(api_keys::revoked_at.eq(now))
+        .execute(conn)?;
+
+    Ok(revoked)
+}
+
+/// Looks up a live key by its hash and stamps it as used.
+/// Returns `None` when the key is unknown, revoked or expired.
+pub fn authenticate_api_key(
+    conn: &mut PgConnection,
+    key_hash: &str
+)
+-> Result<Option<UserApiKey>, anyhow::Error> {
+    let api_key = diesel::update(api_keys::table
+        .filter(api_keys::key_hash.eq(key_hash))
+        .filter(api_keys::revoked_at.is_null())
+        .filter(api_keys::expires_at.is_null().or(api_keys::expires_at.gt(now))))
+        .set(api_keys::last_used_at.eq(now))
+        .returning(UserApiKey::as_returning())
+        .get_result(conn)
+        .optional()?;
+
+    Ok(api_key)
+}
+
+/// Stores a new session, sweeping out expired ones on the way.
+pub fn insert_session(
+    conn: &mut PgConnection,
+    key_hash: &str,
+    state: &str,
+    meta: SessionMeta,
+    ttl_seconds: i32
+)
+-> Result<(), anyhow::Error> {
+    diesel::delete(sessions::table.filter(sessions::expires_at.le(now)))
+        .execute(conn)?;
+
+    diesel::insert_into(sessions::table)
+        .values((
+            sessions::key_hash.eq(key_hash),
+            sessions::state.eq(state),
+            sessions::user_id.eq(meta.user_id),
+            sessions::user_agent.eq(meta.user_agent),
+            sessions::ip.eq(meta.ip),
+            sessions::expires_at.eq(now + ttl_seconds.seconds()),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// State of a live session, stamping it as seen.
+/// Returns `None` when the session is unknown, revoked or expired.
+pub fn load_session(
+    conn: &mut PgConnection,
+    key_hash: &str
+)
+-> Result<Option<String>, anyhow::Error> {
+    let state = diesel::update(sessions::table
+        .filter(sessions::key_hash.eq(key_hash))
+        .filter(sessions::expires_at.gt(now)))
+        .set(sessions::last_seen_at.eq(now))
+        .returning(sessions::state)
+        .get_result(conn)
+        .optional()?;
+
+    Ok(state)
+}
+
+/// Replaces the state of a live session, returning how many rows changed.
+pub fn update_session(
+    conn: &mut PgConnection,
+    key_hash: &str,
+    state: &str,
+    meta: SessionMeta,
+    ttl_seconds: i32
+)
+-> Result<usize, anyhow::Error> {
+    let updated = diesel::update(sessions::table
+        .filter(sessions::key_hash.eq(key_hash))
+        .filter(sessions::expires_at.gt(now)))
+        .set((
+            sessions::state.eq(state),
+            sessions::user_id.eq(meta.user_id),
+            sessions::user_agent.eq(meta.user_agent),
+            sessions::ip.eq(meta.ip),
+            sessions::expires_at.eq(now + ttl_seconds.seconds()),
+        ))
+        .execute(conn)?;
+
+    Ok(updated)
+}
+
+pub fn extend_session(
+    conn: &mut PgConnection,
+    key_hash: &str,
+    ttl_seconds: i32
+)
+-> Result<(), anyhow::Error> {
+    diesel::update(sessions::table.filter(sessions::key_hash.eq(key_hash)))
+        .set(sessions::expires_at.eq(now + ttl_seconds.seconds()))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn delete_session(
+    conn: &mut PgConnection,
+    key_hash: &str
+)
+-> Result<(), anyhow::Error> {
+    diesel::delete(sessions::table.filter(sessions::key_hash.eq(key_hash)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Live sessions of the user, most recently seen first.
+pub fn get_user_sessions(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Vec<UserSession>, anyhow::Error> {
+    let sessions = sessions::table
+        .filter(sessions::user_id.eq(id))
+        .filter(sessions::expires_at.gt(now))
+        .order(sessions::last_seen_at.desc())
+        .select(UserSession::as_select())
+        .load(conn)?;
+
+    Ok(sessions)
+}
+
+/// Revokes one session of the user, or all of them when `session_id` is
+/// `None`. Returns how many were revoked.
+pub fn delete_user_sessions(
+    conn: &mut PgConnection,
+    id: i32,
+    session_id: Option<i32>
+)
+-> Result<usize, anyhow::Error> {
+    let mut query = diesel::delete(sessions::table)
+        .filter(sessions::user_id.eq(id))
+        .into_boxed();
+    if let Some(session_id) = session_id {
+        query = query.filter(sessions::id.eq(session_id));
+    }
+
+    Ok(query.execute(conn)?)
+}
+
+pub fn create_refresh_token(
+    conn: &mut PgConnection,
+    id: i32,
+    family_id: &str,
+    token_hash: &str,
+    ttl_days: i32
+)
+-> Result<(), anyhow::Error> {
+    diesel::insert_into(refresh_tokens::table)
+        .values((
+            refresh_tokens::user_id.eq(id),
+            refresh_tokens::family_id.eq(family_id),
+            refresh_tokens::token_hash.eq(token_hash),
+            refresh_tokens::expires_at.eq(now + ttl_days.days()),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Uses up a refresh token and stores its successor in the same family.
+/// A token that was already used means it leaked, so every token of its
+/// family is revoked.
+pub fn rotate_refresh_token(
+    conn: &mut PgConnection,
+    token_hash: &str,
+    next_hash: &str,
+    ttl_days: i32
+)
+-> Result<RefreshOutcome, anyhow::Error> {
+    conn.transaction(|conn| {
+        let token: Option<(i32, i32, String, bool, bool, bool)> = refresh_tokens::table
+            .filter(refresh_tokens::token_hash.eq(token_hash))
+            .select((
+                refresh_tokens::id,
+                refresh_tokens::user_id,
+                refresh_tokens::family_id,
+                refresh_tokens::used_at.is_not_null(),
+                refresh_tokens::revoked_at.is_not_null(),
+                refresh_tokens::expires_at.le(now),
+            ))
+            .for_update()
+            .get_result(conn)
+            .optional()?;
+
+        let Some((id, user_id, family_id, used, revoked, expired)) = token else {
+            return Ok(RefreshOutcome::Invalid);
+        };
+        if revoked || expired {
+            return Ok(RefreshOutcome::Invalid);
+        }
+        if used {
+            revoke_refresh_family(conn, &family_id)?;
+            return Ok(RefreshOutcome::Reused);
+        }
+
+        diesel::update(refresh_tokens::table.find(id))
+            .set(refresh_tokens::used_at.eq(now))
+            .execute(conn)?;
+        create_refresh_token(conn, user_id, &family_id, next_hash, ttl_days)?;
+
+        Ok(RefreshOutcome::Rotated(user_id))
+    })
+}
+
+pub fn revoke_refresh_family(
+    conn: &mut PgConnection,
+    family_id: &str
+)
+-> Result<usize, anyhow::Error> {
+    let revoked = diesel::update(refresh_tokens::table
+        .filter(refresh_tokens::family_id.eq(family_id))
+        .filter(refresh_tokens::revoked_at.is_null()))
+        .set(refresh_tokens::revoked_at.eq(now))
+        .execute(conn)?;
+
+    Ok(revoked)
+}
+
+/// Revokes the family of a refresh token, for clients logging out.
+pub fn revoke_refresh_token(
+    conn: &mut PgConnection,
+    token_hash: &str
+)
+-> Result<usize, anyhow::Error> {
+    let family_id: Option<String> = refresh_tokens::table
+        .filter(refresh_tokens::token_hash.eq(token_hash))
+        .select(refresh_tokens::family_id)
+        .get_result(conn)
+        .optional()?;
+
+    match family_id {
+        Some(family_id) => revoke_refresh_family(conn, &family_id),
+        None => Ok(0),
+    }
+}
+
+pub fn get_totp_secret(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Option<TotpSecret>, anyhow::Error> {
+    let secret = totp_secrets::table
+        .find(id)
+        .select(TotpSecret::as_select())
+        .get_result(conn)
+        .optional()?;
+
+    Ok(secret)
+}
+
+pub fn has_two_factor(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<bool, anyhow::Error> {
+    let enabled = queries::has_two_factor(id).get_result(conn)?;
+
+    Ok(enabled)
+}
+
+/// Stores a fresh secret awaiting confirmation, replacing an earlier
+/// unconfirmed one. Returns `false` when two-factor is already enabled.
+pub fn start_totp_enrollment(
+    conn: &mut PgConnection,
+    id: i32,
+    secret: &str
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction(|conn| {
+        let existing = totp_secrets::table
+            .find(id)
+            .select(totp_secrets::confirmed_at)
+            .for_update()
+            .get_result::<Option<NaiveDateTime>>(conn)
+            .optional()?;
+        if matches!(existing, Some(Some(_))) {
+            return Ok(false);
+        }
+
+        diesel::insert_into(totp_secrets::table)
+            .values((
+                totp_secrets::user_id.eq(id),
+                totp_secrets::secret.eq(secret),
+            ))
+            .on_conflict(totp_secrets::user_id)
+            .do_update()
+            .set((
+                totp_secrets::secret.eq(excluded(totp_secrets::secret)),
+                totp_secrets::created_at.eq(now),
+                totp_secrets::last_used_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+
+        Ok(true)
+    })
+}
+
+/// Turns a pending secret on and replaces the recovery codes. Returns `false`
+/// when there was no pending secret to confirm.
+pub fn confirm_totp(
+    conn: &mut PgConnection,
+    id: i32,
+    step: i64,
+    code_hashes: &[String]
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction(|conn| {
+        let confirmed = diesel::update(totp_secrets::table
+            .find(id)
+            .filter(totp_secrets::confirmed_at.is_null()))
+            .set((
+                totp_secrets::confirmed_at.eq(now),
+                totp_secrets::last_used_step.eq(step),
+            ))
+            .execute(conn)?;
+        if confirmed == 0 {
+            return Ok(false);
+        }
+
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id)))
+            .execute(conn)?;
+        let rows: Vec<_> = code_hashes
+            .iter()
+            .map(|hash| (recovery_codes::user_id.eq(id), recovery_codes::code_hash.eq(hash)))
+            .collect();
+        diesel::insert_into(recovery_codes::table)
+            .values(&rows)
+            .execute(conn)?;
+
+        Ok(true)
+    })
+}
+
+/// Records the time step of an accepted code. Returns `false` when a code of
+/// this or a later step was already accepted, so the code is a replay.
+pub fn use_totp_step(
+    conn: &mut PgConnection,
+    id: i32,
+    step: i64
+)
+-> Result<bool, anyhow::Error> {
+    let updated = diesel::update(totp_secrets::table
+        .find(id)
+        .filter(totp_secrets::confirmed_at.is_not_null())
+        .filter(totp_secrets::last_used_step.is_null().or(totp_secrets::last_used_step.lt(step))))
+        .set(totp_secrets::last_used_step.eq(step))
+        .execute(conn)?;
+
+    Ok(updated == 1)
+}
+
+/// Burns a recovery code. Returns `false` when it is unknown or already used.
+pub fn use_recovery_code(
+    conn: &mut PgConnection,
+    id: i32,
+    code_hash: &str
+)
+-> Result<bool, anyhow::Error> {
+    let used = diesel::update(recovery_codes::table
+        .filter(recovery_codes::user_id.eq(id))
+        .filter(recovery_codes::code_hash.eq(code_hash))
+        .filter(recovery_codes::used_at.is_null()))
+        .set(recovery_codes::used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+pub fn disable_two_factor(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<usize, anyhow::Error> {
+    conn.transaction(|conn| {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id)))
+            .execute(conn)?;
+        let deleted = diesel::delete(totp_secrets::table.find(id))
+            .execute(conn)?;
+
+        Ok(deleted)
+    })
+}
+
+/// Seconds until the subject may try again, or `None` when it is not locked.
+pub fn throttle_remaining(
+    conn: &mut PgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<Option<i64>, anyhow::Error> {
+    let row: Option<(Option<NaiveDateTime>, NaiveDateTime)> = queries::throttle_lock(scope, subject)
+        .get_result(conn)
+        .optional()?;
+
+    let remaining = row.and_then(|(locked_until, db_now)| {
+        locked_until
+            .filter(|until| *until > db_now)
+            .map(|until| ((until - db_now).num_milliseconds() + 999) / 1000)
+    });
+
+    Ok(remaining)
+}
+
+/// Counts an attempt against the subject and locks it for the delay its
+/// scope's policy asks for. Returns that delay in seconds.
+pub fn record_attempt(
+    conn: &mut PgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<i32, anyhow::Error> {
+    let policy = scope.policy();
+    conn.transaction(|conn| {
+        // Counters that went quiet for a whole window start over.
+        queries::expire_throttle(scope, subject, policy.window_seconds).execute(conn)?;
+        let attempts: i32 = queries::count_attempt(scope, subject).get_result(conn)?;
+
+        let delay = policy.delay_seconds(attempts);
+        if delay > 0 {
+            queries::lock_throttle(scope, subject, delay).execute(conn)?;
+        }
+
+        Ok(delay)
+    })
+}
+
+pub fn clear_throttle(
+    conn: &mut PgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<usize, anyhow::Error> {
+    let cleared = queries::clear_throttle(scope, subject).execute(conn)?;
+
+    Ok(cleared)
+}
+
+/// Counts a request against a rate limit key shared by every instance. The
+/// row is locked while the policy is applied so concurrent hits queue up.
+pub fn apply_rate_limit(
+    conn: &mut PgConnection,
+    key: &str,
+    policy: &RatePolicy,
+    now_ms: i64
+)
+-> Result<Decision, anyhow::Error> {
+    conn.transaction(|conn| {
+        let state = rate_limits::table
+            .find(key)
+            .select((rate_limits::value, rate_limits::previous, rate_limits::stamp_ms))
+            .for_update()
+            .get_result::<(f64, f64, i64)>(conn)
+            .optional()?
+            .map(|(value, previous, stamp_ms)| RateState { value, previous, stamp_ms });
+
+        let (state, decision) = policy.apply(state, now_ms);
+        let expires_ms = policy.expires_at(now_ms);
+
+        diesel::insert_into(rate_limits::table)
+            .values((
+                rate_limits::key.eq(key),
+                rate_limits::value.eq(state.value),
+                rate_limits::previous.eq(state.previous),
+                rate_limits::stamp_ms.eq(state.stamp_ms),
+                rate_limits::expires_ms.eq(expires_ms),
+            ))
+            .on_conflict(rate_limits::key)
+            .do_update()
+            .set((
+                rate_limits::value.eq(state.value),
+                rate_limits::previous.eq(state.previous),
+                rate_limits::stamp_ms.eq(state.stamp_ms),
+                rate_limits::expires_ms.eq(expires_ms),
+            ))
+            .execute(conn)?;
+
+        Ok(decision)
+    })
+}
+
+/// Deletes the rate limit counters that expired by `now_ms`. Returns how
+/// many went.
+pub fn sweep_rate_limits(
+    conn: &mut PgConnection,
+    now_ms: i64
+)
+-> Result<usize, anyhow::Error> {
+    let swept = diesel::delete(rate_limits::table.filter(rate_limits::expires_ms.le(now_ms)))
+        .execute(conn)?;
+
+    Ok(swept)
+}
+
+pub fn get_user(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<Option<User>, anyhow::Error> {
+    let user = queries::user(id).get_result(conn).optional()?;
+
     Ok(user)
 }
 
 pub fn create_liked_videos(
     conn: &mut PgConnection,
     id: i32,
-    title: &str,
     vid_id: i32,
-    video_type: VideoType
-)
--> Result<VideoTypeResult, anyhow::Error> {
-    match video_type {
-        VideoType::LIKED => {
-            let liked_vids = diesel::insert_into(liked_videos::table)
-                .values((
-                    liked_videos::title.eq(title),
-                    liked_videos::video_id.eq(vid_id),
-                    liked_videos::user_id.eq(id),
-                ))
-                .returning(LikedVideos::as_returning())
-                .get_result(conn)?;
-
-            Ok(VideoTypeResult::LIKED(liked_vids))
+    list: VideoList
+)
+-> Result<VideoListEntry, anyhow::Error> {
+    match list {
+        VideoList::Liked => {
+            let liked_vids = queries::like_video(id, vid_id).get_result(conn)?;
+
+            Ok(VideoListEntry::Liked(liked_vids))
         }
-        VideoType::WATCHED => {
-            let watched_vids = diesel::insert_into(watched_videos::table)
-                .values((
-                    watched_videos::title.eq(title),
-                    watched_videos::video_id.eq(vid_id),
-                    watched_videos::user_id.eq(id),
-                ))
-                .returning(WatchedVideos::as_returning())
-                .get_result(conn)?;
-
-            Ok(VideoTypeResult::WATCHED(watched_vids))
+        VideoList::Watched => {
+            let watched_vids = queries::mark_watched(id, vid_id).get_result(conn)?;
+
+            Ok(VideoListEntry::Watched(watched_vids))
         }
     }
 }
+
+pub fn delete_user_video(
+    conn: &mut PgConnection,
+    id: i32,
+    vid_id: i32,
+    list: VideoList
+)
+-> Result<usize, anyhow::Error> {
+    let deleted = match list {
+        VideoList::Liked => queries::unlike_video(id, vid_id).execute(conn)?,
+        VideoList::Watched => queries::delete_watch_row(id, vid_id).execute(conn)?,
+    };
+
+    Ok(deleted)
+}
+
+pub fn get_videos(
+    conn: &mut PgConnection
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::all_videos().load(conn)?;
+
+    Ok(videos)
+}
+
+pub fn create_video(
+    conn: &mut PgConnection,
+    form: VideoForm
+)
+-> Result<Video, anyhow::Error> {
+    let video = queries::insert_video(&form).get_result(conn)?;
+
+    Ok(video)
+}
+
+pub fn update_video(
+    conn: &mut PgConnection,
+    id: i32,
+    form: VideoForm
+)
+-> Result<Option<Video>, anyhow::Error> {
+    let video = queries::update_video(id, &form).get_result(conn).optional()?;
+
+    Ok(video)
+}
+
+pub fn delete_video(
+    conn: &mut PgConnection,
+    id: i32
+)
+-> Result<usize, anyhow::Error> {
+    let deleted = queries::delete_video(id).execute(conn)?;
+
+    Ok(deleted)
+}
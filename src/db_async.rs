@@ -0,0 +1,472 @@
+//! The queries of `db_actions` behind the user and video repositories, on
+//! diesel-async. Names and arguments match their blocking counterparts and
+//! both run the statements of `queries`; these take an `AsyncPgConnection`
+//! and are awaited instead of run in `metrics::block`. Only bcrypt still goes
+//! to the blocking thread pool.
+
+use std::collections::HashSet;
+use diesel::prelude::*;
+use chrono::NaiveDateTime;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use crate::auth::Credentials;
+use crate::db_actions::{dummy_hash, hash_password};
+use crate::metrics::block;
+use crate::models::{
+    DEFAULT_ROLE,
+    ContinueWatching,
+    Role,
+    User,
+    UserWithVideos,
+    Video,
+    VideoForm,
+    VideoList,
+    VideoListEntry,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::queries;
+use crate::settings::DatabaseSettings;
+use crate::throttle::ThrottleScope;
+
+
+pub type AsyncDbPool = Pool<AsyncPgConnection>;
+
+/// Connects to `database.url` with a pool of `database.async_pool_size()`
+/// connections, so that with the blocking pool the server stays within
+/// `database.pool_size`.
+pub fn connect(settings: &DatabaseSettings) -> AsyncDbPool {
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(settings.url.as_str());
+    Pool::builder(manager)
+        .max_size(settings.async_pool_size() as usize)
+        .build()
+        .expect("Failed to create async pool")
+}
+
+/// Hashes on the blocking thread pool, so bcrypt does not stall the worker.
+async fn hash_off_worker(password: &str, cost: u32) -> Result<String, anyhow::Error> {
+    let password = password.to_string();
+    Ok(block(move || hash_password(&password, cost)).await??)
+}
+
+/// Returns the account only when the email exists and the password matches.
+pub async fn authenticate(
+    creds: Credentials,
+    conn: &mut AsyncPgConnection,
+    bcrypt_cost: u32
+)
+-> Result<Option<User>, anyhow::Error> {
+    let user: Option<User> = queries::user_by_email(&creds.email).get_result(conn).await.optional()?;
+
+    let hash = user
+        .as_ref()
+        .map_or(dummy_hash(bcrypt_cost).to_string(), |user| user.password_hash.clone());
+    let verified = block(move || bcrypt::verify(&creds.password, &hash)).await??;
+
+    Ok(user.filter(|_| verified))
+}
+
+pub async fn find_user_by_email(
+    conn: &mut AsyncPgConnection,
+    email: &str
+)
+-> Result<User, anyhow::Error> {
+    let user = queries::user_by_email(email).get_result(conn).await?;
+
+    Ok(user)
+}
+
+/// The account with its liked videos and every video it has a watch row
+/// for, what `GET /user/{id}` answers.
+pub async fn get_everything(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<UserWithVideos, anyhow::Error>  {
+    let user: User = queries::user(id).get_result(conn).await?;
+    let liked_videos = queries::liked_videos(user.id).load(conn).await?;
+    let watched_videos = queries::watch_history(user.id).load(conn).await?;
+
+    let data = UserWithVideos {
+        user,
+        liked_videos,
+        watched_videos
+    };
+
+    Ok(data)
+}
+
+/// Every video the user has a watch row for, finished or not.
+pub async fn get_watch_history(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::watch_history(id).load(conn).await?;
+
+    Ok(videos)
+}
+
+pub async fn get_user_info(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let user: User = queries::user(id).get_result(conn).await?;
+    let videos = queries::liked_videos(user.id).load(conn).await?;
+
+    Ok(videos)
+}
+
+pub async fn get_watched_videos(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::watched_videos(id).load(conn).await?;
+
+    Ok(videos)
+}
+
+pub async fn get_continue_watching(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    limit: i64
+)
+-> Result<Vec<ContinueWatching>, anyhow::Error> {
+    let rows: Vec<(WatchedVideos, Video)> = queries::continue_watching(id, limit).load(conn).await?;
+    let videos = rows
+        .into_iter()
+        .map(|(progress, video)| ContinueWatching { video, progress })
+        .collect();
+
+    Ok(videos)
+}
+
+pub async fn upsert_watch_progress(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    vid_id: i32,
+    progress: WatchProgress
+)
+-> Result<WatchedVideos, anyhow::Error> {
+    let watched = queries::save_progress(id, vid_id, progress).get_result(conn).await?;
+
+    Ok(watched)
+}
+
+pub async fn create_user(
+    conn: &mut AsyncPgConnection,
+    creds: Credentials,
+    bcrypt_cost: u32
+)
+-> Result<User, anyhow::Error> {
+    let role = find_role(conn, DEFAULT_ROLE).await?
+        .ok_or_else(|| anyhow::Error::msg(format!("Role {} is missing", DEFAULT_ROLE)))?;
+    let hashed_password = hash_off_worker(&creds.password, bcrypt_cost).await?;
+    let user = queries::insert_user(creds.email, hashed_password, role.id).get_result(conn).await?;
+
+    Ok(user)
+}
+
+pub async fn get_roles(
+    conn: &mut AsyncPgConnection
+)
+-> Result<Vec<Role>, anyhow::Error> {
+    let roles = queries::all_roles().load(conn).await?;
+
+    Ok(roles)
+}
+
+pub async fn find_role(
+    conn: &mut AsyncPgConnection,
+    name: &str
+)
+-> Result<Option<Role>, anyhow::Error> {
+    let role = queries::role_by_name(name).get_result(conn).await.optional()?;
+
+    Ok(role)
+}
+
+pub async fn set_user_role(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    role_id: i32
+)
+-> Result<Option<User>, anyhow::Error> {
+    let user = queries::set_user_role(id, role_id).get_result(conn).await.optional()?;
+
+    Ok(user)
+}
+
+/// Loads a role with the names of all its permissions, including the ones
+/// inherited from its ancestors.
+pub async fn get_role_permissions(
+    conn: &mut AsyncPgConnection,
+    role_id: i32
+)
+-> Result<(Role, HashSet<String>), anyhow::Error> {
+    let roles = get_roles(conn).await?;
+    let role = roles.iter()
+        .find(|role| role.id == role_id)
+        .cloned()
+        .ok_or(diesel::result::Error::NotFound)?;
+
+    let mut chain = vec![role.id];
+    let mut parent = role.parent_id;
+    while let Some(id) = parent.filter(|id| !chain.contains(id)) {
+        chain.push(id);
+        parent = roles.iter().find(|role| role.id == id).and_then(|role| role.parent_id);
+    }
+
+    let permissions: Vec<String> = queries::permission_names(chain).load(conn).await?;
+
+    Ok((role, permissions.into_iter().collect()))
+}
+
+/// Stores a reset token for the user, dropping any token issued before it so
+/// only the latest mail works.
+pub async fn create_password_reset(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    token_hash: &str,
+    ttl_minutes: i32
+)
+-> Result<(), anyhow::Error> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| async move {
+        queries::delete_password_resets(id).execute(conn).await?;
+        queries::insert_password_reset(id, token_hash, ttl_minutes).execute(conn).await?;
+
+        Ok(())
+    }.scope_boxed())
+    .await
+}
+
+/// Consumes an unused, unexpired reset token and sets the new password,
+/// signing the account out everywhere and lifting its login lockout.
+/// Returns `false` when the token is unknown, expired or already used.
+pub async fn reset_password(
+    conn: &mut AsyncPgConnection,
+    token_hash: &str,
+    password: &str,
+    bcrypt_cost: u32
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| async move {
+        let user_id: Option<i32> = queries::use_password_reset(token_hash).get_result(conn).await.optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        let password_hash = hash_off_worker(password, bcrypt_cost).await?;
+        let email: String = queries::set_password_hash(user_id, password_hash).get_result(conn).await?;
+        queries::delete_sessions(user_id).execute(conn).await?;
+        queries::revoke_refresh_tokens(user_id).execute(conn).await?;
+        clear_throttle(conn, ThrottleScope::Account, &email).await?;
+
+        Ok(true)
+    }.scope_boxed())
+    .await
+}
+
+/// Stores a verification token for the user, replacing any earlier one.
+pub async fn create_email_verification(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    token_hash: &str,
+    ttl_minutes: i32
+)
+-> Result<(), anyhow::Error> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| async move {
+        queries::delete_email_verifications(id).execute(conn).await?;
+        queries::insert_email_verification(id, token_hash, ttl_minutes).execute(conn).await?;
+
+        Ok(())
+    }.scope_boxed())
+    .await
+}
+
+/// True when a verification mail went out to the user within the cooldown.
+pub async fn verification_sent_recently(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    cooldown_minutes: i32
+)
+-> Result<bool, anyhow::Error> {
+    let sent = queries::verification_sent_recently(id, cooldown_minutes).get_result(conn).await?;
+
+    Ok(sent)
+}
+
+/// Consumes an unexpired verification token and marks the email verified.
+/// Returns `false` when the token is unknown or expired.
+pub async fn verify_email(
+    conn: &mut AsyncPgConnection,
+    token_hash: &str
+)
+-> Result<bool, anyhow::Error> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| async move {
+        let user_id: Option<i32> = queries::use_email_verification(token_hash).get_result(conn).await.optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        queries::mark_email_verified(user_id).execute(conn).await?;
+
+        Ok(true)
+    }.scope_boxed())
+    .await
+}
+
+pub async fn has_two_factor(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<bool, anyhow::Error> {
+    let enabled = queries::has_two_factor(id).get_result(conn).await?;
+
+    Ok(enabled)
+}
+
+/// Seconds until the subject may try again, or `None` when it is not locked.
+pub async fn throttle_remaining(
+    conn: &mut AsyncPgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<Option<i64>, anyhow::Error> {
+    let row: Option<(Option<NaiveDateTime>, NaiveDateTime)> = queries::throttle_lock(scope, subject)
+        .get_result(conn).await
+        .optional()?;
+
+    let remaining = row.and_then(|(locked_until, db_now)| {
+        locked_until
+            .filter(|until| *until > db_now)
+            .map(|until| ((until - db_now).num_milliseconds() + 999) / 1000)
+    });
+
+    Ok(remaining)
+}
+
+/// Counts an attempt against the subject and locks it for the delay its
+/// scope's policy asks for. Returns that delay in seconds.
+pub async fn record_attempt(
+    conn: &mut AsyncPgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<i32, anyhow::Error> {
+    let policy = scope.policy();
+    conn.transaction::<_, anyhow::Error, _>(|conn| async move {
+        // Counters that went quiet for a whole window start over.
+        queries::expire_throttle(scope, subject, policy.window_seconds).execute(conn).await?;
+        let attempts: i32 = queries::count_attempt(scope, subject).get_result(conn).await?;
+
+        let delay = policy.delay_seconds(attempts);
+        if delay > 0 {
+            queries::lock_throttle(scope, subject, delay).execute(conn).await?;
+        }
+
+        Ok(delay)
+    }.scope_boxed())
+    .await
+}
+
+pub async fn clear_throttle(
+    conn: &mut AsyncPgConnection,
+    scope: ThrottleScope,
+    subject: &str
+)
+-> Result<usize, anyhow::Error> {
+    let cleared = queries::clear_throttle(scope, subject).execute(conn).await?;
+
+    Ok(cleared)
+}
+
+pub async fn get_user(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<Option<User>, anyhow::Error> {
+    let user = queries::user(id).get_result(conn).await.optional()?;
+
+    Ok(user)
+}
+
+pub async fn create_liked_videos(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    vid_id: i32,
+    list: VideoList
+)
+-> Result<VideoListEntry, anyhow::Error> {
+    match list {
+        VideoList::Liked => {
+            let liked_vids = queries::like_video(id, vid_id).get_result(conn).await?;
+
+            Ok(VideoListEntry::Liked(liked_vids))
+        }
+        VideoList::Watched => {
+            let watched_vids = queries::mark_watched(id, vid_id).get_result(conn).await?;
+
+            Ok(VideoListEntry::Watched(watched_vids))
+        }
+    }
+}
+
+pub async fn delete_user_video(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    vid_id: i32,
+    list: VideoList
+)
+-> Result<usize, anyhow::Error> {
+    let deleted = match list {
+        VideoList::Liked => queries::unlike_video(id, vid_id).execute(conn).await?,
+        VideoList::Watched => queries::delete_watch_row(id, vid_id).execute(conn).await?,
+    };
+
+    Ok(deleted)
+}
+
+pub async fn get_videos(
+    conn: &mut AsyncPgConnection
+)
+-> Result<Vec<Video>, anyhow::Error> {
+    let videos = queries::all_videos().load(conn).await?;
+
+    Ok(videos)
+}
+
+pub async fn create_video(
+    conn: &mut AsyncPgConnection,
+    form: VideoForm
+)
+-> Result<Video, anyhow::Error> {
+    let video = queries::insert_video(&form).get_result(conn).await?;
+
+    Ok(video)
+}
+
+pub async fn update_video(
+    conn: &mut AsyncPgConnection,
+    id: i32,
+    form: VideoForm
+)
+-> Result<Option<Video>, anyhow::Error> {
+    let video = queries::update_video(id, &form).get_result(conn).await.optional()?;
+
+    Ok(video)
+}
+
+pub async fn delete_video(
+    conn: &mut AsyncPgConnection,
+    id: i32
+)
+-> Result<usize, anyhow::Error> {
+    let deleted = queries::delete_video(id).execute(conn).await?;
+
+    Ok(deleted)
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use actix_session::{SessionGetError, SessionInsertError};
+use actix_web::{
+    HttpRequest, HttpResponse, ResponseError,
+    error::BlockingError,
+    http::{header::RETRY_AFTER, StatusCode},
+};
+use diesel::r2d2::PoolError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::ToSchema;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+
+/// Every error a handler, extractor or middleware answers with. Each variant
+/// picks the status code; the body is always an RFC 7807 `ProblemDetails`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request body, path or query does not pass validation
+    Validation(String),
+    /// The email address cannot be normalized, kept apart for its 406 status
+    InvalidEmail,
+    /// No session, token or key, or one that is not valid
+    Unauthorized(String),
+    /// The caller is known but may not do this
+    Forbidden(String),
+    NotFound(String),
+    /// The request clashes with existing data, like a taken email
+    Conflict(String),
+    /// A throttle or rate limit kicked in, retry after this many seconds
+    TooManyRequests { detail: String, retry_after: i64 },
+    /// A dependency like the database cannot serve the request right now
+    Unavailable(String),
+    /// Anything unexpected. Logged, but never shown to the client
+    Internal(anyhow::Error),
+}
+
+/// Error body of every endpoint, served as `application/problem+json`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct ProblemDetails {
+    /// Always `about:blank`, the status and title say what went wrong
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+}
+
+impl AppError {
+    /// The body sent for this error, also used for the OpenAPI examples.
+    pub fn problem(&self) -> ProblemDetails {
+        let status = self.status_code();
+        ProblemDetails {
+            problem_type: String::from("about:blank"),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+        }
+    }
+
+    fn from_diesel(err: DieselError) -> Self {
+        match err {
+            DieselError::NotFound => AppError::NotFound(String::from("Not found")),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                AppError::Conflict(String::from("Already exists"))
+            }
+            err => AppError::Internal(err.into()),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Validation(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Conflict(detail)
+            | AppError::TooManyRequests { detail, .. }
+            | AppError::Unavailable(detail) => f.write_str(detail),
+            AppError::InvalidEmail => f.write_str("Email provided is invalid! please check email"),
+            AppError::Internal(_) => f.write_str("Internal Server Error"),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidEmail => StatusCode::NOT_ACCEPTABLE,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        match self {
+            AppError::Internal(err) => error!(error = ?err, "request failed"),
+            AppError::TooManyRequests { retry_after, .. } => {
+                resp.insert_header((RETRY_AFTER, retry_after.to_string()));
+            }
+            _ => {}
+        }
+        resp.content_type(PROBLEM_CONTENT_TYPE).json(self.problem())
+    }
+}
+
+/// Errors from `db_actions` keep their meaning: an `AppError` raised inside a
+/// blocking closure passes through, and Diesel errors are mapped like
+/// `From<DieselError>` does.
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<AppError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        match err.downcast::<DieselError>() {
+            Ok(err) => AppError::from_diesel(err),
+            Err(err) => AppError::Internal(err),
+        }
+    }
+}
+
+impl From<DieselError> for AppError {
+    fn from(err: DieselError) -> Self {
+        AppError::from_diesel(err)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Error handler for the `Json`, `Query` and `Path` extractor configs, so a
+/// body or URL that does not parse gets a problem+json 400 too.
+pub fn validation_error<E: fmt::Display>(err: E, _req: &HttpRequest) -> actix_web::Error {
+    AppError::Validation(err.to_string()).into()
+}
+
+macro_rules! internal_from {
+    ($($err:ty),*) => {
+        $(impl From<$err> for AppError {
+            fn from(err: $err) -> Self {
+                AppError::Internal(err.into())
+            }
+        })*
+    };
+}
+
+internal_from!(BlockingError, PoolError, SessionGetError, SessionInsertError);
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use super::AppError;
+
+    #[test]
+    fn maps_diesel_errors_through_anyhow() {
+        let missing = AppError::from(anyhow::Error::from(DieselError::NotFound));
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+
+        let taken = DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, Box::new(String::from("taken")));
+        assert_eq!(AppError::from(anyhow::Error::from(taken)).status_code(), StatusCode::CONFLICT);
+
+        let raised = anyhow::Error::from(AppError::Forbidden(String::from("no")));
+        assert_eq!(AppError::from(raised).problem().status, 403);
+
+        let internal = AppError::from(anyhow::Error::msg("connection refused"));
+        assert_eq!(internal.problem().detail, "Internal Server Error");
+    }
+}
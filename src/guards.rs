@@ -1,26 +1,311 @@
+use std::collections::HashSet;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use actix_identity::IdentityExt;
 use actix_utils::future;
-use actix_web::{Error, FromRequest, dev, HttpRequest, error::{ErrorServiceUnavailable, ErrorUnauthorized}, HttpResponse};
+use actix_web::{
+    Error, FromRequest, dev, HttpMessage, HttpRequest, web,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::models::{ADMIN_ROLE, Role, User};
+use crate::tokens::bearer_token;
+use crate::ultils::utils::hash_token;
 
 
-pub struct SessionGuard {
-    pub session: Option<String>,
+fn state_missing() -> AppError {
+    AppError::Internal(anyhow::Error::msg("App state is not configured"))
 }
-impl FromRequest for SessionGuard {
 
+/// How a request claims to be a user.
+enum Credential {
+    /// The email stored by actix-identity in the login session
+    Session(String),
+    /// An `Authorization: Bearer` access token
+    Bearer(String),
+}
+
+/// The user behind the request, loaded from the database along with its role
+/// and every permission the role grants or inherits. Browsers authenticate
+/// with the login session, other clients with a bearer access token from
+/// `POST /token`; a bearer token wins when both are present. Rejects with 401
+/// when neither identifies a live account. The result is cached in the
+/// request extensions, so extracting it again in the same request is free.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub role: Role,
+    pub permissions: HashSet<String>,
+    /// Whether the account has confirmed a TOTP secret
+    pub two_factor: bool,
+}
+
+impl AuthenticatedUser {
+    pub fn id(&self) -> i32 {
+        self.user.id
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.contains(permission)
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+            return Box::pin(future::ok(user.clone()));
+        }
+        let credential = match bearer_token(req) {
+            Some(token) => Some(Credential::Bearer(token.to_string())),
+            None => req.get_identity().and_then(|identity| identity.id()).ok().map(Credential::Session),
+        };
+        let state = req.app_data::<web::Data<Arc<AppState>>>().cloned();
+        let req = req.clone();
+
+        Box::pin(async move {
+            let credential = credential.ok_or_else(|| AppError::Unauthorized(String::from("Not logged in")))?;
+            let state = state.ok_or_else(state_missing)?;
+            let user_id = match &credential {
+                Credential::Bearer(token) => Some(
+                    state.tokens.as_ref()
+                        .and_then(|keys| keys.verify(token))
+                        .ok_or_else(|| AppError::Unauthorized(String::from("Invalid access token")))?,
+                ),
+                Credential::Session(_) => None,
+            };
+            let user = match (credential, user_id) {
+                (_, Some(user_id)) => state.users.get(user_id).await?,
+                (Credential::Session(email), None) => state.users.find_by_email(&email).await?,
+                (Credential::Bearer(_), None) => None,
+            };
+            let user = user.ok_or_else(|| AppError::Unauthorized(String::from("Not logged in")))?;
+            let (role, permissions) = state.users.role_permissions(user.role_id).await?;
+            let two_factor = state.users.has_two_factor(user.id).await?;
+            let user = AuthenticatedUser { user, role, permissions, two_factor };
+
+            req.extensions_mut().insert(user.clone());
+            Ok(user)
+        })
+    }
+}
+
+/// Lets a request through only when its user has the permission. Works on
+/// any service through `.wrap()`, or on a handler through the route macros:
+/// `#[get("/videos", wrap = "RequirePermission(\"videos:read\")")]`.
+/// Requests without a session get 401, users lacking the permission 403.
+/// With `auth.require_admin_2fa` on, admin accounts also get 403 until they
+/// confirm a TOTP secret.
+#[derive(Clone, Copy)]
+pub struct RequirePermission(pub &'static str);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.0,
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
-    type Future = future::Ready<Result<Self, Self::Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
+    forward_ready!(service);
 
-    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
-        let sess_cookie = req.cookie("id");
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let permission = self.permission;
 
-        let session = sess_cookie.map(|c| c.value().to_string());
+        let require_admin_2fa = req.app_data::<web::Data<Arc<AppState>>>()
+            .is_some_and(|state| state.settings.auth.require_admin_2fa);
 
-        future::ok(SessionGuard {session})
+        Box::pin(async move {
+            let denied = match req.extract::<AuthenticatedUser>().await {
+                Ok(user) if require_admin_2fa && user.role.name == ADMIN_ROLE && !user.two_factor => {
+                    Some(AppError::Forbidden(String::from("Admin accounts must turn on two-factor authentication")))
+                }
+                Ok(user) if user.has_permission(permission) => None,
+                Ok(_) => Some(AppError::Forbidden(format!("Missing permission {}", permission))),
+                Err(err) => Some(err),
+            };
+            match denied {
+                Some(err) => Ok(req.error_response(err).map_into_right_body()),
+                None => service.call(req).await.map(ServiceResponse::map_into_left_body),
+            }
+        })
     }
+}
+
+/// A request authenticated by a live key in the `X-API-Key` header.
+/// Rejects with 401 when the header is missing or the key is unknown,
+/// revoked or expired; use `Option<ApiKeyAuth>` for optional keys. Cached in
+/// the request extensions like `AuthenticatedUser`.
+#[derive(Clone, Debug)]
+pub struct ApiKeyAuth {
+    pub key_id: i32,
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+}
 
-    fn extract(req: &HttpRequest) -> Self::Future {
-        Self::from_request(req, &mut dev::Payload::None)
+impl ApiKeyAuth {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
     }
 }
 
+impl FromRequest for ApiKeyAuth {
+    type Error = AppError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        if let Some(api_key) = req.extensions().get::<ApiKeyAuth>() {
+            return Box::pin(future::ok(api_key.clone()));
+        }
+        let key = req.headers()
+            .get("X-API-Key")
+            .and_then(|key| key.to_str().ok())
+            .map(hash_token);
+        let state = req.app_data::<web::Data<Arc<AppState>>>().cloned();
+        let req = req.clone();
+
+        Box::pin(async move {
+            let key_hash = key.ok_or_else(|| AppError::Unauthorized(String::from("Missing API key")))?;
+            let state = state.ok_or_else(state_missing)?;
+            let api_key = state.api_keys
+                .authenticate(&key_hash)
+                .await?
+                .ok_or_else(|| AppError::Unauthorized(String::from("Invalid API key")))?;
+
+            let api_key = ApiKeyAuth {
+                key_id: api_key.id,
+                user_id: api_key.user_id,
+                scopes: api_key.scopes,
+            };
+
+            req.extensions_mut().insert(api_key.clone());
+            Ok(api_key)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_identity::Identity;
+    use actix_web::{http::StatusCode, test, web, HttpMessage, HttpRequest, HttpResponse};
+    use crate::test_util::{bearer, call, TestState};
+    use crate::tokens::TokenKeys;
+    use super::{AuthenticatedUser, RequirePermission};
+
+    async fn log_in_as(req: HttpRequest, email: web::Path<String>) -> HttpResponse {
+        Identity::login(&req.extensions(), email.into_inner()).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    /// Extracts the user twice to go through the per request cache.
+    async fn whoami(user: AuthenticatedUser, again: AuthenticatedUser) -> HttpResponse {
+        assert_eq!(user.id(), again.id());
+        HttpResponse::Ok().body(user.role.name)
+    }
+
+    fn routes(cfg: &mut web::ServiceConfig) {
+        cfg.route("/as/{email}", web::post().to(log_in_as))
+            .route("/whoami", web::get().to(whoami))
+            .service(
+                web::resource("/catalog")
+                    .wrap(RequirePermission("videos:write"))
+                    .route(web::get().to(HttpResponse::Ok)),
+            );
+    }
+
+    fn get(uri: &str) -> test::TestRequest {
+        test::TestRequest::get().uri(uri)
+    }
+
+    #[actix_web::test]
+    async fn loads_role_and_rejects_sessions_of_deleted_users() {
+        let test = TestState::new();
+        let admin = test.user("admin@example.com", "admin").await;
+        let app = test.app(routes).await;
+
+        let reply = call(&app, get("/whoami")).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+
+        let session = call(&app, test::TestRequest::post().uri("/as/admin@example.com")).await.cookie();
+        let reply = call(&app, get("/whoami").cookie(session.clone())).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, "admin");
+
+        test.users.remove(admin.id).unwrap();
+        let reply = call(&app, get("/whoami").cookie(session)).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn require_permission_follows_role_inheritance() {
+        let test = TestState::new();
+        test.user("user@example.com", "user").await;
+        test.user("admin@example.com", "admin").await;
+        let app = test.app(routes).await;
+
+        let reply = call(&app, get("/catalog")).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+
+        let session = call(&app, test::TestRequest::post().uri("/as/user@example.com")).await.cookie();
+        let reply = call(&app, get("/catalog").cookie(session)).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+
+        // `videos:write` belongs to moderator, which admin inherits from.
+        let session = call(&app, test::TestRequest::post().uri("/as/admin@example.com")).await.cookie();
+        let reply = call(&app, get("/catalog").cookie(session)).await;
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn accepts_bearer_tokens_in_place_of_a_session() {
+        let test = TestState::new();
+        let moderator = test.user("moderator@example.com", "moderator").await;
+        let app = test.app(routes).await;
+
+        let token = test.token(moderator.id);
+        let reply = call(&app, bearer(get("/whoami"), &token)).await;
+        assert_eq!(reply.body, "moderator");
+        let reply = call(&app, bearer(get("/catalog"), &token)).await;
+        assert_eq!(reply.status, StatusCode::OK);
+
+        let forged = TokenKeys::from_secret(b"some-other-secret-of-32-bytes!!!").issue(moderator.id).unwrap();
+        let reply = call(&app, bearer(get("/whoami"), &forged)).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+
+        test.users.remove(moderator.id).unwrap();
+        let reply = call(&app, bearer(get("/whoami"), &token)).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{HttpResponse, web, get, rt::time::timeout};
+use diesel::{sql_query, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::RequirePermission;
+use crate::metrics::block;
+use crate::migrations::{MigrationStatus, migration_status, pending_migrations};
+
+/// How long `/readyz` waits for a connection and an answer to `SELECT 1`.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct Health {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct PoolStatus {
+    /// Connections the pool has open
+    pub connections: u32,
+    /// Open connections waiting to be checked out
+    pub idle: u32,
+    /// Connections checked out by requests right now
+    pub active: u32,
+    pub max_size: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct ServerStatus {
+    /// Version of the crate the server was built from
+    pub version: String,
+    pub uptime_seconds: u64,
+    pub pool: PoolStatus,
+    pub migrations: MigrationStatus,
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "The process is up and serving requests",
+            body = Health
+        ),
+    )
+)]
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Health { status: String::from("ok") })
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "The database answers and its schema is current",
+            body = Health
+        ),
+        (
+            status = 503,
+            description = "The database is unreachable, slow or missing migrations",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unavailable(String::from("Database did not answer in time")).problem())
+        ),
+    )
+)]
+#[get("/readyz")]
+pub async fn readyz(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let check = block(move || {
+        let mut conn = state.pool.get_timeout(READY_TIMEOUT)?;
+        sql_query("SELECT 1").execute(&mut conn)?;
+        pending_migrations(&mut conn)
+    });
+
+    let pending = match timeout(READY_TIMEOUT, check).await {
+        Err(_) => return Err(AppError::Unavailable(String::from("Database did not answer in time"))),
+        Ok(result) => result?.map_err(|err| {
+            warn!(error = %err, "readiness check failed");
+            AppError::Unavailable(String::from("Database is unreachable"))
+        })?,
+    };
+    if !pending.is_empty() {
+        return Err(AppError::Unavailable(format!(
+            "Database schema is behind by {} migrations",
+            pending.len()
+        )));
+    }
+
+    Ok(HttpResponse::Ok().json(Health { status: String::from("ready") }))
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Reports the build version, uptime, connection pool and migrations",
+            body = ServerStatus
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the system:status permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission system:status")).problem())
+        ),
+    )
+)]
+#[get("/status", wrap = "RequirePermission(\"system:status\")")]
+pub async fn status(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let pool_state = state.pool.state();
+    let pool = PoolStatus {
+        connections: pool_state.connections,
+        idle: pool_state.idle_connections,
+        active: pool_state.connections - pool_state.idle_connections,
+        max_size: state.pool.max_size(),
+    };
+    let uptime_seconds = state.started_at.elapsed().as_secs();
+
+    let migrations = block(move || {
+        let mut conn = state.pool.get()?;
+        migration_status(&mut conn)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(ServerStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_seconds,
+        pool,
+        migrations,
+    }))
+}
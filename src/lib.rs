@@ -0,0 +1,188 @@
+pub mod schema;
+pub mod models;
+pub mod api_keys;
+pub mod app;
+pub mod auth;
+pub mod db_actions;
+#[cfg(feature = "async-db")]
+pub mod db_async;
+pub mod errors;
+pub mod guards;
+pub mod health;
+pub mod mailer;
+pub mod metrics;
+pub mod migrations;
+pub mod queries;
+pub mod rate_limit;
+pub mod repository;
+pub mod roles;
+pub mod session_store;
+pub mod sessions;
+pub mod settings;
+pub mod telemetry;
+#[cfg(test)]
+mod test_util;
+pub mod throttle;
+pub mod tokens;
+pub mod two_factor;
+pub mod ultils;
+pub mod users;
+pub mod videos;
+
+use std::sync::Arc;
+use std::time::Instant;
+use diesel::{
+    r2d2::{self,ConnectionManager},
+    PgConnection
+};
+use crate::{
+    mailer::Mailer,
+    repository::{
+        ApiKeyRepository,
+        Repositories,
+        SessionRepository,
+        TwoFactorRepository,
+        UserRepository,
+        VideoRepository,
+    },
+    settings::{DatabaseSettings, Settings},
+    tokens::TokenKeys,
+};
+
+pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+pub struct AppState {
+    /// Only for what is about the database itself: health, readiness,
+    /// migration status and pool metrics. Data goes through the
+    /// repositories.
+    pub(crate) pool: DbPool,
+    /// Accounts, roles, throttles and account tokens
+    pub users: Arc<dyn UserRepository>,
+    /// The catalog and the users' lists
+    pub videos: Arc<dyn VideoRepository>,
+    /// Server-side sessions and refresh tokens
+    pub sessions: Arc<dyn SessionRepository>,
+    pub api_keys: Arc<dyn ApiKeyRepository>,
+    pub two_factor: Arc<dyn TwoFactorRepository>,
+    pub settings: Settings,
+    pub mailer: Box<dyn Mailer>,
+    /// Signing keys for bearer tokens, `None` when token mode is off
+    pub tokens: Option<TokenKeys>,
+    /// When the server started, for the uptime in `/status`
+    pub started_at: Instant,
+}
+
+impl AppState {
+    /// The state the server runs on, with the Postgres repositories over
+    /// `pool`.
+    pub fn new(settings: Settings, pool: DbPool, mailer: Box<dyn Mailer>, tokens: Option<TokenKeys>) -> Self {
+        let repositories = repository::postgres(&settings.database, &pool);
+        AppState::with_repositories(settings, pool, repositories, mailer, tokens)
+    }
+
+    fn with_repositories(
+        settings: Settings,
+        pool: DbPool,
+        repositories: Repositories,
+        mailer: Box<dyn Mailer>,
+        tokens: Option<TokenKeys>
+    ) -> Self {
+        AppState {
+            pool,
+            users: repositories.users,
+            videos: repositories.videos,
+            sessions: repositories.sessions,
+            api_keys: repositories.api_keys,
+            two_factor: repositories.two_factor,
+            settings,
+            mailer,
+            tokens,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+/// Builds `AppState` for tests. Starts from the in-memory repositories, a
+/// pool that never connects, logged mail and no token mode, with bcrypt at
+/// its lowest cost and email verification off so accounts can log in right
+/// after signing up. Integration tests get it through the `test-util`
+/// feature.
+#[cfg(any(test, feature = "test-util"))]
+pub struct AppStateBuilder {
+    pool: DbPool,
+    repositories: Repositories,
+    settings: Settings,
+    mailer: Box<dyn Mailer>,
+    tokens: Option<TokenKeys>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl AppState {
+    pub fn builder() -> AppStateBuilder {
+        let mut settings = Settings::default();
+        settings.auth.bcrypt_cost = 4;
+        settings.auth.require_email_verification = false;
+        AppStateBuilder {
+            pool: r2d2::Pool::builder()
+                .min_idle(Some(0))
+                .build_unchecked(ConnectionManager::new("postgres://localhost/unused")),
+            repositories: Repositories::memory(Arc::default(), Arc::default()),
+            settings,
+            mailer: Box::new(mailer::LogMailer),
+            tokens: None,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl AppStateBuilder {
+    /// Runs on `pool` and the Postgres repositories over it.
+    pub fn postgres(mut self, database: &DatabaseSettings, pool: DbPool) -> Self {
+        self.repositories = repository::postgres(database, &pool);
+        self.pool = pool;
+        self
+    }
+
+    /// Keeps accounts, sessions, API keys and two-factor in `accounts`.
+    pub fn accounts(mut self, accounts: Arc<repository::MemoryUserRepository>) -> Self {
+        self.repositories.users = accounts.clone();
+        self.repositories.sessions = accounts.clone();
+        self.repositories.api_keys = accounts.clone();
+        self.repositories.two_factor = accounts;
+        self
+    }
+
+    pub fn videos(mut self, videos: Arc<dyn VideoRepository>) -> Self {
+        self.repositories.videos = videos;
+        self
+    }
+
+    pub fn settings(mut self, configure: impl FnOnce(&mut Settings)) -> Self {
+        configure(&mut self.settings);
+        self
+    }
+
+    pub fn mailer(mut self, mailer: impl Mailer + 'static) -> Self {
+        self.mailer = Box::new(mailer);
+        self
+    }
+
+    pub fn tokens(mut self, tokens: TokenKeys) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    pub fn build(self) -> Arc<AppState> {
+        Arc::new(AppState::with_repositories(self.settings, self.pool, self.repositories, self.mailer, self.tokens))
+    }
+}
+
+/// Connects to `database.url`, for the server and the `admin` binary alike,
+/// with a pool of `database.blocking_pool_size()` connections.
+pub fn connect(settings: &DatabaseSettings) -> DbPool {
+    let manager = r2d2::ConnectionManager::<PgConnection>::new(settings.url.as_str());
+    r2d2::Pool::builder()
+        .max_size(settings.blocking_pool_size())
+        .build(manager)
+        .expect("Failed to create pool")
+}
@@ -0,0 +1,60 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use serde_json::json;
+use tracing::info;
+use crate::settings::MailSettings;
+
+
+/// Delivers account mail such as password reset tokens.
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), anyhow::Error>;
+}
+
+/// Writes outgoing mail to the log instead of delivering it.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), anyhow::Error> {
+        info!(to, subject, body, "outgoing mail");
+        Ok(())
+    }
+}
+
+/// Appends every mail to a file as one JSON object per line, so dev setups
+/// and tests can read the messages back.
+pub struct FileMailer {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileMailer {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), anyhow::Error> {
+        let _guard = self.lock.lock().map_err(|_| anyhow::Error::msg("Mail outbox lock poisoned"))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = json!({ "to": to, "subject": subject, "body": body });
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Uses a `FileMailer` when `mail.outbox` names a file, otherwise logs.
+pub fn mailer_from_settings(settings: &MailSettings) -> Box<dyn Mailer> {
+    match &settings.outbox {
+        Some(path) => Box::new(FileMailer::new(path)),
+        None => Box::new(LogMailer),
+    }
+}
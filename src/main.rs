@@ -1,272 +1,111 @@
-use actix_cors::Cors;
-use actix_files::{NamedFile, Files};
-use actix_identity::{IdentityMiddleware, Identity};
-use actix_session::{
-    config::{PersistentSession, CookieContentSecurity},
-    storage::CookieSessionStore,
-    SessionMiddleware, Session
-};
 use actix_web::{
-    cookie::{Key,SameSite},
-    App,
-    HttpResponse,
+    cookie::Key,
     HttpServer,
-    Result,
-    error::{ErrorInternalServerError, HttpError},
-    web, Responder, HttpRequest, Error, get,
-};
-use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
-    Modify, OpenApi,
 };
-use utoipa_swagger_ui::SwaggerUi;
-
-use auth::sign_up;
-use cookie::time::Duration;
-use guards::SessionGuard;
-use models::VideoType;
-use tracing::info;
 
-
-use diesel::{
-    r2d2::{self,ConnectionManager},
-    PgConnection
-};
 use dotenv::dotenv;
-use std::{env, sync::{Arc, Mutex}, path::PathBuf};
+use clap::Parser;
+use tracing::{info, warn};
+use std::{process, sync::Arc};
 use std::io;
-pub mod schema;
-pub mod models;
-pub mod auth;
-pub mod db_actions;
-pub mod guards;
-pub mod ultils;
 
-use crate::{
-    auth::{login,logout},
-    models::{SwaggerErrorResponse, Role}
+use actix_diesel::{
+    AppState, DbPool, connect,
+    app::build_app,
+    mailer::mailer_from_settings,
+    migrations::{pending_migrations, run_migrations},
+    settings::Settings,
+    telemetry,
+    tokens::TokenKeys,
 };
-use crate::db_actions::{
-    get_everything,
-    create_liked_videos
-};
-
-pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
-pub type DbError = Box<dyn std::error::Error + Send + Sync>;
-
-const ONEDAY: Duration = Duration::days(1);
-const _ONEMIN: Duration = Duration::minutes(1);
-
-pub struct AppState {
-    pub pool: DbPool,
-    pub api_keys: Mutex<Vec<String>>,
-}
-
-
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    // env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    // info!("staring server at http://localhost:8080");
-    let key = Key::generate();
-     dotenv().ok();
-    let database_url = env::var("DATABASE_URL")
-        .expect("Database url in .env must be set dude!");
-    let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
-    let pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool");
-    let state = Arc::new(AppState {
-        pool,
-        api_keys: Mutex::new(Vec::new()),
-    });
-
-    #[derive(OpenApi)]
-    #[openapi(
-        paths(
-            auth::sign_up,
-            auth::login,
-            auth::logout,
-            user_data
-        ),
-        components (
-            schemas(
-                models::LikedVideos,
-                models::WatchedVideos,
-                models::UserWithVideos,
-                models::User,
-                models::SwaggerErrorResponse,
-                auth::Credentials
-            )
-        )
-    )]
-    struct ApiDoc;
-
-    let openapi = ApiDoc::openapi();
-
-    HttpServer::new(move|| {
-          let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .expose_any_header();
-        App::new()
-            .wrap(IdentityMiddleware::default())
-            .wrap(
-                SessionMiddleware::builder(
-                    CookieSessionStore::default(), key.clone(),
-                )
-                .cookie_http_only(true)
-                .cookie_content_security(CookieContentSecurity::Private)
-                .cookie_same_site(SameSite::Strict)
-                .session_lifecycle(PersistentSession::default().session_ttl(ONEDAY))
-                .build(),
-            )
-            .wrap(cors)
-            .app_data(web::Data::new(state.clone()))
-            .service(sign_up)
-            .service(login)
-            .service(logout)
-            .service(user_data)
-            .service(
-                SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-doc/openapi.json", openapi.clone()),
-            )
-            // .service(
-            //     Files::new("/","./static")
-            //     .show_files_listing()
-            //         .index_file("index.html")
-            //         .use_last_modified(true)
-            // )
-            // .route("/", web::get().to(index))
-            // .route("/logot", web::post().to(logout))
-            // .service(web::scope("/user")
-            //     .route("/secret", web::get().to(secret))
-            //     .route("/info/{user_id}", web::get().to(user_data))
-            //     .route("/video/{id}/{title}/{vid_id}/{video_type}", web::post().to(new_video))
-            // )
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+use actix_diesel::rate_limit::{RateLimiter, RateLimitStore, MemoryStore, PgStore, StoreKind};
+
+/// Command line of the server, everything else comes from `Settings`.
+#[derive(Parser)]
+#[command(about = "Serves the video library API")]
+struct Args {
+    /// Exit instead of starting when the database is missing migrations
+    #[arg(long)]
+    check: bool,
 }
 
-
-
-async fn index(
-    _user: Option<Identity>, 
-    _req: HttpRequest
-) -> Result<impl Responder> {
-    // if let Some(_user) = user {
-        let path: PathBuf = "./static/index.html".parse().unwrap();
-        Ok(NamedFile::open(path).unwrap())
-    // } else {
-    //     Err(HttpResponse::Unauthorized().body("not signed in or logged in"))
-    // }
-
-}
-
-
-#[utoipa::path(
-    responses(
-        (
-            status = 200,
-            description = "Fetches a specific user",
-            body = UserWithVideos
-        ),
-        (
-            status = 401,
-            description = "Not authorized",
-            body = SwaggerErrorResponse,
-            example = json!(SwaggerErrorResponse::Unauthorized(String::from("Not authorized User")))
-        ),
-    )
-)]
-#[get("/user/{id}")]
-async fn user_data(
-    state: web::Data<Arc<AppState>>,
-    path: web::Path<i32>,
-    session_guard: SessionGuard,
-    session: Session
-)
--> Result<HttpResponse> {
-    if let Some(_sess) =  session_guard.session {
-        if let Some(role) = session.get::<String>("role").unwrap() {
-            let user_role = Role::try_from(role).unwrap();
-            match user_role {
-                Role::ADMIN => {
-                    let user_id = path.into_inner();
-                    let info = web::block(move || {
-                        let mut conn = state.pool.get()?;
-                        get_everything(&mut conn, user_id)
-
-                    })
-                    .await?;
-                    Ok(HttpResponse::Ok().json(info.unwrap()))
-                },
-                Role::User => {
-                    Ok(HttpResponse::Unauthorized().body("Not authorized!"))
-                }
-            }
-        } else {
-        Ok(HttpResponse::Unauthorized().body("Not authorized!"))
+/// Applies pending migrations when `database.run_migrations` is on, then with
+/// `--check` refuses to go on while any are left.
+fn prepare_schema(pool: &DbPool, run: bool, check: bool) -> Result<(), anyhow::Error> {
+    if !run && !check {
+        return Ok(());
+    }
+    let mut conn = pool.get()?;
+    if run {
+        for version in run_migrations(&mut conn)? {
+            info!(version, "applied migration");
+        }
+    }
+    if check {
+        let pending = pending_migrations(&mut conn)?;
+        if !pending.is_empty() {
+            return Err(anyhow::Error::msg(format!(
+                "Database schema is behind, pending migrations: {}",
+                pending.join(", ")
+            )));
         }
-    } else {
-        Ok(HttpResponse::Unauthorized().body("Not authorized!"))
     }
-    
+    Ok(())
 }
 
-// async fn new_user(
-//     state: web::Data<Arc<AppState>>,
-//     path: web::Path<(String, String)>,
-// )
-// -> Result<HttpResponse> {
-//     let (email, password) = path.into_inner();
-//     match validate_email(&email) {
-//         Ok(_) => {
-//             let user = web::block(move|| {
-//                 let mut conn = state.pool.get()?;
-//                 create_user(&mut conn,&email,password)
-//             })
-//                 .await?
-//                 .map_err(|err| ErrorInternalServerError(err))?;
-//
-//             Ok(HttpResponse::Ok().json(user))
-//         },
-//             Err(err) => {
-//             Ok(err)
-//         }
-//     }
-//    
-// }
-
-
-async fn new_video(
-    state: web::Data<Arc<AppState>>,
-    path: web::Path<(i32,String,i32,String)>,
-)
--> Result<HttpResponse> {
-    let (id,title,vid_id,video_type) = path.into_inner();
-    let video_type =  video_type.parse::<VideoType>().expect("expected this from str to work!");
-    info!("video type : {:?}",video_type);
-    match video_type {
-        VideoType::LIKED => {
-            let liked_vid = web::block(move|| {
-                let mut conn = state.pool.get()?;
-                create_liked_videos(&mut conn, id,title.as_str(),vid_id,models::VideoType::LIKED)
-            })
-                .await?
-                .map_err(|err| ErrorInternalServerError(err))?;
-
-            Ok(HttpResponse::Ok().json(liked_vid))
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    dotenv().ok();
+    let args = Args::parse();
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
         }
-        VideoType::WATCHED => {
-            let watched_vid = web::block(move|| {
-                let mut conn = state.pool.get()?;
-                create_liked_videos(&mut conn, id,title.as_str(),vid_id,models::VideoType::WATCHED)
-            })
-                .await?
-                .map_err(|err| ErrorInternalServerError(err))?;
-
-            Ok(HttpResponse::Ok().json(watched_vid))
-        },
+    };
+    let telemetry = match telemetry::init(&settings.tracing) {
+        Ok(telemetry) => telemetry,
+        Err(err) => {
+            eprintln!("Failed to set up tracing: {}", err);
+            process::exit(1);
+        }
+    };
+    // `Settings::load` turned a missing or short key away outside development
+    let key = match &settings.session.key {
+        Some(secret) => Key::from(secret.as_bytes()),
+        None => {
+            warn!("session.key is not set, sessions will not survive a restart");
+            Key::generate()
+        }
+    };
+    let tokens = match TokenKeys::from_settings(&settings.tokens) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            eprintln!("Invalid token keys: {}", err);
+            process::exit(1);
+        }
+    };
+    let pool = connect(&settings.database);
+    if let Err(err) = prepare_schema(&pool, settings.database.run_migrations, args.check) {
+        eprintln!("{}", err);
+        process::exit(1);
     }
+    let rate_limits = settings.rate_limit.clone();
+    let rate_limit_store: Arc<dyn RateLimitStore> = match rate_limits.store {
+        StoreKind::Memory => Arc::new(MemoryStore::default()),
+        StoreKind::Postgres => Arc::new(PgStore::new(pool.clone())),
+    };
+    let rate_limiter = RateLimiter::new(rate_limits, rate_limit_store);
+    let address = (settings.server.host.clone(), settings.server.port);
+    let mailer = mailer_from_settings(&settings.mail);
+    let state = Arc::new(AppState::new(settings, pool, mailer, tokens));
+
+    let server = HttpServer::new(move || build_app(state.clone(), key.clone(), rate_limiter.clone()))
+    .bind(address)?
+    .run()
+    .await;
+
+    telemetry.shutdown();
+    server
 }
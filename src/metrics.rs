@@ -0,0 +1,227 @@
+use std::any::type_name;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use actix_web::{
+    HttpResponse, web, get,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::info_span;
+use crate::AppState;
+use crate::errors::AppError;
+
+/// Route label of requests that matched no route, so scanners cannot blow up
+/// the number of series.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+
+/// Everything `/metrics` reports. Kept process wide, like the registry of
+/// most Prometheus clients, so any code path can count without being handed
+/// the `AppState`.
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub db_pool_connections: IntGaugeVec,
+    pub blocking_wait: Histogram,
+    pub signups: IntCounter,
+    pub logins: IntCounter,
+    pub failed_logins: IntCounter,
+    pub likes: IntCounter,
+    pub watches: IntCounter,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Failed to register metrics"))
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| -> Result<IntCounter, prometheus::Error> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests answered, by route and status class"),
+                &["method", "route", "status"],
+            )?,
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time taken to answer a request"),
+                &["method", "route"],
+            )?,
+            db_pool_connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Open database connections, idle or checked out"),
+                &["state"],
+            )?,
+            blocking_wait: Histogram::with_opts(
+                HistogramOpts::new("blocking_queue_wait_seconds", "Time work waited for a thread of the blocking pool")
+                    .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            )?,
+            signups: counter("signups_total", "Accounts created through sign up")?,
+            logins: counter("logins_total", "Completed logins, by session or token")?,
+            failed_logins: counter("failed_logins_total", "Logins rejected for a wrong email or password")?,
+            likes: counter("video_likes_total", "Videos added to a liked list")?,
+            watches: counter("video_watches_total", "Videos added to a watched list")?,
+            registry: registry.clone(),
+        };
+        registry.register(Box::new(metrics.http_requests.clone()))?;
+        registry.register(Box::new(metrics.http_request_duration.clone()))?;
+        registry.register(Box::new(metrics.db_pool_connections.clone()))?;
+        registry.register(Box::new(metrics.blocking_wait.clone()))?;
+        Ok(metrics)
+    }
+
+    /// The registry in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// `web::block` that records how long the closure queued for a thread of the
+/// blocking pool, and runs it in a `db` span under the request's span. Every
+/// database call of a request goes through it.
+pub async fn block<F, R>(f: F) -> Result<R, actix_web::error::BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let queued = Instant::now();
+    let span = info_span!("db", call = type_name::<F>());
+    web::block(move || {
+        let _entered = span.entered();
+        metrics().blocking_wait.observe(queued.elapsed().as_secs_f64());
+        f()
+    })
+    .await
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Request, pool and business metrics in the Prometheus text format",
+            content_type = "text/plain; version=0.0.4",
+            body = String
+        ),
+    )
+)]
+#[get("/metrics")]
+pub async fn metrics_endpoint(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let metrics = metrics();
+    let pool = state.pool.state();
+    metrics.db_pool_connections.with_label_values(&["idle"]).set(i64::from(pool.idle_connections));
+    metrics.db_pool_connections
+        .with_label_values(&["active"])
+        .set(i64::from(pool.connections - pool.idle_connections));
+
+    let body = metrics.render().map_err(|err| AppError::Internal(err.into()))?;
+    Ok(HttpResponse::Ok()
+        .content_type(TextEncoder::new().format_type())
+        .body(body))
+}
+
+/// Counts every request and times it, labelled by method, route pattern and
+/// status class. Register it last so it wraps the other middleware and sees
+/// the requests they turn away too.
+#[derive(Clone, Copy, Default)]
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let method = req.method().to_string();
+        let route = req.match_pattern().unwrap_or_else(|| String::from(UNMATCHED_ROUTE));
+        let started = Instant::now();
+
+        Box::pin(async move {
+            let res = service.call(req).await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            let metrics = metrics();
+            let class = format!("{}xx", status.as_u16() / 100);
+            metrics.http_requests.with_label_values(&[&method, &route, &class]).inc();
+            metrics.http_request_duration
+                .with_label_values(&[&method, &route])
+                .observe(started.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+    use super::{metrics, RequestMetrics, UNMATCHED_ROUTE};
+
+    #[actix_web::test]
+    async fn labels_requests_by_route_pattern() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestMetrics)
+                .route("/metrics-test/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let requests = &metrics().http_requests;
+        let matched = requests.with_label_values(&["GET", "/metrics-test/{id}", "2xx"]);
+        let unmatched = requests.with_label_values(&["GET", UNMATCHED_ROUTE, "4xx"]);
+        let (matched_before, unmatched_before) = (matched.get(), unmatched.get());
+
+        for uri in ["/metrics-test/1", "/metrics-test/2", "/nowhere"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        assert_eq!(matched.get() - matched_before, 2);
+        assert!(unmatched.get() > unmatched_before);
+        assert!(metrics().render().unwrap().contains("route=\"/metrics-test/{id}\""));
+    }
+}
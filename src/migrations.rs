@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use actix_web::{HttpResponse, web, get};
+use diesel::{sql_query, sql_types::BigInt, PgConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::AppState;
+use crate::errors::AppError;
+use crate::metrics::block;
+
+/// The `migrations` directory, compiled into every binary of the crate.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+/// Key of the Postgres advisory lock held while migrating, so instances
+/// booting together take turns instead of racing on the same DDL.
+const MIGRATION_LOCK_KEY: i64 = 0x6163_7469_7864_6965;
+
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct MigrationStatus {
+    /// Version of the newest applied migration, `None` on an empty database
+    pub version: Option<String>,
+    /// Embedded migrations the database has not applied yet, oldest first
+    pub pending: Vec<String>,
+}
+
+/// Applies the pending migrations under the advisory lock and returns the
+/// versions applied. An instance waiting on the lock finds nothing left to
+/// do once it gets it.
+pub fn run_migrations(conn: &mut PgConnection) -> Result<Vec<String>, anyhow::Error> {
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(ToString::to_string).collect())
+        .map_err(|err| anyhow::anyhow!(err));
+    sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+
+    applied
+}
+
+/// Names of the embedded migrations the database is missing.
+pub fn pending_migrations(conn: &mut PgConnection) -> Result<Vec<String>, anyhow::Error> {
+    let pending = conn
+        .pending_migrations(MIGRATIONS)
+        .map_err(|err| anyhow::anyhow!(err))?;
+
+    Ok(pending.iter().map(|migration| migration.name().to_string()).collect())
+}
+
+pub fn migration_status(conn: &mut PgConnection) -> Result<MigrationStatus, anyhow::Error> {
+    let version = conn
+        .applied_migrations()
+        .map_err(|err| anyhow::anyhow!(err))?
+        .into_iter()
+        .max()
+        .map(|version| version.to_string());
+
+    Ok(MigrationStatus {
+        version,
+        pending: pending_migrations(conn)?,
+    })
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Reports the newest applied migration and the ones still pending",
+            body = MigrationStatus
+        ),
+    )
+)]
+#[get("/status/migrations")]
+pub async fn get_migration_status(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let status = block(move || {
+        let mut conn = state.pool.get()?;
+        migration_status(&mut conn)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(status))
+}
@@ -1,126 +1,274 @@
-use std::str::FromStr;
-
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use serde::{Serialize, Deserialize};
 use utoipa::ToSchema;
-use crate::schema::{users,liked_videos,watched_videos};
+use crate::schema::{users,liked_videos,watched_videos,videos,api_keys,roles,sessions,totp_secrets};
 
 
-#[derive(Deserialize,Serialize,Clone,Debug)]
-pub enum Role {
-    User,
-    ADMIN
+/// A named set of permissions. A role also has every permission of its
+/// parent, so `admin` inherits from `moderator`, which inherits from `user`.
+#[derive(ToSchema,Queryable, Identifiable, Selectable, Debug, PartialEq, Clone, Serialize,Deserialize)]
+#[diesel(table_name = roles)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub parent_id: Option<i32>
 }
 
-impl TryFrom<String> for Role {
-    type Error = anyhow::Error;
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.as_str() {
-             "User" => Ok(Role::User),
-            "ADMIN" => Ok(Role::ADMIN),
-            _ => {
-            Err(anyhow::Error::msg("Try From<String> Couldn't convert in Role Enum"))
-            }
-        }
-    }
-}
+/// Role every new account starts with.
+pub const DEFAULT_ROLE: &str = "user";
+/// Role `admin create-admin` and `admin promote` give, and the one
+/// `require_admin_2fa` holds to a second factor.
+pub const ADMIN_ROLE: &str = "admin";
 
-impl FromStr for Role {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "User" => Ok(Role::User),
-            "ADMIN" => Ok(Role::ADMIN),
-            _ => {
-            Err(anyhow::Error::msg("FromStr Couldn't convert in Role Enum"))
-            }
-        }
-    }
+/// A role with everything it grants, inherited permissions included.
+#[derive(ToSchema,Serialize,Clone,Debug)]
+pub struct RoleWithPermissions {
+    #[serde(flatten)]
+    pub role: Role,
+    pub permissions: Vec<String>
 }
 
-
+#[derive(ToSchema,Deserialize,Serialize,Clone,Debug)]
+pub struct AssignRole {
+    pub role: String
+}
 
 #[derive(ToSchema,Queryable, Identifiable, Selectable, Debug, PartialEq, Clone, Serialize,Deserialize)]
 #[diesel(table_name = users)]
 pub struct User {
     pub id: i32,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password_hash: String,
-    pub role: Option<String>
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub role_id: i32
 }
 
-#[derive(ToSchema,Queryable, Selectable, Identifiable, Associations, Debug, PartialEq, Serialize,Deserialize)]
+/// Lets an API key read the liked, watched and in-progress videos of its owner.
+pub const LIBRARY_READ_SCOPE: &str = "library:read";
+/// Scopes an API key can be granted.
+pub const API_KEY_SCOPES: [&str; 1] = [LIBRARY_READ_SCOPE];
+
+#[derive(ToSchema,Queryable, Selectable, Identifiable, Associations, Debug, PartialEq, Clone, Serialize)]
 #[diesel(belongs_to(User))]
+#[diesel(table_name = api_keys)]
+pub struct UserApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// First characters of the key, to tell keys apart
+    pub key_prefix: String,
+    #[serde(skip)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>
+}
+
+#[derive(ToSchema,Deserialize,Serialize,Clone,Debug)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Days until the key stops working, never when left out
+    pub expires_in_days: Option<i32>
+}
+
+/// Returned once when a key is created, the plaintext key is not stored.
+#[derive(ToSchema,Serialize)]
+pub struct NewApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: UserApiKey
+}
+
+/// A login session kept in the `sessions` table. The state and the hash of
+/// the cookie key never leave the server.
+#[derive(ToSchema,Queryable, Selectable, Identifiable, Debug, PartialEq, Clone, Serialize)]
+#[diesel(table_name = sessions)]
+pub struct UserSession {
+    pub id: i32,
+    #[serde(skip)]
+    pub key_hash: String,
+    pub user_id: Option<i32>,
+    #[serde(skip)]
+    pub state: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime
+}
+
+/// Columns of a session row filled from its state, so sessions can be
+/// listed per user without decoding every state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionMeta {
+    pub user_id: Option<i32>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>
+}
+
+/// A user's TOTP secret. It only protects the account once `confirmed_at`
+/// is set, and never leaves the server after enrollment.
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Clone)]
+#[diesel(primary_key(user_id))]
+#[diesel(table_name = totp_secrets)]
+pub struct TotpSecret {
+    pub user_id: i32,
+    /// Base32, as shown to authenticator apps
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>
+}
+
+/// What presenting a refresh token led to.
+#[derive(Debug, PartialEq)]
+pub enum RefreshOutcome {
+    /// The token was live and is now used up, the user may have a new pair
+    Rotated(i32),
+    /// The token had been rotated before, so its family was revoked
+    Reused,
+    /// The token is unknown, revoked or expired
+    Invalid,
+}
+
+#[derive(ToSchema,Queryable, Selectable, Identifiable, Debug, PartialEq, Clone, Serialize,Deserialize)]
+#[diesel(table_name = videos)]
+pub struct Video {
+    pub id: i32,
+    pub title: String,
+    pub description: String,
+    /// Length of the video in seconds
+    pub duration: i32,
+    pub release_year: Option<i32>,
+    pub maturity_rating: Option<String>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(ToSchema,Insertable,AsChangeset,Deserialize,Serialize,Clone,Debug)]
+#[diesel(table_name = videos)]
+#[diesel(treat_none_as_null = true)]
+pub struct VideoForm {
+    pub title: String,
+    pub description: String,
+    /// Length of the video in seconds
+    pub duration: i32,
+    pub release_year: Option<i32>,
+    pub maturity_rating: Option<String>
+}
+
+/// Longest maturity rating the `videos` table holds.
+pub const MATURITY_RATING_MAX_LENGTH: usize = 10;
+
+impl VideoForm {
+    /// Everything wrong with the form, in one message meant for the user.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.title.trim().is_empty() {
+            problems.push(String::from("Title must not be empty"));
+        }
+        if self.duration < 0 {
+            problems.push(String::from("Duration must not be negative"));
+        }
+        if let Some(rating) = &self.maturity_rating {
+            if rating.chars().count() > MATURITY_RATING_MAX_LENGTH {
+                problems.push(format!("Maturity rating must be at most {} characters", MATURITY_RATING_MAX_LENGTH));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+#[derive(ToSchema,Queryable, Selectable, Identifiable, Associations, Debug, PartialEq, Clone, Serialize,Deserialize)]
+#[diesel(belongs_to(User))]
+#[diesel(belongs_to(Video))]
 #[diesel(table_name = liked_videos)]
 pub struct LikedVideos {
     pub id: i32,
-    pub title: String,
     pub video_id: i32,
     pub user_id: i32
 }
 
 
-#[derive(ToSchema,Queryable, Selectable, Identifiable, Associations, Debug, PartialEq, Serialize,Deserialize)]
+#[derive(ToSchema,Queryable, Selectable, Identifiable, Associations, Debug, PartialEq, Clone, Serialize,Deserialize)]
 #[diesel(belongs_to(User))]
+#[diesel(belongs_to(Video))]
 #[diesel(table_name = watched_videos)]
 pub struct WatchedVideos {
     pub id: i32,
-    pub title: String,
     pub video_id: i32,
-    pub user_id: i32
+    pub user_id: i32,
+    /// Playback position in seconds
+    pub position: i32,
+    /// Length of the video in seconds, as reported by the player
+    pub duration: i32,
+    pub completed: bool,
+    pub last_watched_at: NaiveDateTime
 }
 
+/// Percentage of a video that has to be played for it to count as watched.
+pub const COMPLETION_THRESHOLD: i32 = 95;
 
-#[derive(ToSchema,Serialize)]
-pub struct UserWithVideos {
-    #[serde(flatten)]
-    pub user: User,
-    pub liked_videos: Vec<LikedVideos>,
-    pub watched_videos: Vec<WatchedVideos>
+#[derive(ToSchema,Deserialize,Serialize,Clone,Debug)]
+pub struct WatchProgress {
+    /// Playback position in seconds
+    pub position: i32,
+    /// Length of the video in seconds
+    pub duration: i32
 }
 
-#[derive(Deserialize,Serialize,Clone)]
-pub struct PostUser {
-    pub pass: String,
-}
+impl WatchProgress {
+    pub fn is_valid(&self) -> bool {
+        self.position >= 0 && self.duration >= 0 && self.position <= self.duration
+    }
 
+    /// Worked out in i64, as a position and duration near `i32::MAX` are
+    /// valid but their percentages are not.
+    pub fn is_completed(&self) -> bool {
+        self.duration > 0
+            && i64::from(self.position) * 100 >= i64::from(self.duration) * i64::from(COMPLETION_THRESHOLD)
+    }
+}
 
-#[derive(Deserialize,Serialize,Clone,Debug)]
-pub enum VideoType {
-    WATCHED,
-    LIKED
+#[derive(ToSchema,Serialize)]
+pub struct ContinueWatching {
+    pub video: Video,
+    pub progress: WatchedVideos
 }
 
 
-impl FromStr for VideoType {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "liked" => {
-                Ok(VideoType::LIKED)
-            },
-            "watched" => {
-                Ok(VideoType::LIKED)
-            },
-                _ => {
-                Err(anyhow::Error::msg("Couldn't convert"))
-            }
-        }
-    }
+#[derive(ToSchema,Serialize)]
+pub struct UserWithVideos {
+    #[serde(flatten)]
+    pub user: User,
+    pub liked_videos: Vec<Video>,
+    pub watched_videos: Vec<Video>
+}
+
+#[derive(ToSchema,Deserialize,Serialize,Clone,Debug)]
+pub struct VideoRef {
+    pub video_id: i32
 }
 
-#[derive(Deserialize,Serialize,Debug)]
-pub enum VideoTypeResult {
-    WATCHED(WatchedVideos),
-    LIKED(LikedVideos)
+/// The per-user video lists a video can be added to or removed from.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum VideoList {
+    Liked,
+    Watched
 }
 
-#[derive(Serialize, Deserialize, Clone, ToSchema)]
-pub enum SwaggerErrorResponse {
-    /// When Todo is not found by search term.
-    NotFound(String),
-    /// When there is a conflict storing a new todo.
-    Conflict(String),
-    /// When todo endpoint was called without correct credentials
-    Unauthorized(String),
+/// The row adding a video to a list created.
+#[derive(Serialize,Debug)]
+pub enum VideoListEntry {
+    Liked(LikedVideos),
+    Watched(WatchedVideos)
 }
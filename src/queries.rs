@@ -0,0 +1,352 @@
+//! The statements behind the user and video repositories, built once here
+//! and run by both `db_actions` and `db_async`. Each function returns its
+//! statement unexecuted: the blocking functions run it with diesel's
+//! `RunQueryDsl`, the async ones with diesel-async's.
+
+use diesel::dsl::{now, AsSelect, IntervalDsl, SqlTypeOf};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::sql_types::{Bool, Integer, Nullable, Text, Timestamp};
+use diesel::upsert::excluded;
+use crate::models::{
+    LikedVideos,
+    Role,
+    User,
+    Video,
+    VideoForm,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::schema::email_verification_tokens;
+use crate::schema::liked_videos;
+use crate::schema::login_throttles;
+use crate::schema::password_reset_tokens;
+use crate::schema::permissions;
+use crate::schema::refresh_tokens;
+use crate::schema::role_permissions;
+use crate::schema::roles;
+use crate::schema::sessions;
+use crate::schema::totp_secrets;
+use crate::schema::users;
+use crate::schema::videos;
+use crate::schema::watched_videos;
+use crate::throttle::ThrottleScope;
+
+
+sql_function!(fn lower(x: Text) -> Text);
+
+/// A statement from this module. diesel-async runs any statement, but
+/// diesel's blocking `RunQueryDsl` has to be implemented for the type.
+#[derive(QueryId)]
+pub struct Statement<Q>(Q);
+
+impl<Q: Query> Query for Statement<Q> {
+    type SqlType = Q::SqlType;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Statement<Q> {
+    fn walk_ast<'b>(&'b self, pass: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        self.0.walk_ast(pass)
+    }
+}
+
+impl<Q> RunQueryDsl<PgConnection> for Statement<Q> {}
+
+/// A statement that returns rows of `ST`.
+pub trait Rows<ST>: Query<SqlType = ST> + QueryFragment<Pg> + QueryId + Send {}
+
+impl<Q, ST> Rows<ST> for Q where Q: Query<SqlType = ST> + QueryFragment<Pg> + QueryId + Send {}
+
+/// A statement run for the number of rows it changes.
+pub trait Changes: QueryFragment<Pg> + QueryId + Send {}
+
+impl<Q> Changes for Q where Q: QueryFragment<Pg> + QueryId + Send {}
+
+/// Rows loaded into `T` through `as_select` or `as_returning`.
+type Selected<T> = SqlTypeOf<AsSelect<T, Pg>>;
+
+pub fn user(id: i32) -> Statement<impl Rows<Selected<User>>> {
+    Statement(users::table.find(id).select(User::as_select()))
+}
+
+/// `email` must already be normalized.
+pub fn user_by_email(email: &str) -> Statement<impl Rows<Selected<User>> + '_> {
+    Statement(users::table
+        .filter(lower(users::email).eq(email))
+        .select(User::as_select()))
+}
+
+pub fn insert_user(email: String, password_hash: String, role_id: i32) -> Statement<impl Rows<Selected<User>>> {
+    Statement(diesel::insert_into(users::table)
+        .values((
+            users::email.eq(email),
+            users::password_hash.eq(password_hash),
+            users::role_id.eq(role_id),
+        ))
+        .returning(User::as_returning()))
+}
+
+/// Returns the email of the account.
+pub fn set_password_hash(id: i32, password_hash: String) -> Statement<impl Rows<Text>> {
+    Statement(diesel::update(users::table.find(id))
+        .set(users::password_hash.eq(password_hash))
+        .returning(users::email))
+}
+
+pub fn set_user_role(id: i32, role_id: i32) -> Statement<impl Rows<Selected<User>>> {
+    Statement(diesel::update(users::table.find(id))
+        .set(users::role_id.eq(role_id))
+        .returning(User::as_returning()))
+}
+
+pub fn mark_email_verified(id: i32) -> Statement<impl Changes> {
+    Statement(diesel::update(users::table.find(id))
+        .filter(users::email_verified_at.is_null())
+        .set(users::email_verified_at.eq(now)))
+}
+
+pub fn all_roles() -> Statement<impl Rows<Selected<Role>>> {
+    Statement(roles::table
+        .order(roles::id)
+        .select(Role::as_select()))
+}
+
+pub fn role_by_name(name: &str) -> Statement<impl Rows<Selected<Role>> + '_> {
+    Statement(roles::table
+        .filter(roles::name.eq(name))
+        .select(Role::as_select()))
+}
+
+/// Names of the permissions granted to any of the roles.
+pub fn permission_names(role_ids: Vec<i32>) -> Statement<impl Rows<Text>> {
+    Statement(role_permissions::table
+        .inner_join(permissions::table)
+        .filter(role_permissions::role_id.eq_any(role_ids))
+        .select(permissions::name))
+}
+
+pub fn has_two_factor(id: i32) -> Statement<impl Rows<Bool>> {
+    Statement(diesel::select(diesel::dsl::exists(totp_secrets::table
+        .find(id)
+        .filter(totp_secrets::confirmed_at.is_not_null()))))
+}
+
+pub fn delete_sessions(user_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(sessions::table.filter(sessions::user_id.eq(user_id))))
+}
+
+pub fn revoke_refresh_tokens(user_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::update(refresh_tokens::table
+        .filter(refresh_tokens::user_id.eq(user_id))
+        .filter(refresh_tokens::revoked_at.is_null()))
+        .set(refresh_tokens::revoked_at.eq(now)))
+}
+
+pub fn delete_password_resets(user_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(password_reset_tokens::table
+        .filter(password_reset_tokens::user_id.eq(user_id))))
+}
+
+pub fn insert_password_reset(user_id: i32, token_hash: &str, ttl_minutes: i32) -> Statement<impl Changes + '_> {
+    Statement(diesel::insert_into(password_reset_tokens::table)
+        .values((
+            password_reset_tokens::user_id.eq(user_id),
+            password_reset_tokens::token_hash.eq(token_hash),
+            password_reset_tokens::expires_at.eq(now + ttl_minutes.minutes()),
+        )))
+}
+
+/// Marks an unused, unexpired reset token used, returning its user.
+pub fn use_password_reset(token_hash: &str) -> Statement<impl Rows<Integer> + '_> {
+    Statement(diesel::update(password_reset_tokens::table
+        .filter(password_reset_tokens::token_hash.eq(token_hash))
+        .filter(password_reset_tokens::used_at.is_null())
+        .filter(password_reset_tokens::expires_at.gt(now)))
+        .set(password_reset_tokens::used_at.eq(now))
+        .returning(password_reset_tokens::user_id))
+}
+
+pub fn delete_email_verifications(user_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(email_verification_tokens::table
+        .filter(email_verification_tokens::user_id.eq(user_id))))
+}
+
+pub fn insert_email_verification(user_id: i32, token_hash: &str, ttl_minutes: i32) -> Statement<impl Changes + '_> {
+    Statement(diesel::insert_into(email_verification_tokens::table)
+        .values((
+            email_verification_tokens::user_id.eq(user_id),
+            email_verification_tokens::token_hash.eq(token_hash),
+            email_verification_tokens::expires_at.eq(now + ttl_minutes.minutes()),
+        )))
+}
+
+pub fn verification_sent_recently(user_id: i32, cooldown_minutes: i32) -> Statement<impl Rows<Bool>> {
+    Statement(diesel::select(diesel::dsl::exists(email_verification_tokens::table
+        .filter(email_verification_tokens::user_id.eq(user_id))
+        .filter(email_verification_tokens::created_at.gt(now - cooldown_minutes.minutes())))))
+}
+
+/// Deletes an unexpired verification token, returning its user.
+pub fn use_email_verification(token_hash: &str) -> Statement<impl Rows<Integer> + '_> {
+    Statement(diesel::delete(email_verification_tokens::table
+        .filter(email_verification_tokens::token_hash.eq(token_hash))
+        .filter(email_verification_tokens::expires_at.gt(now)))
+        .returning(email_verification_tokens::user_id))
+}
+
+/// When the subject is locked until, next to the database clock.
+pub fn throttle_lock(scope: ThrottleScope, subject: &str) -> Statement<impl Rows<(Nullable<Timestamp>, Timestamp)> + '_> {
+    Statement(login_throttles::table
+        .find((scope.as_str(), subject))
+        .select((login_throttles::locked_until, now)))
+}
+
+/// Drops a counter that went quiet for a whole window and is not locked.
+pub fn expire_throttle(scope: ThrottleScope, subject: &str, window_seconds: i32) -> Statement<impl Changes + '_> {
+    Statement(diesel::delete(login_throttles::table
+        .find((scope.as_str(), subject))
+        .filter(login_throttles::last_attempt_at.lt(now - window_seconds.seconds()))
+        .filter(login_throttles::locked_until.is_null().or(login_throttles::locked_until.lt(now)))))
+}
+
+/// Counts an attempt, returning the attempts so far.
+pub fn count_attempt(scope: ThrottleScope, subject: &str) -> Statement<impl Rows<Integer> + '_> {
+    Statement(diesel::insert_into(login_throttles::table)
+        .values((
+            login_throttles::scope.eq(scope.as_str()),
+            login_throttles::subject.eq(subject),
+            login_throttles::attempts.eq(1),
+        ))
+        .on_conflict((login_throttles::scope, login_throttles::subject))
+        .do_update()
+        .set((
+            login_throttles::attempts.eq(login_throttles::attempts + 1),
+            login_throttles::last_attempt_at.eq(now),
+        ))
+        .returning(login_throttles::attempts))
+}
+
+pub fn lock_throttle(scope: ThrottleScope, subject: &str, delay_seconds: i32) -> Statement<impl Changes + '_> {
+    Statement(diesel::update(login_throttles::table.find((scope.as_str(), subject)))
+        .set(login_throttles::locked_until.eq((now + delay_seconds.seconds()).nullable())))
+}
+
+pub fn clear_throttle(scope: ThrottleScope, subject: &str) -> Statement<impl Changes + '_> {
+    Statement(diesel::delete(login_throttles::table.find((scope.as_str(), subject))))
+}
+
+pub fn all_videos() -> Statement<impl Rows<Selected<Video>>> {
+    Statement(videos::table
+        .order(videos::id)
+        .select(Video::as_select()))
+}
+
+pub fn insert_video(form: &VideoForm) -> Statement<impl Rows<Selected<Video>> + '_> {
+    Statement(diesel::insert_into(videos::table)
+        .values(form)
+        .returning(Video::as_returning()))
+}
+
+pub fn update_video(id: i32, form: &VideoForm) -> Statement<impl Rows<Selected<Video>> + '_> {
+    Statement(diesel::update(videos::table.find(id))
+        .set(form)
+        .returning(Video::as_returning()))
+}
+
+pub fn delete_video(id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(videos::table.find(id)))
+}
+
+pub fn liked_videos(user_id: i32) -> Statement<impl Rows<Selected<Video>>> {
+    Statement(liked_videos::table
+        .inner_join(videos::table)
+        .filter(liked_videos::user_id.eq(user_id))
+        .select(Video::as_select()))
+}
+
+pub fn like_video(user_id: i32, video_id: i32) -> Statement<impl Rows<Selected<LikedVideos>>> {
+    Statement(diesel::insert_into(liked_videos::table)
+        .values((
+            liked_videos::video_id.eq(video_id),
+            liked_videos::user_id.eq(user_id),
+        ))
+        .returning(LikedVideos::as_returning()))
+}
+
+pub fn unlike_video(user_id: i32, video_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(liked_videos::table
+        .filter(liked_videos::user_id.eq(user_id))
+        .filter(liked_videos::video_id.eq(video_id))))
+}
+
+/// Every video the user has a watch row for, finished or not.
+pub fn watch_history(user_id: i32) -> Statement<impl Rows<Selected<Video>>> {
+    Statement(watched_videos::table
+        .inner_join(videos::table)
+        .filter(watched_videos::user_id.eq(user_id))
+        .select(Video::as_select()))
+}
+
+pub fn watched_videos(user_id: i32) -> Statement<impl Rows<Selected<Video>>> {
+    Statement(watched_videos::table
+        .inner_join(videos::table)
+        .filter(watched_videos::user_id.eq(user_id))
+        .filter(watched_videos::completed.eq(true))
+        .select(Video::as_select()))
+}
+
+/// Unfinished videos with their progress, most recently watched first.
+pub fn continue_watching(user_id: i32, limit: i64) -> Statement<impl Rows<(Selected<WatchedVideos>, Selected<Video>)>> {
+    Statement(watched_videos::table
+        .inner_join(videos::table)
+        .filter(watched_videos::user_id.eq(user_id))
+        .filter(watched_videos::completed.eq(false))
+        .order(watched_videos::last_watched_at.desc())
+        .limit(limit)
+        .select((WatchedVideos::as_select(), Video::as_select())))
+}
+
+pub fn save_progress(user_id: i32, video_id: i32, progress: WatchProgress) -> Statement<impl Rows<Selected<WatchedVideos>>> {
+    Statement(diesel::insert_into(watched_videos::table)
+        .values((
+            watched_videos::video_id.eq(video_id),
+            watched_videos::user_id.eq(user_id),
+            watched_videos::position.eq(progress.position),
+            watched_videos::duration.eq(progress.duration),
+            watched_videos::completed.eq(progress.is_completed()),
+            watched_videos::last_watched_at.eq(now),
+        ))
+        .on_conflict((watched_videos::user_id, watched_videos::video_id))
+        .do_update()
+        .set((
+            watched_videos::position.eq(excluded(watched_videos::position)),
+            watched_videos::duration.eq(excluded(watched_videos::duration)),
+            watched_videos::completed.eq(excluded(watched_videos::completed)),
+            watched_videos::last_watched_at.eq(now),
+        ))
+        .returning(WatchedVideos::as_returning()))
+}
+
+/// Marks a video watched to the end, starting a watch row if needed.
+pub fn mark_watched(user_id: i32, video_id: i32) -> Statement<impl Rows<Selected<WatchedVideos>>> {
+    Statement(diesel::insert_into(watched_videos::table)
+        .values((
+            watched_videos::video_id.eq(video_id),
+            watched_videos::user_id.eq(user_id),
+            watched_videos::completed.eq(true),
+        ))
+        .on_conflict((watched_videos::user_id, watched_videos::video_id))
+        .do_update()
+        .set((
+            watched_videos::completed.eq(true),
+            watched_videos::last_watched_at.eq(now),
+        ))
+        .returning(WatchedVideos::as_returning()))
+}
+
+pub fn delete_watch_row(user_id: i32, video_id: i32) -> Statement<impl Changes> {
+    Statement(diesel::delete(watched_videos::table
+        .filter(watched_videos::user_id.eq(user_id))
+        .filter(watched_videos::video_id.eq(video_id))))
+}
@@ -0,0 +1,142 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    Error, ResponseError,
+};
+use tracing::warn;
+use crate::errors::AppError;
+use crate::throttle::client_ip;
+use crate::guards::{ApiKeyAuth, AuthenticatedUser};
+use super::{Decision, KeySource, RateLimitConfig, RateLimitStore};
+
+
+/// Applies the policies of a `RateLimitConfig` to every request and reports
+/// the quota through `RateLimit-*` headers. Register it before the identity
+/// and session middleware so it runs inside them and can see the user.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter {
+            config: Arc::new(config),
+            store,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            config: self.config.clone(),
+            store: self.store.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<RateLimitConfig>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+/// Names the bucket of a request. Credentials only count once they check
+/// out, through the same extractors the handlers use, so a made-up key or
+/// token lands in the bucket of the client's IP instead of a fresh one.
+async fn request_key(req: &mut ServiceRequest, source: KeySource) -> String {
+    let ip = format!("ip:{}", client_ip(req.request()));
+    match source {
+        KeySource::Ip => ip,
+        KeySource::User => match req.extract::<AuthenticatedUser>().await {
+            Ok(user) => format!("user:{}", user.id()),
+            Err(_) => ip,
+        },
+        KeySource::ApiKey => match req.extract::<ApiKeyAuth>().await {
+            Ok(api_key) => format!("key:{}", api_key.key_id),
+            Err(_) => ip,
+        },
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, window_seconds: u32) {
+    let values = [
+        ("ratelimit-limit", decision.limit.to_string()),
+        ("ratelimit-remaining", decision.remaining.to_string()),
+        ("ratelimit-reset", decision.reset_seconds.to_string()),
+        ("ratelimit-policy", format!("{};w={}", decision.limit, window_seconds)),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let Some((name, policy)) = self.config.policy_for(&req) else {
+            return Box::pin(async move {
+                service.call(req).await.map(ServiceResponse::map_into_left_body)
+            });
+        };
+        let policy = policy.clone();
+        let store = self.store.clone();
+
+        Box::pin(async move {
+            let mut req = req;
+            let key = format!("{}|{}", name, request_key(&mut req, policy.key).await);
+            let decision = match store.hit(&key, &policy).await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    // A broken store should not take the whole API down with it.
+                    warn!(error = %err, "rate limit store failed, letting request through");
+                    return service.call(req).await.map(ServiceResponse::map_into_left_body);
+                }
+            };
+
+            if !decision.allowed {
+                let mut resp = AppError::TooManyRequests {
+                    detail: String::from("Too many requests, please slow down"),
+                    retry_after: i64::try_from(decision.retry_after_seconds).unwrap_or(i64::MAX),
+                }
+                .error_response();
+                insert_headers(resp.headers_mut(), &decision, policy.window_seconds);
+                return Ok(req.into_response(resp).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            insert_headers(res.headers_mut(), &decision, policy.window_seconds);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
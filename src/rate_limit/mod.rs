@@ -0,0 +1,247 @@
+pub mod middleware;
+pub mod store;
+
+use actix_web::dev::ServiceRequest;
+use serde::Deserialize;
+
+pub use middleware::RateLimiter;
+pub use store::{RateLimitStore, MemoryStore, PgStore};
+
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Bursts up to `limit`, refilled evenly over `window_seconds`
+    TokenBucket,
+    /// At most `limit` requests in any `window_seconds` long window, estimated
+    /// from the current and previous fixed windows
+    SlidingWindow,
+}
+
+/// What requests are counted against.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    Ip,
+    /// The user of the login session or bearer token, falling back to the
+    /// IP for anonymous requests and credentials that do not check out
+    User,
+    /// The key of the `X-API-Key` header, falling back to the IP when it is
+    /// missing or invalid
+    ApiKey,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RatePolicy {
+    pub algorithm: Algorithm,
+    pub key: KeySource,
+    pub limit: u32,
+    pub window_seconds: u32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RoutePolicy {
+    /// Route pattern as registered, e.g. `/user/{id}`. A trailing `*` matches
+    /// every pattern starting with what comes before it.
+    pub path: String,
+    /// Methods the policy applies to, all of them when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(flatten)]
+    pub policy: RatePolicy,
+}
+
+impl RoutePolicy {
+    fn matches(&self, pattern: &str, method: &str) -> bool {
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => pattern.starts_with(prefix),
+            None => pattern == self.path,
+        };
+        path_matches
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+    }
+}
+
+/// Rate limits, the `rate_limit` section of the settings. Without policies
+/// nothing is limited.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub store: StoreKind,
+    /// Applies to every route without a policy of its own
+    pub default: Option<RatePolicy>,
+    pub routes: Vec<RoutePolicy>,
+}
+
+impl RateLimitConfig {
+    /// The policy for a request and the name its counters are stored under.
+    pub fn policy_for(&self, req: &ServiceRequest) -> Option<(String, &RatePolicy)> {
+        let pattern = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+        let method = req.method().as_str();
+        match self.routes.iter().find(|route| route.matches(&pattern, method)) {
+            Some(route) => Some((route.path.clone(), &route.policy)),
+            None => self.default.as_ref().map(|policy| (String::from("default"), policy)),
+        }
+    }
+}
+
+/// Counter state kept per key by the stores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateState {
+    /// Tokens left for the token bucket, hits in the current window otherwise
+    pub value: f64,
+    /// Hits in the previous window, unused by the token bucket
+    pub previous: f64,
+    /// Last refill for the token bucket, start of the current window
+    /// otherwise, in milliseconds since the epoch
+    pub stamp_ms: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the quota is fully restored
+    pub reset_seconds: u64,
+    /// Seconds until the next request would be allowed, zero when allowed
+    pub retry_after_seconds: u64,
+}
+
+fn ceil_seconds(ms: f64) -> u64 {
+    (ms.max(0.0) / 1000.0).ceil() as u64
+}
+
+impl RatePolicy {
+    /// When a counter last hit at `now_ms` can be dropped: once two windows
+    /// have passed without hits it is worthless.
+    pub fn expires_at(&self, now_ms: i64) -> i64 {
+        now_ms + i64::from(self.window_seconds) * 2000
+    }
+
+    /// Counts one request against `state` at `now_ms`, returning the new state
+    /// and whether the request may go through.
+    pub fn apply(&self, state: Option<RateState>, now_ms: i64) -> (RateState, Decision) {
+        let limit = f64::from(self.limit);
+        let window_ms = i64::from(self.window_seconds.max(1)) * 1000;
+        match self.algorithm {
+            Algorithm::TokenBucket => {
+                let per_ms = limit / window_ms as f64;
+                let (tokens, stamp_ms) = state
+                    .map(|state| (state.value, state.stamp_ms))
+                    .unwrap_or((limit, now_ms));
+                let tokens = (tokens + (now_ms - stamp_ms).max(0) as f64 * per_ms).min(limit);
+                let allowed = tokens >= 1.0;
+                let tokens = if allowed { tokens - 1.0 } else { tokens };
+                let decision = Decision {
+                    allowed,
+                    limit: self.limit,
+                    remaining: tokens.floor() as u32,
+                    reset_seconds: ceil_seconds((limit - tokens) / per_ms),
+                    retry_after_seconds: if allowed { 0 } else { ceil_seconds((1.0 - tokens) / per_ms).max(1) },
+                };
+                (RateState { value: tokens, previous: 0.0, stamp_ms: now_ms }, decision)
+            }
+            Algorithm::SlidingWindow => {
+                let start_ms = now_ms - now_ms.rem_euclid(window_ms);
+                let (current, previous) = match state {
+                    Some(state) if state.stamp_ms == start_ms => (state.value, state.previous),
+                    Some(state) if state.stamp_ms == start_ms - window_ms => (0.0, state.value),
+                    _ => (0.0, 0.0),
+                };
+                let elapsed_ms = (now_ms - start_ms) as f64;
+                let weight = 1.0 - elapsed_ms / window_ms as f64;
+                let estimated = previous * weight + current;
+                let allowed = estimated + 1.0 <= limit;
+                let current = if allowed { current + 1.0 } else { current };
+                let until_next_window = window_ms as f64 - elapsed_ms;
+                let retry_after_ms = if allowed {
+                    0.0
+                } else if current + 1.0 > limit || previous <= 0.0 {
+                    until_next_window
+                } else {
+                    // Wait for enough of the previous window to slide out.
+                    let target_weight = (limit - current - 1.0) / previous;
+                    (window_ms as f64 * (1.0 - target_weight) - elapsed_ms).min(until_next_window)
+                };
+                let decision = Decision {
+                    allowed,
+                    limit: self.limit,
+                    remaining: (limit - previous * weight - current).max(0.0).floor() as u32,
+                    reset_seconds: ceil_seconds(until_next_window),
+                    retry_after_seconds: if allowed { 0 } else { ceil_seconds(retry_after_ms).max(1) },
+                };
+                (RateState { value: current, previous, stamp_ms: start_ms }, decision)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(algorithm: Algorithm, limit: u32, window_seconds: u32) -> RatePolicy {
+        RatePolicy { algorithm, key: KeySource::Ip, limit, window_seconds }
+    }
+
+    #[test]
+    fn token_bucket_allows_burst_then_refills() {
+        let policy = policy(Algorithm::TokenBucket, 3, 3);
+        let mut state = None;
+        for remaining in [2, 1, 0] {
+            let (next, decision) = policy.apply(state, 0);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            state = Some(next);
+        }
+
+        let (next, decision) = policy.apply(state, 0);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after_seconds, 1);
+
+        let (_, decision) = policy.apply(Some(next), 1000);
+        assert!(decision.allowed);
+    }
+
+    #[test]
+    fn sliding_window_weighs_previous_window() {
+        let policy = policy(Algorithm::SlidingWindow, 2, 10);
+        let (state, _) = policy.apply(None, 1_000);
+        let (state, _) = policy.apply(Some(state), 2_000);
+        let (state, decision) = policy.apply(Some(state), 3_000);
+        assert!(!decision.allowed);
+        assert_eq!(decision.reset_seconds, 7);
+
+        // Half way through the next window one of the two old hits still counts.
+        let (_, decision) = policy.apply(Some(state), 15_000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        // A whole window later the old hits are gone.
+        let (_, decision) = policy.apply(Some(state), 25_000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn route_policies_match_patterns_and_methods() {
+        let route = |path: &str, methods: &[&str]| RoutePolicy {
+            path: path.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            policy: policy(Algorithm::TokenBucket, 1, 1),
+        };
+        assert!(route("/login", &["POST"]).matches("/login", "POST"));
+        assert!(!route("/login", &["POST"]).matches("/login", "GET"));
+        assert!(route("/me/*", &[]).matches("/me/watched/{video_id}", "PUT"));
+        assert!(!route("/me/*", &[]).matches("/user/{id}", "GET"));
+    }
+}
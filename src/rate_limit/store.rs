@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use crate::DbPool;
+use crate::db_actions::{apply_rate_limit, sweep_rate_limits};
+use crate::metrics::block;
+use super::{Decision, RatePolicy, RateState};
+
+/// Entries are swept out of the memory store once it grows past this.
+const MEMORY_SWEEP_THRESHOLD: usize = 10_000;
+/// Expired rows are deleted from the `rate_limits` table at most this often.
+const PG_SWEEP_INTERVAL_MS: i64 = 60_000;
+
+
+/// Where rate limit counters live.
+#[async_trait(?Send)]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts one request for `key` under `policy`.
+    async fn hit(&self, key: &str, policy: &RatePolicy) -> Result<Decision, anyhow::Error>;
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Counters for a single instance, shared by all of its workers.
+#[derive(Default)]
+pub struct MemoryStore {
+    counters: Mutex<HashMap<String, (RateState, i64)>>,
+}
+
+#[async_trait(?Send)]
+impl RateLimitStore for MemoryStore {
+    async fn hit(&self, key: &str, policy: &RatePolicy) -> Result<Decision, anyhow::Error> {
+        let now = now_ms();
+        let mut counters = self
+            .counters
+            .lock()
+            .map_err(|_| anyhow::Error::msg("Rate limit counters lock poisoned"))?;
+
+        if counters.len() >= MEMORY_SWEEP_THRESHOLD {
+            counters.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        let state = counters.get(key).map(|(state, _)| *state);
+        let (state, decision) = policy.apply(state, now);
+        counters.insert(key.to_string(), (state, policy.expires_at(now)));
+
+        Ok(decision)
+    }
+}
+
+/// Counters in the `rate_limits` table, shared across instances. Every
+/// minute one hit also deletes the expired rows.
+pub struct PgStore {
+    pool: DbPool,
+    last_sweep_ms: AtomicI64,
+}
+
+impl PgStore {
+    pub fn new(pool: DbPool) -> Self {
+        PgStore { pool, last_sweep_ms: AtomicI64::new(0) }
+    }
+
+    /// Whether the hit at `now` is the one to sweep, claimed by a single
+    /// worker.
+    fn claim_sweep(&self, now: i64) -> bool {
+        let last = self.last_sweep_ms.load(Ordering::Relaxed);
+        now - last >= PG_SWEEP_INTERVAL_MS
+            && self.last_sweep_ms
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+}
+
+#[async_trait(?Send)]
+impl RateLimitStore for PgStore {
+    async fn hit(&self, key: &str, policy: &RatePolicy) -> Result<Decision, anyhow::Error> {
+        let pool = self.pool.clone();
+        let key = key.to_string();
+        let policy = policy.clone();
+        let now = now_ms();
+        let sweep = self.claim_sweep(now);
+        block(move || {
+            let mut conn = pool.get()?;
+            let decision = apply_rate_limit(&mut conn, &key, &policy, now)?;
+            if sweep {
+                sweep_rate_limits(&mut conn, now)?;
+            }
+            Ok(decision)
+        })
+        .await?
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+use async_trait::async_trait;
+use diesel::result::Error as DieselError;
+use crate::auth::Credentials;
+use crate::db_async::{self, AsyncDbPool};
+use crate::models::{
+    ContinueWatching,
+    Role,
+    User,
+    Video,
+    VideoForm,
+    VideoList,
+    VideoListEntry,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::throttle::ThrottleScope;
+use super::pg::map_list_error;
+use super::{UserRepository, VideoRepository};
+
+
+/// Accounts in Postgres over diesel-async. Queries are awaited on the worker
+/// instead of holding a thread of the blocking pool for their whole run.
+#[derive(Clone)]
+pub struct AsyncPgUserRepository {
+    pool: AsyncDbPool,
+}
+
+impl AsyncPgUserRepository {
+    pub fn new(pool: AsyncDbPool) -> Self {
+        AsyncPgUserRepository { pool }
+    }
+}
+
+#[async_trait(?Send)]
+impl UserRepository for AsyncPgUserRepository {
+    async fn get(&self, id: i32) -> Result<Option<User>, anyhow::Error> {
+        db_async::get_user(&mut *self.pool.get().await?, id).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, anyhow::Error> {
+        match db_async::find_user_by_email(&mut *self.pool.get().await?, email).await {
+            Ok(user) => Ok(Some(user)),
+            Err(err) if matches!(err.downcast_ref::<DieselError>(), Some(DieselError::NotFound)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn authenticate(&self, creds: Credentials, bcrypt_cost: u32) -> Result<Option<User>, anyhow::Error> {
+        db_async::authenticate(creds, &mut *self.pool.get().await?, bcrypt_cost).await
+    }
+
+    async fn create(&self, creds: Credentials, bcrypt_cost: u32) -> Result<User, anyhow::Error> {
+        db_async::create_user(&mut *self.pool.get().await?, creds, bcrypt_cost).await
+    }
+
+    async fn role_permissions(&self, role_id: i32) -> Result<(Role, HashSet<String>), anyhow::Error> {
+        db_async::get_role_permissions(&mut *self.pool.get().await?, role_id).await
+    }
+
+    async fn roles(&self) -> Result<Vec<Role>, anyhow::Error> {
+        db_async::get_roles(&mut *self.pool.get().await?).await
+    }
+
+    async fn find_role(&self, name: &str) -> Result<Option<Role>, anyhow::Error> {
+        db_async::find_role(&mut *self.pool.get().await?, name).await
+    }
+
+    async fn assign_role(&self, id: i32, role_id: i32) -> Result<Option<User>, anyhow::Error> {
+        db_async::set_user_role(&mut *self.pool.get().await?, id, role_id).await
+    }
+
+    async fn has_two_factor(&self, id: i32) -> Result<bool, anyhow::Error> {
+        db_async::has_two_factor(&mut *self.pool.get().await?, id).await
+    }
+
+    async fn throttle_remaining(&self, scope: ThrottleScope, subject: &str) -> Result<Option<i64>, anyhow::Error> {
+        db_async::throttle_remaining(&mut *self.pool.get().await?, scope, subject).await
+    }
+
+    async fn record_attempt(&self, scope: ThrottleScope, subject: &str) -> Result<i32, anyhow::Error> {
+        db_async::record_attempt(&mut *self.pool.get().await?, scope, subject).await
+    }
+
+    async fn clear_throttle(&self, scope: ThrottleScope, subject: &str) -> Result<usize, anyhow::Error> {
+        db_async::clear_throttle(&mut *self.pool.get().await?, scope, subject).await
+    }
+
+    async fn create_password_reset(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        db_async::create_password_reset(&mut *self.pool.get().await?, id, token_hash, ttl_minutes).await
+    }
+
+    async fn reset_password(&self, token_hash: &str, password: &str, bcrypt_cost: u32) -> Result<bool, anyhow::Error> {
+        db_async::reset_password(&mut *self.pool.get().await?, token_hash, password, bcrypt_cost).await
+    }
+
+    async fn create_email_verification(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        db_async::create_email_verification(&mut *self.pool.get().await?, id, token_hash, ttl_minutes).await
+    }
+
+    async fn verification_sent_recently(&self, id: i32, cooldown_minutes: i32) -> Result<bool, anyhow::Error> {
+        db_async::verification_sent_recently(&mut *self.pool.get().await?, id, cooldown_minutes).await
+    }
+
+    async fn verify_email(&self, token_hash: &str) -> Result<bool, anyhow::Error> {
+        db_async::verify_email(&mut *self.pool.get().await?, token_hash).await
+    }
+}
+
+/// The catalog and lists in Postgres over diesel-async.
+#[derive(Clone)]
+pub struct AsyncPgVideoRepository {
+    pool: AsyncDbPool,
+}
+
+impl AsyncPgVideoRepository {
+    pub fn new(pool: AsyncDbPool) -> Self {
+        AsyncPgVideoRepository { pool }
+    }
+}
+
+#[async_trait(?Send)]
+impl VideoRepository for AsyncPgVideoRepository {
+    async fn list(&self) -> Result<Vec<Video>, anyhow::Error> {
+        db_async::get_videos(&mut *self.pool.get().await?).await
+    }
+
+    async fn create(&self, form: VideoForm) -> Result<Video, anyhow::Error> {
+        db_async::create_video(&mut *self.pool.get().await?, form).await
+    }
+
+    async fn update(&self, id: i32, form: VideoForm) -> Result<Option<Video>, anyhow::Error> {
+        db_async::update_video(&mut *self.pool.get().await?, id, form).await
+    }
+
+    async fn delete(&self, id: i32) -> Result<usize, anyhow::Error> {
+        db_async::delete_video(&mut *self.pool.get().await?, id).await
+    }
+
+    async fn add_to_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<VideoListEntry, anyhow::Error> {
+        db_async::create_liked_videos(&mut *self.pool.get().await?, user_id, video_id, list).await.map_err(map_list_error)
+    }
+
+    async fn remove_from_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<usize, anyhow::Error> {
+        db_async::delete_user_video(&mut *self.pool.get().await?, user_id, video_id, list).await
+    }
+
+    async fn liked(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        db_async::get_user_info(&mut *self.pool.get().await?, user_id).await
+    }
+
+    async fn watched(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        db_async::get_watched_videos(&mut *self.pool.get().await?, user_id).await
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        db_async::get_watch_history(&mut *self.pool.get().await?, user_id).await
+    }
+
+    async fn save_progress(&self, user_id: i32, video_id: i32, progress: WatchProgress) -> Result<WatchedVideos, anyhow::Error> {
+        db_async::upsert_watch_progress(&mut *self.pool.get().await?, user_id, video_id, progress).await.map_err(map_list_error)
+    }
+
+    async fn continue_watching(&self, user_id: i32, limit: i64) -> Result<Vec<ContinueWatching>, anyhow::Error> {
+        db_async::get_continue_watching(&mut *self.pool.get().await?, user_id, limit).await
+    }
+}
@@ -0,0 +1,781 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+use crate::auth::Credentials;
+use crate::errors::AppError;
+use crate::models::{
+    ContinueWatching,
+    LikedVideos,
+    RefreshOutcome,
+    Role,
+    SessionMeta,
+    TotpSecret,
+    User,
+    UserApiKey,
+    UserSession,
+    Video,
+    VideoForm,
+    VideoList,
+    VideoListEntry,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::throttle::ThrottleScope;
+use super::{ApiKeyRepository, SessionRepository, TwoFactorRepository, UserRepository, VideoRepository};
+
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, anyhow::Error> {
+    mutex.lock().map_err(|_| anyhow::Error::msg("Repository lock poisoned"))
+}
+
+struct Throttle {
+    attempts: i32,
+    last_attempt_at: NaiveDateTime,
+    locked_until: Option<NaiveDateTime>,
+}
+
+struct Token {
+    user_id: i32,
+    token_hash: String,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+}
+
+struct RefreshToken {
+    user_id: i32,
+    family_id: String,
+    token_hash: String,
+    used: bool,
+    revoked: bool,
+    expires_at: NaiveDateTime,
+}
+
+struct RecoveryCode {
+    user_id: i32,
+    code_hash: String,
+    used: bool,
+}
+
+struct Users {
+    users: Vec<User>,
+    roles: Vec<Role>,
+    permissions: HashMap<i32, Vec<&'static str>>,
+    throttles: HashMap<(&'static str, String), Throttle>,
+    resets: Vec<Token>,
+    verifications: Vec<Token>,
+    sessions: Vec<UserSession>,
+    refresh_tokens: Vec<RefreshToken>,
+    api_keys: Vec<UserApiKey>,
+    secrets: Vec<TotpSecret>,
+    recovery_codes: Vec<RecoveryCode>,
+    /// Last id handed to a session or API key
+    next_id: i32,
+}
+
+impl Users {
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Ends every session of the account and revokes its refresh tokens.
+    fn sign_out_everywhere(&mut self, user_id: i32) {
+        self.sessions.retain(|session| session.user_id != Some(user_id));
+        for token in self.refresh_tokens.iter_mut().filter(|token| token.user_id == user_id) {
+            token.revoked = true;
+        }
+    }
+}
+
+/// Accounts held in memory with their sessions, API keys and two-factor
+/// secrets, for unit tests of the handlers. Starts with the roles and
+/// permissions the migrations seed.
+pub struct MemoryUserRepository {
+    inner: Mutex<Users>,
+}
+
+impl Default for MemoryUserRepository {
+    fn default() -> Self {
+        let role = |id: i32, name: &str, parent_id: Option<i32>| Role { id, name: name.to_string(), parent_id };
+        MemoryUserRepository {
+            inner: Mutex::new(Users {
+                users: Vec::new(),
+                roles: vec![role(1, "user", None), role(2, "moderator", Some(1)), role(3, "admin", Some(2))],
+                permissions: HashMap::from([
+                    (2, vec!["videos:read", "videos:write"]),
+                    (3, vec!["users:read", "users:write", "roles:assign", "system:status"]),
+                ]),
+                throttles: HashMap::new(),
+                resets: Vec::new(),
+                verifications: Vec::new(),
+                sessions: Vec::new(),
+                refresh_tokens: Vec::new(),
+                api_keys: Vec::new(),
+                secrets: Vec::new(),
+                recovery_codes: Vec::new(),
+                next_id: 0,
+            }),
+        }
+    }
+}
+
+impl MemoryUserRepository {
+    /// Deletes an account outright, which the API never does, for tests of
+    /// what becomes of its sessions and tokens.
+    pub fn remove(&self, id: i32) -> Result<(), anyhow::Error> {
+        lock(&self.inner)?.users.retain(|user| user.id != id);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl UserRepository for MemoryUserRepository {
+    async fn get(&self, id: i32) -> Result<Option<User>, anyhow::Error> {
+        Ok(lock(&self.inner)?.users.iter().find(|user| user.id == id).cloned())
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, anyhow::Error> {
+        Ok(lock(&self.inner)?.users.iter().find(|user| user.email.to_lowercase() == email).cloned())
+    }
+
+    async fn authenticate(&self, creds: Credentials, _bcrypt_cost: u32) -> Result<Option<User>, anyhow::Error> {
+        let Some(user) = self.find_by_email(&creds.email).await? else {
+            return Ok(None);
+        };
+        let verified = bcrypt::verify(&creds.password, &user.password_hash)?;
+        Ok(Some(user).filter(|_| verified))
+    }
+
+    async fn create(&self, creds: Credentials, bcrypt_cost: u32) -> Result<User, anyhow::Error> {
+        let password_hash = bcrypt::hash(&creds.password, bcrypt_cost)?;
+        let mut inner = lock(&self.inner)?;
+        if inner.users.iter().any(|user| user.email.to_lowercase() == creds.email) {
+            return Err(AppError::Conflict(String::from("Already exists")).into());
+        }
+        let user = User {
+            id: inner.users.iter().map(|user| user.id).max().unwrap_or(0) + 1,
+            email: creds.email,
+            password_hash,
+            email_verified_at: None,
+            role_id: 1,
+        };
+        inner.users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn role_permissions(&self, role_id: i32) -> Result<(Role, HashSet<String>), anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        let role = inner.roles.iter()
+            .find(|role| role.id == role_id)
+            .cloned()
+            .ok_or(AppError::NotFound(String::from("Role not found")))?;
+
+        let mut permissions = HashSet::new();
+        let mut next = Some(role.id);
+        while let Some(id) = next {
+            permissions.extend(inner.permissions.get(&id).into_iter().flatten().map(ToString::to_string));
+            next = inner.roles.iter().find(|role| role.id == id).and_then(|role| role.parent_id);
+        }
+        Ok((role, permissions))
+    }
+
+    async fn roles(&self) -> Result<Vec<Role>, anyhow::Error> {
+        Ok(lock(&self.inner)?.roles.clone())
+    }
+
+    async fn find_role(&self, name: &str) -> Result<Option<Role>, anyhow::Error> {
+        Ok(lock(&self.inner)?.roles.iter().find(|role| role.name == name).cloned())
+    }
+
+    async fn assign_role(&self, id: i32, role_id: i32) -> Result<Option<User>, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let user = inner.users.iter_mut().find(|user| user.id == id).map(|user| {
+            user.role_id = role_id;
+            user.clone()
+        });
+        Ok(user)
+    }
+
+    async fn has_two_factor(&self, id: i32) -> Result<bool, anyhow::Error> {
+        let enabled = lock(&self.inner)?
+            .secrets
+            .iter()
+            .any(|secret| secret.user_id == id && secret.confirmed_at.is_some());
+        Ok(enabled)
+    }
+
+    async fn throttle_remaining(&self, scope: ThrottleScope, subject: &str) -> Result<Option<i64>, anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        let now = now();
+        let remaining = inner.throttles
+            .get(&(scope.as_str(), subject.to_string()))
+            .and_then(|throttle| throttle.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| ((until - now).num_milliseconds() + 999) / 1000);
+        Ok(remaining)
+    }
+
+    async fn record_attempt(&self, scope: ThrottleScope, subject: &str) -> Result<i32, anyhow::Error> {
+        let policy = scope.policy();
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let key = (scope.as_str(), subject.to_string());
+        let stale = inner.throttles.get(&key).is_some_and(|throttle| {
+            throttle.last_attempt_at < now - Duration::seconds(policy.window_seconds.into())
+                && throttle.locked_until.is_none_or(|until| until < now)
+        });
+        if stale {
+            inner.throttles.remove(&key);
+        }
+
+        let throttle = inner.throttles.entry(key).or_insert(Throttle {
+            attempts: 0,
+            last_attempt_at: now,
+            locked_until: None,
+        });
+        throttle.attempts += 1;
+        throttle.last_attempt_at = now;
+        let delay = policy.delay_seconds(throttle.attempts);
+        if delay > 0 {
+            throttle.locked_until = Some(now + Duration::seconds(delay.into()));
+        }
+        Ok(delay)
+    }
+
+    async fn clear_throttle(&self, scope: ThrottleScope, subject: &str) -> Result<usize, anyhow::Error> {
+        let removed = lock(&self.inner)?.throttles.remove(&(scope.as_str(), subject.to_string()));
+        Ok(usize::from(removed.is_some()))
+    }
+
+    async fn create_password_reset(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.resets.retain(|token| token.user_id != id);
+        inner.resets.push(Token {
+            user_id: id,
+            token_hash: token_hash.to_string(),
+            created_at: now(),
+            expires_at: now() + Duration::minutes(ttl_minutes.into()),
+        });
+        Ok(())
+    }
+
+    async fn reset_password(&self, token_hash: &str, password: &str, bcrypt_cost: u32) -> Result<bool, anyhow::Error> {
+        let password_hash = bcrypt::hash(password, bcrypt_cost)?;
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let Some(index) = inner.resets.iter().position(|token| token.token_hash == token_hash && token.expires_at > now) else {
+            return Ok(false);
+        };
+        let token = inner.resets.remove(index);
+        if let Some(user) = inner.users.iter_mut().find(|user| user.id == token.user_id) {
+            user.password_hash = password_hash;
+            let email = user.email.clone();
+            inner.throttles.remove(&(ThrottleScope::Account.as_str(), email));
+        }
+        inner.sign_out_everywhere(token.user_id);
+        Ok(true)
+    }
+
+    async fn create_email_verification(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.verifications.retain(|token| token.user_id != id);
+        inner.verifications.push(Token {
+            user_id: id,
+            token_hash: token_hash.to_string(),
+            created_at: now(),
+            expires_at: now() + Duration::minutes(ttl_minutes.into()),
+        });
+        Ok(())
+    }
+
+    async fn verification_sent_recently(&self, id: i32, cooldown_minutes: i32) -> Result<bool, anyhow::Error> {
+        let since = now() - Duration::minutes(cooldown_minutes.into());
+        let sent = lock(&self.inner)?
+            .verifications
+            .iter()
+            .any(|token| token.user_id == id && token.created_at > since);
+        Ok(sent)
+    }
+
+    async fn verify_email(&self, token_hash: &str) -> Result<bool, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let Some(index) = inner.verifications.iter().position(|token| token.token_hash == token_hash && token.expires_at > now) else {
+            return Ok(false);
+        };
+        let token = inner.verifications.remove(index);
+        if let Some(user) = inner.users.iter_mut().find(|user| user.id == token.user_id) {
+            user.email_verified_at.get_or_insert(now);
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionRepository for MemoryUserRepository {
+    async fn insert(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<(), anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        inner.sessions.retain(|session| session.expires_at > now);
+        let id = inner.next_id();
+        inner.sessions.push(UserSession {
+            id,
+            key_hash: key_hash.to_string(),
+            user_id: meta.user_id,
+            state: state.to_string(),
+            user_agent: meta.user_agent,
+            ip: meta.ip,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + Duration::seconds(ttl_seconds.into()),
+        });
+        Ok(())
+    }
+
+    async fn load(&self, key_hash: &str) -> Result<Option<String>, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let state = inner.sessions
+            .iter_mut()
+            .find(|session| session.key_hash == key_hash && session.expires_at > now)
+            .map(|session| {
+                session.last_seen_at = now;
+                session.state.clone()
+            });
+        Ok(state)
+    }
+
+    async fn update(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let Some(session) = inner.sessions.iter_mut().find(|session| session.key_hash == key_hash && session.expires_at > now) else {
+            return Ok(0);
+        };
+        session.state = state.to_string();
+        session.user_id = meta.user_id;
+        session.user_agent = meta.user_agent;
+        session.ip = meta.ip;
+        session.expires_at = now + Duration::seconds(ttl_seconds.into());
+        Ok(1)
+    }
+
+    async fn extend(&self, key_hash: &str, ttl_seconds: i32) -> Result<(), anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        if let Some(session) = inner.sessions.iter_mut().find(|session| session.key_hash == key_hash) {
+            session.expires_at = now() + Duration::seconds(ttl_seconds.into());
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key_hash: &str) -> Result<(), anyhow::Error> {
+        lock(&self.inner)?.sessions.retain(|session| session.key_hash != key_hash);
+        Ok(())
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<UserSession>, anyhow::Error> {
+        let now = now();
+        let mut sessions: Vec<UserSession> = lock(&self.inner)?
+            .sessions
+            .iter()
+            .filter(|session| session.user_id == Some(user_id) && session.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+        Ok(sessions)
+    }
+
+    async fn revoke(&self, user_id: i32, session_id: Option<i32>) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let before = inner.sessions.len();
+        inner.sessions.retain(|session| {
+            session.user_id != Some(user_id) || session_id.is_some_and(|id| id != session.id)
+        });
+        Ok(before - inner.sessions.len())
+    }
+
+    async fn create_refresh_token(&self, user_id: i32, family_id: &str, token_hash: &str, ttl_days: i32) -> Result<(), anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.refresh_tokens.push(RefreshToken {
+            user_id,
+            family_id: family_id.to_string(),
+            token_hash: token_hash.to_string(),
+            used: false,
+            revoked: false,
+            expires_at: now() + Duration::days(ttl_days.into()),
+        });
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, token_hash: &str, next_hash: &str, ttl_days: i32) -> Result<RefreshOutcome, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let Some(token) = inner.refresh_tokens.iter_mut().find(|token| token.token_hash == token_hash) else {
+            return Ok(RefreshOutcome::Invalid);
+        };
+        if token.revoked || token.expires_at <= now {
+            return Ok(RefreshOutcome::Invalid);
+        }
+        let (user_id, family_id) = (token.user_id, token.family_id.clone());
+        if token.used {
+            for token in inner.refresh_tokens.iter_mut().filter(|token| token.family_id == family_id) {
+                token.revoked = true;
+            }
+            return Ok(RefreshOutcome::Reused);
+        }
+
+        token.used = true;
+        inner.refresh_tokens.push(RefreshToken {
+            user_id,
+            family_id,
+            token_hash: next_hash.to_string(),
+            used: false,
+            revoked: false,
+            expires_at: now + Duration::days(ttl_days.into()),
+        });
+        Ok(RefreshOutcome::Rotated(user_id))
+    }
+
+    async fn revoke_refresh_token(&self, token_hash: &str) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let Some(family_id) = inner.refresh_tokens.iter().find(|token| token.token_hash == token_hash).map(|token| token.family_id.clone()) else {
+            return Ok(0);
+        };
+        let mut revoked = 0;
+        for token in inner.refresh_tokens.iter_mut().filter(|token| token.family_id == family_id && !token.revoked) {
+            token.revoked = true;
+            revoked += 1;
+        }
+        Ok(revoked)
+    }
+}
+
+#[async_trait(?Send)]
+impl ApiKeyRepository for MemoryUserRepository {
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        key_prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_in_days: Option<i32>
+    ) -> Result<UserApiKey, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let api_key = UserApiKey {
+            id: inner.next_id(),
+            user_id,
+            name: name.to_string(),
+            key_prefix: key_prefix.to_string(),
+            key_hash: key_hash.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: now,
+            last_used_at: None,
+            expires_at: expires_in_days.map(|days| now + Duration::days(days.into())),
+            revoked_at: None,
+        };
+        inner.api_keys.push(api_key.clone());
+        Ok(api_key)
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<UserApiKey>, anyhow::Error> {
+        let keys = lock(&self.inner)?
+            .api_keys
+            .iter()
+            .rev()
+            .filter(|key| key.user_id == user_id && key.revoked_at.is_none())
+            .cloned()
+            .collect();
+        Ok(keys)
+    }
+
+    async fn revoke(&self, user_id: i32, key_id: i32) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let key = inner.api_keys
+            .iter_mut()
+            .find(|key| key.id == key_id && key.user_id == user_id && key.revoked_at.is_none());
+        Ok(match key {
+            Some(key) => {
+                key.revoked_at = Some(now());
+                1
+            }
+            None => 0,
+        })
+    }
+
+    async fn authenticate(&self, key_hash: &str) -> Result<Option<UserApiKey>, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let now = now();
+        let key = inner.api_keys
+            .iter_mut()
+            .find(|key| key.key_hash == key_hash && key.revoked_at.is_none() && key.expires_at.is_none_or(|at| at > now))
+            .map(|key| {
+                key.last_used_at = Some(now);
+                key.clone()
+            });
+        Ok(key)
+    }
+}
+
+#[async_trait(?Send)]
+impl TwoFactorRepository for MemoryUserRepository {
+    async fn secret(&self, user_id: i32) -> Result<Option<TotpSecret>, anyhow::Error> {
+        Ok(lock(&self.inner)?.secrets.iter().find(|secret| secret.user_id == user_id).cloned())
+    }
+
+    async fn start_enrollment(&self, user_id: i32, secret: &str) -> Result<bool, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        if inner.secrets.iter().any(|existing| existing.user_id == user_id && existing.confirmed_at.is_some()) {
+            return Ok(false);
+        }
+        inner.secrets.retain(|existing| existing.user_id != user_id);
+        inner.secrets.push(TotpSecret {
+            user_id,
+            secret: secret.to_string(),
+            created_at: now(),
+            confirmed_at: None,
+            last_used_step: None,
+        });
+        Ok(true)
+    }
+
+    async fn confirm(&self, user_id: i32, step: i64, code_hashes: Vec<String>) -> Result<bool, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let Some(secret) = inner.secrets.iter_mut().find(|secret| secret.user_id == user_id && secret.confirmed_at.is_none()) else {
+            return Ok(false);
+        };
+        secret.confirmed_at = Some(now());
+        secret.last_used_step = Some(step);
+        inner.recovery_codes.retain(|code| code.user_id != user_id);
+        inner.recovery_codes.extend(code_hashes.into_iter().map(|code_hash| RecoveryCode { user_id, code_hash, used: false }));
+        Ok(true)
+    }
+
+    async fn use_step(&self, user_id: i32, step: i64) -> Result<bool, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let secret = inner.secrets.iter_mut().find(|secret| {
+            secret.user_id == user_id
+                && secret.confirmed_at.is_some()
+                && secret.last_used_step.is_none_or(|last| last < step)
+        });
+        Ok(match secret {
+            Some(secret) => {
+                secret.last_used_step = Some(step);
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let code = inner.recovery_codes
+            .iter_mut()
+            .find(|code| code.user_id == user_id && code.code_hash == code_hash && !code.used);
+        Ok(match code {
+            Some(code) => {
+                code.used = true;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn disable(&self, user_id: i32) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.recovery_codes.retain(|code| code.user_id != user_id);
+        let before = inner.secrets.len();
+        inner.secrets.retain(|secret| secret.user_id != user_id);
+        Ok(before - inner.secrets.len())
+    }
+}
+
+#[derive(Default)]
+struct Videos {
+    videos: Vec<Video>,
+    liked: Vec<LikedVideos>,
+    watched: Vec<WatchedVideos>,
+    next_id: i32,
+}
+
+impl Videos {
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn find(&self, id: i32) -> Result<Video, anyhow::Error> {
+        self.videos
+            .iter()
+            .find(|video| video.id == id)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(String::from("Video not found")).into())
+    }
+
+    fn upsert_watched(&mut self, user_id: i32, video_id: i32, position: i32, duration: i32, completed: bool) -> WatchedVideos {
+        let row = match self.watched.iter().position(|row| row.user_id == user_id && row.video_id == video_id) {
+            Some(index) => &mut self.watched[index],
+            None => {
+                let id = self.next_id();
+                self.watched.push(WatchedVideos {
+                    id,
+                    video_id,
+                    user_id,
+                    position: 0,
+                    duration: 0,
+                    completed: false,
+                    last_watched_at: now(),
+                });
+                self.watched.last_mut().expect("row was just pushed")
+            }
+        };
+        row.position = position;
+        row.duration = duration;
+        row.completed = completed;
+        row.last_watched_at = now();
+        row.clone()
+    }
+}
+
+/// The catalog and lists held in memory, for unit tests of the handlers.
+/// Starts empty.
+#[derive(Default)]
+pub struct MemoryVideoRepository {
+    inner: Mutex<Videos>,
+}
+
+#[async_trait(?Send)]
+impl VideoRepository for MemoryVideoRepository {
+    async fn list(&self) -> Result<Vec<Video>, anyhow::Error> {
+        Ok(lock(&self.inner)?.videos.clone())
+    }
+
+    async fn create(&self, form: VideoForm) -> Result<Video, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let video = Video {
+            id: inner.next_id(),
+            title: form.title,
+            description: form.description,
+            duration: form.duration,
+            release_year: form.release_year,
+            maturity_rating: form.maturity_rating,
+            created_at: now(),
+        };
+        inner.videos.push(video.clone());
+        Ok(video)
+    }
+
+    async fn update(&self, id: i32, form: VideoForm) -> Result<Option<Video>, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let Some(video) = inner.videos.iter_mut().find(|video| video.id == id) else {
+            return Ok(None);
+        };
+        video.title = form.title;
+        video.description = form.description;
+        video.duration = form.duration;
+        video.release_year = form.release_year;
+        video.maturity_rating = form.maturity_rating;
+        Ok(Some(video.clone()))
+    }
+
+    async fn delete(&self, id: i32) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let before = inner.videos.len();
+        inner.videos.retain(|video| video.id != id);
+        inner.liked.retain(|row| row.video_id != id);
+        inner.watched.retain(|row| row.video_id != id);
+        Ok(before - inner.videos.len())
+    }
+
+    async fn add_to_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<VideoListEntry, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.find(video_id)?;
+        match list {
+            VideoList::Liked => {
+                if inner.liked.iter().any(|row| row.user_id == user_id && row.video_id == video_id) {
+                    return Err(AppError::Conflict(String::from("Video is already liked")).into());
+                }
+                let liked = LikedVideos { id: inner.next_id(), video_id, user_id };
+                inner.liked.push(liked.clone());
+                Ok(VideoListEntry::Liked(liked))
+            }
+            // Like the upsert in Postgres, progress already saved is kept.
+            VideoList::Watched => {
+                let (position, duration) = inner.watched
+                    .iter()
+                    .find(|row| row.user_id == user_id && row.video_id == video_id)
+                    .map_or((0, 0), |row| (row.position, row.duration));
+                Ok(VideoListEntry::Watched(inner.upsert_watched(user_id, video_id, position, duration, true)))
+            }
+        }
+    }
+
+    async fn remove_from_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<usize, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        let removed = match list {
+            VideoList::Liked => {
+                let before = inner.liked.len();
+                inner.liked.retain(|row| row.user_id != user_id || row.video_id != video_id);
+                before - inner.liked.len()
+            }
+            VideoList::Watched => {
+                let before = inner.watched.len();
+                inner.watched.retain(|row| row.user_id != user_id || row.video_id != video_id);
+                before - inner.watched.len()
+            }
+        };
+        Ok(removed)
+    }
+
+    async fn liked(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        inner.liked
+            .iter()
+            .filter(|row| row.user_id == user_id)
+            .map(|row| inner.find(row.video_id))
+            .collect()
+    }
+
+    async fn watched(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        inner.watched
+            .iter()
+            .filter(|row| row.user_id == user_id && row.completed)
+            .map(|row| inner.find(row.video_id))
+            .collect()
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        inner.watched
+            .iter()
+            .filter(|row| row.user_id == user_id)
+            .map(|row| inner.find(row.video_id))
+            .collect()
+    }
+
+    async fn save_progress(&self, user_id: i32, video_id: i32, progress: WatchProgress) -> Result<WatchedVideos, anyhow::Error> {
+        let mut inner = lock(&self.inner)?;
+        inner.find(video_id)?;
+        let completed = progress.is_completed();
+        Ok(inner.upsert_watched(user_id, video_id, progress.position, progress.duration, completed))
+    }
+
+    async fn continue_watching(&self, user_id: i32, limit: i64) -> Result<Vec<ContinueWatching>, anyhow::Error> {
+        let inner = lock(&self.inner)?;
+        let mut rows: Vec<&WatchedVideos> = inner.watched
+            .iter()
+            .filter(|row| row.user_id == user_id && !row.completed)
+            .collect();
+        rows.sort_by_key(|row| std::cmp::Reverse(row.last_watched_at));
+        rows.into_iter()
+            .take(usize::try_from(limit).unwrap_or(0))
+            .map(|row| Ok(ContinueWatching { video: inner.find(row.video_id)?, progress: row.clone() }))
+            .collect()
+    }
+}
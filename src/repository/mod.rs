@@ -0,0 +1,257 @@
+#[cfg(feature = "async-db")]
+pub mod async_pg;
+pub mod memory;
+pub mod pg;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::DbPool;
+use crate::auth::Credentials;
+use crate::models::{
+    ContinueWatching,
+    RefreshOutcome,
+    Role,
+    SessionMeta,
+    TotpSecret,
+    User,
+    UserApiKey,
+    UserSession,
+    Video,
+    VideoForm,
+    VideoList,
+    VideoListEntry,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::settings::DatabaseSettings;
+use crate::throttle::ThrottleScope;
+
+pub use memory::{MemoryUserRepository, MemoryVideoRepository};
+pub use pg::{PgUserRepository, PgVideoRepository};
+#[cfg(feature = "async-db")]
+pub use async_pg::{AsyncPgUserRepository, AsyncPgVideoRepository};
+
+
+/// Accounts and the state that guards them: login throttles, password reset
+/// and email verification tokens. Handlers reach it through `AppState`, so
+/// their logic runs against Postgres in production and against memory in
+/// unit tests.
+#[async_trait(?Send)]
+pub trait UserRepository: Send + Sync {
+    async fn get(&self, id: i32) -> Result<Option<User>, anyhow::Error>;
+
+    /// `email` must already be normalized.
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, anyhow::Error>;
+
+    /// The account only when the email exists and the password matches.
+    async fn authenticate(&self, creds: Credentials, bcrypt_cost: u32) -> Result<Option<User>, anyhow::Error>;
+
+    /// Creates an account with the default role. A taken email fails with a
+    /// conflict.
+    async fn create(&self, creds: Credentials, bcrypt_cost: u32) -> Result<User, anyhow::Error>;
+
+    /// A role with the names of all its permissions, inherited ones included.
+    async fn role_permissions(&self, role_id: i32) -> Result<(Role, HashSet<String>), anyhow::Error>;
+
+    async fn roles(&self) -> Result<Vec<Role>, anyhow::Error>;
+
+    async fn find_role(&self, name: &str) -> Result<Option<Role>, anyhow::Error>;
+
+    /// Moves an account to another role. `None` when the account is missing.
+    async fn assign_role(&self, id: i32, role_id: i32) -> Result<Option<User>, anyhow::Error>;
+
+    async fn has_two_factor(&self, id: i32) -> Result<bool, anyhow::Error>;
+
+    /// Seconds until the subject may try again, or `None` when it is not locked.
+    async fn throttle_remaining(&self, scope: ThrottleScope, subject: &str) -> Result<Option<i64>, anyhow::Error>;
+
+    /// Counts an attempt and locks the subject for the delay of its scope's
+    /// policy. Returns that delay in seconds.
+    async fn record_attempt(&self, scope: ThrottleScope, subject: &str) -> Result<i32, anyhow::Error>;
+
+    async fn clear_throttle(&self, scope: ThrottleScope, subject: &str) -> Result<usize, anyhow::Error>;
+
+    /// Stores a reset token, dropping any issued before it.
+    async fn create_password_reset(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error>;
+
+    /// Uses up a live reset token and sets the password, ending the
+    /// account's sessions and refresh tokens and lifting its login lockout.
+    /// `false` when the token is unknown, expired or already used.
+    async fn reset_password(&self, token_hash: &str, password: &str, bcrypt_cost: u32) -> Result<bool, anyhow::Error>;
+
+    /// Stores a verification token, replacing any earlier one.
+    async fn create_email_verification(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error>;
+
+    async fn verification_sent_recently(&self, id: i32, cooldown_minutes: i32) -> Result<bool, anyhow::Error>;
+
+    /// Uses up a live verification token and marks the email verified.
+    /// `false` when the token is unknown or expired.
+    async fn verify_email(&self, token_hash: &str) -> Result<bool, anyhow::Error>;
+}
+
+/// What keeps an account signed in: login sessions, stored by the hash of
+/// their cookie key, and the refresh tokens of token mode.
+#[async_trait(?Send)]
+pub trait SessionRepository: Send + Sync {
+    /// Stores a new session, sweeping out expired ones on the way.
+    async fn insert(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<(), anyhow::Error>;
+
+    /// State of a live session, stamping it as seen.
+    async fn load(&self, key_hash: &str) -> Result<Option<String>, anyhow::Error>;
+
+    /// Replaces the state of a live session. Returns how many changed, zero
+    /// when it was revoked or expired meanwhile.
+    async fn update(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<usize, anyhow::Error>;
+
+    async fn extend(&self, key_hash: &str, ttl_seconds: i32) -> Result<(), anyhow::Error>;
+
+    async fn delete(&self, key_hash: &str) -> Result<(), anyhow::Error>;
+
+    /// Live sessions of the user, most recently seen first.
+    async fn list(&self, user_id: i32) -> Result<Vec<UserSession>, anyhow::Error>;
+
+    /// Ends one session of the user, or all of them when `session_id` is
+    /// `None`. Returns how many ended.
+    async fn revoke(&self, user_id: i32, session_id: Option<i32>) -> Result<usize, anyhow::Error>;
+
+    async fn create_refresh_token(&self, user_id: i32, family_id: &str, token_hash: &str, ttl_days: i32) -> Result<(), anyhow::Error>;
+
+    /// Uses up a refresh token and stores its successor in the same family.
+    /// Presenting a used token revokes its whole family.
+    async fn rotate_refresh_token(&self, token_hash: &str, next_hash: &str, ttl_days: i32) -> Result<RefreshOutcome, anyhow::Error>;
+
+    /// Revokes the family of a refresh token. Returns how many were revoked.
+    async fn revoke_refresh_token(&self, token_hash: &str) -> Result<usize, anyhow::Error>;
+}
+
+/// API keys of the users, stored by hash.
+#[async_trait(?Send)]
+pub trait ApiKeyRepository: Send + Sync {
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        key_prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_in_days: Option<i32>
+    ) -> Result<UserApiKey, anyhow::Error>;
+
+    /// Keys of the user that are not revoked, newest first.
+    async fn list(&self, user_id: i32) -> Result<Vec<UserApiKey>, anyhow::Error>;
+
+    /// Returns how many keys were revoked, zero when the user has no such
+    /// live key.
+    async fn revoke(&self, user_id: i32, key_id: i32) -> Result<usize, anyhow::Error>;
+
+    /// A live key by its hash, stamped as used. `None` when it is unknown,
+    /// revoked or expired.
+    async fn authenticate(&self, key_hash: &str) -> Result<Option<UserApiKey>, anyhow::Error>;
+}
+
+/// TOTP secrets and recovery codes.
+#[async_trait(?Send)]
+pub trait TwoFactorRepository: Send + Sync {
+    async fn secret(&self, user_id: i32) -> Result<Option<TotpSecret>, anyhow::Error>;
+
+    /// Stores a secret awaiting confirmation, replacing an unconfirmed one.
+    /// `false` when two-factor is already on.
+    async fn start_enrollment(&self, user_id: i32, secret: &str) -> Result<bool, anyhow::Error>;
+
+    /// Turns the pending secret on and replaces the recovery codes. `false`
+    /// when no secret was pending.
+    async fn confirm(&self, user_id: i32, step: i64, code_hashes: Vec<String>) -> Result<bool, anyhow::Error>;
+
+    /// Records the time step of an accepted code. `false` when this or a
+    /// later step was accepted before, so the code is a replay.
+    async fn use_step(&self, user_id: i32, step: i64) -> Result<bool, anyhow::Error>;
+
+    /// Burns a recovery code. `false` when it is unknown or used.
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, anyhow::Error>;
+
+    /// Deletes the secret and the recovery codes.
+    async fn disable(&self, user_id: i32) -> Result<usize, anyhow::Error>;
+}
+
+/// The video catalog and the liked and watched lists of every user.
+/// Referring to a video missing from the catalog fails with not found.
+#[async_trait(?Send)]
+pub trait VideoRepository: Send + Sync {
+    async fn list(&self) -> Result<Vec<Video>, anyhow::Error>;
+
+    async fn create(&self, form: VideoForm) -> Result<Video, anyhow::Error>;
+
+    async fn update(&self, id: i32, form: VideoForm) -> Result<Option<Video>, anyhow::Error>;
+
+    async fn delete(&self, id: i32) -> Result<usize, anyhow::Error>;
+
+    /// Likes a video, or marks it watched to the end.
+    async fn add_to_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<VideoListEntry, anyhow::Error>;
+
+    async fn remove_from_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<usize, anyhow::Error>;
+
+    async fn liked(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error>;
+
+    /// Videos watched to the end.
+    async fn watched(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error>;
+
+    /// Every video the user started, finished or not.
+    async fn history(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error>;
+
+    async fn save_progress(&self, user_id: i32, video_id: i32, progress: WatchProgress) -> Result<WatchedVideos, anyhow::Error>;
+
+    /// Unfinished videos, most recently watched first.
+    async fn continue_watching(&self, user_id: i32, limit: i64) -> Result<Vec<ContinueWatching>, anyhow::Error>;
+}
+
+/// Every repository `AppState` holds.
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub videos: Arc<dyn VideoRepository>,
+    pub sessions: Arc<dyn SessionRepository>,
+    pub api_keys: Arc<dyn ApiKeyRepository>,
+    pub two_factor: Arc<dyn TwoFactorRepository>,
+}
+
+impl Repositories {
+    /// Everything in memory, with one `accounts` behind the account traits
+    /// so a password reset ends the sessions it holds.
+    pub fn memory(accounts: Arc<MemoryUserRepository>, videos: Arc<MemoryVideoRepository>) -> Self {
+        Repositories {
+            users: accounts.clone(),
+            videos,
+            sessions: accounts.clone(),
+            api_keys: accounts.clone(),
+            two_factor: accounts,
+        }
+    }
+}
+
+/// The Postgres repositories the server runs on. With the `async-db`
+/// feature accounts and videos go over diesel-async with a pool of their
+/// own; sessions, API keys and two-factor stay on `pool` either way.
+#[cfg(feature = "async-db")]
+pub fn postgres(database: &DatabaseSettings, pool: &DbPool) -> Repositories {
+    let async_pool = crate::db_async::connect(database);
+    let accounts = Arc::new(PgUserRepository::new(pool.clone()));
+    Repositories {
+        users: Arc::new(AsyncPgUserRepository::new(async_pool.clone())),
+        videos: Arc::new(AsyncPgVideoRepository::new(async_pool)),
+        sessions: accounts.clone(),
+        api_keys: accounts.clone(),
+        two_factor: accounts,
+    }
+}
+
+#[cfg(not(feature = "async-db"))]
+pub fn postgres(_database: &DatabaseSettings, pool: &DbPool) -> Repositories {
+    let accounts = Arc::new(PgUserRepository::new(pool.clone()));
+    Repositories {
+        users: accounts.clone(),
+        videos: Arc::new(PgVideoRepository::new(pool.clone())),
+        sessions: accounts.clone(),
+        api_keys: accounts.clone(),
+        two_factor: accounts,
+    }
+}
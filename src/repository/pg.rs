@@ -0,0 +1,501 @@
+use std::collections::HashSet;
+use async_trait::async_trait;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use crate::DbPool;
+use crate::auth::Credentials;
+use crate::db_actions;
+use crate::errors::AppError;
+use crate::metrics::block;
+use crate::models::{
+    ContinueWatching,
+    RefreshOutcome,
+    Role,
+    SessionMeta,
+    TotpSecret,
+    User,
+    UserApiKey,
+    UserSession,
+    Video,
+    VideoForm,
+    VideoList,
+    VideoListEntry,
+    WatchProgress,
+    WatchedVideos
+};
+use crate::throttle::ThrottleScope;
+use super::{ApiKeyRepository, SessionRepository, TwoFactorRepository, UserRepository, VideoRepository};
+
+
+/// Accounts in Postgres, along with their sessions, API keys and two-factor
+/// secrets, through the functions of `db_actions`. Every call checks a
+/// connection out of the pool on the blocking thread pool.
+#[derive(Clone)]
+pub struct PgUserRepository {
+    pool: DbPool,
+}
+
+impl PgUserRepository {
+    pub fn new(pool: DbPool) -> Self {
+        PgUserRepository { pool }
+    }
+}
+
+#[async_trait(?Send)]
+impl UserRepository for PgUserRepository {
+    async fn get(&self, id: i32) -> Result<Option<User>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_user(&mut *pool.get()?, id)
+        })
+        .await?
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, anyhow::Error> {
+        let pool = self.pool.clone();
+        let email = email.to_string();
+        block(move || {
+            match db_actions::find_user_by_email(&mut *pool.get()?, &email) {
+                Ok(user) => Ok(Some(user)),
+                Err(err) if matches!(err.downcast_ref::<DieselError>(), Some(DieselError::NotFound)) => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+        .await?
+    }
+
+    async fn authenticate(&self, creds: Credentials, bcrypt_cost: u32) -> Result<Option<User>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::authenticate(creds, &mut *pool.get()?, bcrypt_cost)
+        })
+        .await?
+    }
+
+    async fn create(&self, creds: Credentials, bcrypt_cost: u32) -> Result<User, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::create_user(&mut *pool.get()?, creds, bcrypt_cost)
+        })
+        .await?
+    }
+
+    async fn role_permissions(&self, role_id: i32) -> Result<(Role, HashSet<String>), anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_role_permissions(&mut *pool.get()?, role_id)
+        })
+        .await?
+    }
+
+    async fn roles(&self) -> Result<Vec<Role>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_roles(&mut *pool.get()?)
+        })
+        .await?
+    }
+
+    async fn find_role(&self, name: &str) -> Result<Option<Role>, anyhow::Error> {
+        let pool = self.pool.clone();
+        let name = name.to_string();
+        block(move || {
+            db_actions::find_role(&mut *pool.get()?, &name)
+        })
+        .await?
+    }
+
+    async fn assign_role(&self, id: i32, role_id: i32) -> Result<Option<User>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::set_user_role(&mut *pool.get()?, id, role_id)
+        })
+        .await?
+    }
+
+    async fn has_two_factor(&self, id: i32) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::has_two_factor(&mut *pool.get()?, id)
+        })
+        .await?
+    }
+
+    async fn throttle_remaining(&self, scope: ThrottleScope, subject: &str) -> Result<Option<i64>, anyhow::Error> {
+        let pool = self.pool.clone();
+        let subject = subject.to_string();
+        block(move || {
+            db_actions::throttle_remaining(&mut *pool.get()?, scope, &subject)
+        })
+        .await?
+    }
+
+    async fn record_attempt(&self, scope: ThrottleScope, subject: &str) -> Result<i32, anyhow::Error> {
+        let pool = self.pool.clone();
+        let subject = subject.to_string();
+        block(move || {
+            db_actions::record_attempt(&mut *pool.get()?, scope, &subject)
+        })
+        .await?
+    }
+
+    async fn clear_throttle(&self, scope: ThrottleScope, subject: &str) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        let subject = subject.to_string();
+        block(move || {
+            db_actions::clear_throttle(&mut *pool.get()?, scope, &subject)
+        })
+        .await?
+    }
+
+    async fn create_password_reset(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let token_hash = token_hash.to_string();
+        block(move || {
+            db_actions::create_password_reset(&mut *pool.get()?, id, &token_hash, ttl_minutes)
+        })
+        .await?
+    }
+
+    async fn reset_password(&self, token_hash: &str, password: &str, bcrypt_cost: u32) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        let token_hash = token_hash.to_string();
+        let password = password.to_string();
+        block(move || {
+            db_actions::reset_password(&mut *pool.get()?, &token_hash, &password, bcrypt_cost)
+        })
+        .await?
+    }
+
+    async fn create_email_verification(&self, id: i32, token_hash: &str, ttl_minutes: i32) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let token_hash = token_hash.to_string();
+        block(move || {
+            db_actions::create_email_verification(&mut *pool.get()?, id, &token_hash, ttl_minutes)
+        })
+        .await?
+    }
+
+    async fn verification_sent_recently(&self, id: i32, cooldown_minutes: i32) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::verification_sent_recently(&mut *pool.get()?, id, cooldown_minutes)
+        })
+        .await?
+    }
+
+    async fn verify_email(&self, token_hash: &str) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        let token_hash = token_hash.to_string();
+        block(move || {
+            db_actions::verify_email(&mut *pool.get()?, &token_hash)
+        })
+        .await?
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionRepository for PgUserRepository {
+    async fn insert(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let (key_hash, state) = (key_hash.to_string(), state.to_string());
+        block(move || {
+            db_actions::insert_session(&mut *pool.get()?, &key_hash, &state, meta, ttl_seconds)
+        })
+        .await?
+    }
+
+    async fn load(&self, key_hash: &str) -> Result<Option<String>, anyhow::Error> {
+        let pool = self.pool.clone();
+        let key_hash = key_hash.to_string();
+        block(move || {
+            db_actions::load_session(&mut *pool.get()?, &key_hash)
+        })
+        .await?
+    }
+
+    async fn update(&self, key_hash: &str, state: &str, meta: SessionMeta, ttl_seconds: i32) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        let (key_hash, state) = (key_hash.to_string(), state.to_string());
+        block(move || {
+            db_actions::update_session(&mut *pool.get()?, &key_hash, &state, meta, ttl_seconds)
+        })
+        .await?
+    }
+
+    async fn extend(&self, key_hash: &str, ttl_seconds: i32) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let key_hash = key_hash.to_string();
+        block(move || {
+            db_actions::extend_session(&mut *pool.get()?, &key_hash, ttl_seconds)
+        })
+        .await?
+    }
+
+    async fn delete(&self, key_hash: &str) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let key_hash = key_hash.to_string();
+        block(move || {
+            db_actions::delete_session(&mut *pool.get()?, &key_hash)
+        })
+        .await?
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<UserSession>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_user_sessions(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn revoke(&self, user_id: i32, session_id: Option<i32>) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::delete_user_sessions(&mut *pool.get()?, user_id, session_id)
+        })
+        .await?
+    }
+
+    async fn create_refresh_token(&self, user_id: i32, family_id: &str, token_hash: &str, ttl_days: i32) -> Result<(), anyhow::Error> {
+        let pool = self.pool.clone();
+        let (family_id, token_hash) = (family_id.to_string(), token_hash.to_string());
+        block(move || {
+            db_actions::create_refresh_token(&mut *pool.get()?, user_id, &family_id, &token_hash, ttl_days)
+        })
+        .await?
+    }
+
+    async fn rotate_refresh_token(&self, token_hash: &str, next_hash: &str, ttl_days: i32) -> Result<RefreshOutcome, anyhow::Error> {
+        let pool = self.pool.clone();
+        let (token_hash, next_hash) = (token_hash.to_string(), next_hash.to_string());
+        block(move || {
+            db_actions::rotate_refresh_token(&mut *pool.get()?, &token_hash, &next_hash, ttl_days)
+        })
+        .await?
+    }
+
+    async fn revoke_refresh_token(&self, token_hash: &str) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        let token_hash = token_hash.to_string();
+        block(move || {
+            db_actions::revoke_refresh_token(&mut *pool.get()?, &token_hash)
+        })
+        .await?
+    }
+}
+
+#[async_trait(?Send)]
+impl ApiKeyRepository for PgUserRepository {
+    async fn create(
+        &self,
+        user_id: i32,
+        name: &str,
+        key_prefix: &str,
+        key_hash: &str,
+        scopes: &[String],
+        expires_in_days: Option<i32>
+    ) -> Result<UserApiKey, anyhow::Error> {
+        let pool = self.pool.clone();
+        let (name, key_prefix, key_hash, scopes) = (name.to_string(), key_prefix.to_string(), key_hash.to_string(), scopes.to_vec());
+        block(move || {
+            db_actions::create_api_key(&mut *pool.get()?, user_id, &name, &key_prefix, &key_hash, &scopes, expires_in_days)
+        })
+        .await?
+    }
+
+    async fn list(&self, user_id: i32) -> Result<Vec<UserApiKey>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_api_keys(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn revoke(&self, user_id: i32, key_id: i32) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::revoke_api_key(&mut *pool.get()?, user_id, key_id)
+        })
+        .await?
+    }
+
+    async fn authenticate(&self, key_hash: &str) -> Result<Option<UserApiKey>, anyhow::Error> {
+        let pool = self.pool.clone();
+        let key_hash = key_hash.to_string();
+        block(move || {
+            db_actions::authenticate_api_key(&mut *pool.get()?, &key_hash)
+        })
+        .await?
+    }
+}
+
+#[async_trait(?Send)]
+impl TwoFactorRepository for PgUserRepository {
+    async fn secret(&self, user_id: i32) -> Result<Option<TotpSecret>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_totp_secret(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn start_enrollment(&self, user_id: i32, secret: &str) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        let secret = secret.to_string();
+        block(move || {
+            db_actions::start_totp_enrollment(&mut *pool.get()?, user_id, &secret)
+        })
+        .await?
+    }
+
+    async fn confirm(&self, user_id: i32, step: i64, code_hashes: Vec<String>) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::confirm_totp(&mut *pool.get()?, user_id, step, &code_hashes)
+        })
+        .await?
+    }
+
+    async fn use_step(&self, user_id: i32, step: i64) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::use_totp_step(&mut *pool.get()?, user_id, step)
+        })
+        .await?
+    }
+
+    async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> Result<bool, anyhow::Error> {
+        let pool = self.pool.clone();
+        let code_hash = code_hash.to_string();
+        block(move || {
+            db_actions::use_recovery_code(&mut *pool.get()?, user_id, &code_hash)
+        })
+        .await?
+    }
+
+    async fn disable(&self, user_id: i32) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::disable_two_factor(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+}
+
+/// The catalog and lists in Postgres, through the functions of `db_actions`.
+#[derive(Clone)]
+pub struct PgVideoRepository {
+    pool: DbPool,
+}
+
+impl PgVideoRepository {
+    pub fn new(pool: DbPool) -> Self {
+        PgVideoRepository { pool }
+    }
+}
+
+/// Inserting a row for a video missing from the catalog trips the foreign
+/// key, liking a video twice the unique key. Watch rows are upserted, so only
+/// likes can be duplicates.
+pub(super) fn map_list_error(err: anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            AppError::NotFound(String::from("Video not found")).into()
+        }
+        Some(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            AppError::Conflict(String::from("Video is already liked")).into()
+        }
+        _ => err,
+    }
+}
+
+#[async_trait(?Send)]
+impl VideoRepository for PgVideoRepository {
+    async fn list(&self) -> Result<Vec<Video>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_videos(&mut *pool.get()?)
+        })
+        .await?
+    }
+
+    async fn create(&self, form: VideoForm) -> Result<Video, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::create_video(&mut *pool.get()?, form)
+        })
+        .await?
+    }
+
+    async fn update(&self, id: i32, form: VideoForm) -> Result<Option<Video>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::update_video(&mut *pool.get()?, id, form)
+        })
+        .await?
+    }
+
+    async fn delete(&self, id: i32) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::delete_video(&mut *pool.get()?, id)
+        })
+        .await?
+    }
+
+    async fn add_to_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<VideoListEntry, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::create_liked_videos(&mut *pool.get()?, user_id, video_id, list).map_err(map_list_error)
+        })
+        .await?
+    }
+
+    async fn remove_from_list(&self, user_id: i32, video_id: i32, list: VideoList) -> Result<usize, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::delete_user_video(&mut *pool.get()?, user_id, video_id, list)
+        })
+        .await?
+    }
+
+    async fn liked(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_user_info(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn watched(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_watched_videos(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn history(&self, user_id: i32) -> Result<Vec<Video>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_watch_history(&mut *pool.get()?, user_id)
+        })
+        .await?
+    }
+
+    async fn save_progress(&self, user_id: i32, video_id: i32, progress: WatchProgress) -> Result<WatchedVideos, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::upsert_watch_progress(&mut *pool.get()?, user_id, video_id, progress).map_err(map_list_error)
+        })
+        .await?
+    }
+
+    async fn continue_watching(&self, user_id: i32, limit: i64) -> Result<Vec<ContinueWatching>, anyhow::Error> {
+        let pool = self.pool.clone();
+        block(move || {
+            db_actions::get_continue_watching(&mut *pool.get()?, user_id, limit)
+        })
+        .await?
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use actix_web::{
+    HttpResponse, web, get, put,
+};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::RequirePermission;
+use crate::models::{AssignRole, RoleWithPermissions};
+
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Fetches every role with its permissions, inherited ones included",
+            body = [RoleWithPermissions]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the roles:assign permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission roles:assign")).problem())
+        ),
+    )
+)]
+#[get("/roles", wrap = "RequirePermission(\"roles:assign\")")]
+pub async fn list_roles(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let mut roles = Vec::new();
+    for role in state.users.roles().await? {
+        let (role, permissions) = state.users.role_permissions(role.id).await?;
+        let mut permissions: Vec<String> = permissions.into_iter().collect();
+        permissions.sort();
+        roles.push(RoleWithPermissions { role, permissions });
+    }
+
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+#[utoipa::path(
+    request_body = AssignRole,
+    responses(
+        (
+            status = 200,
+            description = "Gives a user another role",
+            body = User
+        ),
+        (
+            status = 400,
+            description = "No role has this name",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Unknown role")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the roles:assign permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission roles:assign")).problem())
+        ),
+        (
+            status = 404,
+            description = "User Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("User not found")).problem())
+        ),
+    )
+)]
+#[put("/user/{id}/role", wrap = "RequirePermission(\"roles:assign\")")]
+pub async fn assign_role(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>,
+    body: web::Json<AssignRole>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    let name = body.into_inner().role;
+    let Some(role) = state.users.find_role(&name).await? else {
+        return Err(AppError::Validation(String::from("Unknown role")));
+    };
+
+    let user = state.users.assign_role(user_id, role.id).await?;
+
+    match user {
+        Some(user) => Ok(HttpResponse::Ok().json(user)),
+        None => Err(AppError::NotFound(String::from("User not found"))),
+    }
+}
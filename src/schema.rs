@@ -1,14 +1,150 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        #[max_length = 8]
+        key_prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Bpchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        token_hash -> Bpchar,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     liked_videos (id) {
         id -> Int4,
-        title -> Text,
         video_id -> Int4,
         user_id -> Int4,
     }
 }
 
+diesel::table! {
+    login_throttles (scope, subject) {
+        #[max_length = 16]
+        scope -> Varchar,
+        subject -> Text,
+        attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        last_attempt_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        token_hash -> Bpchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    permissions (id) {
+        id -> Int4,
+        #[max_length = 64]
+        name -> Varchar,
+        description -> Text,
+    }
+}
+
+diesel::table! {
+    rate_limits (key) {
+        key -> Text,
+        value -> Float8,
+        previous -> Float8,
+        stamp_ms -> Int8,
+        expires_ms -> Int8,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        code_hash -> Bpchar,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 32]
+        family_id -> Varchar,
+        #[max_length = 64]
+        token_hash -> Bpchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Int4,
+        permission_id -> Int4,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Int4,
+        #[max_length = 32]
+        name -> Varchar,
+        parent_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Int4,
+        #[max_length = 64]
+        key_hash -> Bpchar,
+        user_id -> Nullable<Int4>,
+        state -> Text,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    totp_secrets (user_id) {
+        user_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        created_at -> Timestamp,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
@@ -16,25 +152,66 @@ diesel::table! {
         email -> Varchar,
         #[max_length = 60]
         password_hash -> Bpchar,
-        #[max_length = 5]
-        role -> Nullable<Varchar>,
+        email_verified_at -> Nullable<Timestamp>,
+        role_id -> Int4,
     }
 }
 
 diesel::table! {
-    watched_videos (id) {
+    videos (id) {
         id -> Int4,
         title -> Text,
+        description -> Text,
+        duration -> Int4,
+        release_year -> Nullable<Int4>,
+        #[max_length = 10]
+        maturity_rating -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    watched_videos (id) {
+        id -> Int4,
         video_id -> Int4,
         user_id -> Int4,
+        position -> Int4,
+        duration -> Int4,
+        completed -> Bool,
+        last_watched_at -> Timestamp,
     }
 }
 
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(email_verification_tokens -> users (user_id));
 diesel::joinable!(liked_videos -> users (user_id));
+diesel::joinable!(liked_videos -> videos (video_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission_id));
+diesel::joinable!(role_permissions -> roles (role_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(totp_secrets -> users (user_id));
+diesel::joinable!(users -> roles (role_id));
 diesel::joinable!(watched_videos -> users (user_id));
+diesel::joinable!(watched_videos -> videos (video_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_keys,
+    email_verification_tokens,
     liked_videos,
+    login_throttles,
+    password_reset_tokens,
+    permissions,
+    rate_limits,
+    recovery_codes,
+    refresh_tokens,
+    role_permissions,
+    roles,
+    sessions,
+    totp_secrets,
     users,
+    videos,
     watched_videos,
 );
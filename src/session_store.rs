@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use actix_session::{
+    storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    Session, SessionInsertError,
+};
+use std::sync::Arc;
+use actix_web::{cookie::time::Duration, HttpRequest};
+use async_trait::async_trait;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use crate::models::SessionMeta;
+use crate::repository::SessionRepository;
+use crate::throttle::client_ip;
+use crate::ultils::utils::hash_token;
+
+/// Session entries copied into their own columns by `RepositorySessionStore`.
+pub const SESSION_USER_ID: &str = "user_id";
+pub const SESSION_USER_AGENT: &str = "user_agent";
+pub const SESSION_IP: &str = "ip";
+
+const SESSION_KEY_LENGTH: usize = 64;
+
+
+/// Records who owns the session and where it was opened from, so the user
+/// can tell their sessions apart. Call it right after logging a user in.
+pub fn remember_device(
+    session: &Session,
+    req: &HttpRequest,
+    user_id: i32
+)
+-> Result<(), SessionInsertError> {
+    let user_agent = req.headers()
+        .get("User-Agent")
+        .and_then(|agent| agent.to_str().ok())
+        .unwrap_or("unknown");
+    session.insert(SESSION_USER_ID, user_id)?;
+    session.insert(SESSION_USER_AGENT, user_agent)?;
+    session.insert(SESSION_IP, client_ip(req))
+}
+
+/// Session values are stored JSON encoded.
+fn entry<T: DeserializeOwned>(state: &HashMap<String, String>, key: &str) -> Option<T> {
+    state.get(key).and_then(|value| serde_json::from_str(value).ok())
+}
+
+fn session_meta(state: &HashMap<String, String>) -> SessionMeta {
+    SessionMeta {
+        user_id: entry(state, SESSION_USER_ID),
+        user_agent: entry(state, SESSION_USER_AGENT),
+        ip: entry(state, SESSION_IP),
+    }
+}
+
+fn ttl_seconds(ttl: &Duration) -> i32 {
+    ttl.whole_seconds().clamp(0, i64::from(i32::MAX)) as i32
+}
+
+fn generate_session_key() -> SessionKey {
+    let key: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    // Far below the 4064 byte limit, so this cannot fail.
+    SessionKey::try_from(key).expect("Session key is too long")
+}
+
+/// Keeps sessions in the session repository, the `sessions` table in
+/// production, so they survive restarts and can be revoked by deleting
+/// them. Only a hash of the session key is stored, like the tokens handed
+/// out by mail.
+#[derive(Clone)]
+pub struct RepositorySessionStore {
+    sessions: Arc<dyn SessionRepository>,
+}
+
+impl RepositorySessionStore {
+    pub fn new(sessions: Arc<dyn SessionRepository>) -> Self {
+        RepositorySessionStore { sessions }
+    }
+
+    async fn insert(
+        &self,
+        state: HashMap<String, String>,
+        ttl: &Duration
+    )
+    -> Result<SessionKey, anyhow::Error> {
+        let body = serde_json::to_string(&state)?;
+        let meta = session_meta(&state);
+        let key = generate_session_key();
+        let key_hash = hash_token(key.as_ref());
+        self.sessions.insert(&key_hash, &body, meta, ttl_seconds(ttl)).await?;
+
+        Ok(key)
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for RepositorySessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let key_hash = hash_token(session_key.as_ref());
+        let state = self.sessions.load(&key_hash).await.map_err(LoadError::Other)?;
+
+        state
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|err| LoadError::Deserialization(err.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration
+    )
+    -> Result<SessionKey, SaveError> {
+        self.insert(session_state, ttl).await.map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration
+    )
+    -> Result<SessionKey, UpdateError> {
+        let body = serde_json::to_string(&session_state)
+            .map_err(|err| UpdateError::Serialization(err.into()))?;
+        let meta = session_meta(&session_state);
+        let key_hash = hash_token(session_key.as_ref());
+        let updated = self.sessions
+            .update(&key_hash, &body, meta, ttl_seconds(ttl))
+            .await
+            .map_err(UpdateError::Other)?;
+
+        if updated > 0 {
+            return Ok(session_key);
+        }
+        // The session was revoked or expired meanwhile. Its state still
+        // holds the identity, so the client gets an empty session instead of
+        // a logged in one.
+        self.insert(HashMap::new(), ttl).await.map_err(UpdateError::Other)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        let key_hash = hash_token(session_key.as_ref());
+        self.sessions.extend(&key_hash, ttl_seconds(ttl)).await
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let key_hash = hash_token(session_key.as_ref());
+        self.sessions.delete(&key_hash).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use actix_session::storage::SessionStore;
+    use actix_web::cookie::time::Duration;
+    use crate::repository::memory::MemoryUserRepository;
+    use super::{RepositorySessionStore, SESSION_USER_ID};
+
+    #[actix_web::test]
+    async fn updating_a_revoked_session_does_not_restore_it() {
+        let store = RepositorySessionStore::new(Arc::new(MemoryUserRepository::default()));
+        let ttl = Duration::hours(1);
+        let state = HashMap::from([(String::from(SESSION_USER_ID), String::from("7"))]);
+        let key = store.save(state.clone(), &ttl).await.unwrap();
+
+        store.delete(&key).await.unwrap();
+        let renewed = store.update(key, state, &ttl).await.unwrap();
+
+        assert_eq!(store.load(&renewed).await.unwrap(), Some(HashMap::new()));
+    }
+}
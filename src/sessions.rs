@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use actix_session::Session;
+use actix_web::{
+    HttpResponse, web, get, delete,
+};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::AuthenticatedUser;
+
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Fetches the active sessions of the logged in user",
+            body = [UserSession]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[get("/me/sessions")]
+pub async fn list_sessions(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let sessions = state.sessions.list(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 204,
+            description = "Logs the user out of one of their sessions",
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 404,
+            description = "Session Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Session not found")).problem())
+        ),
+    )
+)]
+#[delete("/me/sessions/{id}")]
+pub async fn revoke_session(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let session_id = path.into_inner();
+    let revoked = state.sessions.revoke(user_id, Some(session_id)).await?;
+
+    if revoked == 0 {
+        Err(AppError::NotFound(String::from("Session not found")))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 204,
+            description = "Logs the user out everywhere, this session included",
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[delete("/me/sessions")]
+pub async fn revoke_all_sessions(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    session: Session
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    state.sessions.revoke(user_id, None).await?;
+
+    // Drop the cookie too, or this request would save the session again.
+    session.purge();
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,466 @@
+use std::env;
+use std::path::Path;
+use config::{Config, ConfigError, Environment, File, Source};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+use crate::rate_limit::RateLimitConfig;
+
+/// Environment picked when `APP_ENVIRONMENT` is not set.
+const DEFAULT_ENVIRONMENT: &str = "development";
+/// Directory holding the `<environment>.toml` files.
+const CONFIG_DIR: &str = "config";
+/// Lowest and highest costs the bcrypt crate accepts.
+const BCRYPT_COST_RANGE: std::ops::RangeInclusive<u32> = 4..=31;
+/// Shortest session signing key the cookie crate accepts.
+const MIN_SESSION_KEY_LENGTH: usize = 64;
+/// Shortest HS256 secret accepted, matching the output size of SHA-256.
+const MIN_TOKEN_SECRET_LENGTH: usize = 32;
+
+
+/// Everything the server can be tuned with. Built from the defaults below,
+/// then `config/<APP_ENVIRONMENT>.toml`, then `APP__*` variables, where `__`
+/// separates the levels: `APP__SERVER__PORT=9000`,
+/// `APP__CORS__ALLOWED_ORIGINS=https://a.example,https://b.example`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Name of the loaded environment, `development` unless `APP_ENVIRONMENT`
+    /// says otherwise
+    pub environment: String,
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub session: SessionSettings,
+    pub cors: CorsSettings,
+    pub auth: AuthSettings,
+    pub tokens: TokenSettings,
+    pub rate_limit: RateLimitConfig,
+    pub mail: MailSettings,
+    pub tracing: TracingSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    /// Falls back to `DATABASE_URL`
+    pub url: String,
+    /// Most connections the server opens, split between the two pools under
+    /// `async-db`
+    pub pool_size: u32,
+    /// Applies pending migrations at startup
+    pub run_migrations: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    /// How long a login session lasts without being used
+    pub ttl_hours: i64,
+    /// Signs and encrypts the session cookies, at least 64 bytes. Required
+    /// outside development, where a key generated at startup is used instead
+    /// and every session ends with the process.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins allowed to call the API, `*` for any
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// Work factor of new password hashes
+    pub bcrypt_cost: u32,
+    /// Turns logins away until the account has confirmed its email
+    pub require_email_verification: bool,
+    /// Keeps admin accounts out of permission guarded routes until they
+    /// turn on two-factor authentication
+    pub require_admin_2fa: bool,
+}
+
+/// Bearer token mode, off unless `private_key_file` and `public_key_file`
+/// name an Ed25519 PEM key pair (EdDSA), or `secret` holds a shared secret
+/// (HS256). The key pair wins when both are set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TokenSettings {
+    /// At least 32 bytes
+    pub secret: Option<String>,
+    pub private_key_file: Option<String>,
+    pub public_key_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MailSettings {
+    /// File the mail is appended to as JSON lines, logged when unset
+    pub outbox: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TracingSettings {
+    /// `tracing_subscriber` filter, like `info` or `actix_diesel=debug,info`
+    pub filter: String,
+    pub format: LogFormat,
+    /// OTLP/HTTP traces endpoint of a collector, like
+    /// `http://localhost:4318/v1/traces`. Spans are only logged when unset.
+    pub otlp_endpoint: Option<String>,
+    /// Name the spans are exported under
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line and colored, for a terminal
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            host: String::from("127.0.0.1"),
+            port: 8080,
+        }
+    }
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        DatabaseSettings {
+            url: String::new(),
+            pool_size: 10,
+            run_migrations: false,
+        }
+    }
+}
+
+impl DatabaseSettings {
+    /// Size of the r2d2 pool: all of `pool_size`, or half of it when the
+    /// diesel-async pool takes the rest.
+    pub fn blocking_pool_size(&self) -> u32 {
+        if cfg!(feature = "async-db") {
+            self.pool_size / 2
+        } else {
+            self.pool_size
+        }
+    }
+
+    /// Size of the diesel-async pool, what the r2d2 pool leaves of `pool_size`.
+    pub fn async_pool_size(&self) -> u32 {
+        self.pool_size - self.blocking_pool_size()
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        SessionSettings { ttl_hours: 24, key: None }
+    }
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        CorsSettings {
+            allowed_origins: vec![String::from("*")],
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            require_email_verification: true,
+            require_admin_2fa: false,
+        }
+    }
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings {
+            filter: String::from("info"),
+            format: LogFormat::Pretty,
+            otlp_endpoint: None,
+            service_name: String::from(env!("CARGO_PKG_NAME")),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads and validates the settings of the environment named by
+    /// `APP_ENVIRONMENT`. Its file is optional for the default environment
+    /// and required for any other, so a typo does not go unnoticed.
+    pub fn load() -> Result<Self, ConfigError> {
+        let environment = env::var("APP_ENVIRONMENT").ok();
+        let name = environment.as_deref().unwrap_or(DEFAULT_ENVIRONMENT);
+        Settings::build_from(
+            name,
+            File::with_name(&format!("{}/{}", CONFIG_DIR, name)).required(environment.is_some()),
+            Environment::with_prefix("APP")
+                .separator("__")
+                .list_separator(",")
+                .with_list_parse_key("cors.allowed_origins")
+                .try_parsing(true),
+        )
+    }
+
+    fn build_from(
+        name: &str,
+        file: impl Source + Send + Sync + 'static,
+        environment: Environment
+    )
+    -> Result<Self, ConfigError> {
+        let settings: Settings = Config::builder()
+            .set_default("database.url", env::var("DATABASE_URL").unwrap_or_default())?
+            .add_source(file)
+            .add_source(environment)
+            .set_override("environment", name)?
+            .build()?
+            .try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reports every invalid value at once, named by its key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.server.host.is_empty() {
+            problems.push(String::from("server.host must not be empty"));
+        }
+        if self.database.url.is_empty() {
+            problems.push(String::from("database.url is not set, set DATABASE_URL or APP__DATABASE__URL"));
+        }
+        // A connection for each pool.
+        let min_pool_size = if cfg!(feature = "async-db") { 2 } else { 1 };
+        if self.database.pool_size < min_pool_size {
+            problems.push(format!("database.pool_size must be at least {}", min_pool_size));
+        }
+        if self.session.ttl_hours < 1 {
+            problems.push(String::from("session.ttl_hours must be at least 1"));
+        }
+        match &self.session.key {
+            Some(key) if key.len() < MIN_SESSION_KEY_LENGTH => problems.push(format!(
+                "session.key must be at least {} bytes long", MIN_SESSION_KEY_LENGTH
+            )),
+            None if !self.is_development() => problems.push(String::from(
+                "session.key is not set, set APP__SESSION__KEY so sessions survive a restart"
+            )),
+            _ => {}
+        }
+        if self.cors.allowed_origins.is_empty() {
+            problems.push(String::from("cors.allowed_origins must not be empty, use \"*\" to allow any origin"));
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                problems.push(format!("cors.allowed_origins has {:?}, origins start with http:// or https://", origin));
+            }
+        }
+        if let Some(secret) = &self.tokens.secret {
+            if secret.len() < MIN_TOKEN_SECRET_LENGTH {
+                problems.push(format!("tokens.secret must be at least {} bytes long", MIN_TOKEN_SECRET_LENGTH));
+            }
+        }
+        match (&self.tokens.private_key_file, &self.tokens.public_key_file) {
+            (Some(_), None) | (None, Some(_)) => problems.push(String::from(
+                "tokens.private_key_file and tokens.public_key_file must be set together"
+            )),
+            (Some(private), Some(public)) => {
+                for (key, path) in [("tokens.private_key_file", private), ("tokens.public_key_file", public)] {
+                    if !Path::new(path).is_file() {
+                        problems.push(format!("{} names {:?}, which is not a file", key, path));
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+        if let Err(err) = EnvFilter::try_new(&self.tracing.filter) {
+            problems.push(format!("tracing.filter is invalid: {}", err));
+        }
+        if let Some(endpoint) = &self.tracing.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                problems.push(String::from("tracing.otlp_endpoint must start with http:// or https://"));
+            }
+        }
+        if !BCRYPT_COST_RANGE.contains(&self.auth.bcrypt_cost) {
+            problems.push(format!(
+                "auth.bcrypt_cost must be between {} and {}",
+                BCRYPT_COST_RANGE.start(), BCRYPT_COST_RANGE.end()
+            ));
+        }
+        let policies = self.rate_limit.default.iter()
+            .map(|policy| (String::from("default"), policy))
+            .chain(self.rate_limit.routes.iter().map(|route| (route.path.clone(), &route.policy)));
+        for (name, policy) in policies {
+            if policy.limit == 0 || policy.window_seconds == 0 {
+                problems.push(format!("rate_limit policy {} needs a limit and window_seconds of at least 1", name));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Message(problems.join("; ")))
+        }
+    }
+
+    /// Whether the default environment is loaded, the only one allowed to go
+    /// without a session key.
+    pub fn is_development(&self) -> bool {
+        self.environment == DEFAULT_ENVIRONMENT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use config::{Environment, File, FileFormat};
+    use crate::rate_limit::StoreKind;
+    use super::{DatabaseSettings, Settings};
+
+    fn variables(pairs: &[(&str, &str)]) -> Environment {
+        let source: HashMap<String, String> = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Environment::with_prefix("APP")
+            .separator("__")
+            .list_separator(",")
+            .with_list_parse_key("cors.allowed_origins")
+            .try_parsing(true)
+            .source(Some(source))
+    }
+
+    #[test]
+    fn variables_override_the_file() {
+        let file = File::from_str(
+            "[server]\nport = 9000\n[database]\nurl = \"postgres://file\"\npool_size = 4\n",
+            FileFormat::Toml,
+        );
+        let settings = Settings::build_from("development", file, variables(&[
+            ("APP__DATABASE__POOL_SIZE", "2"),
+            ("APP__CORS__ALLOWED_ORIGINS", "https://a.example,https://b.example"),
+        ]))
+        .unwrap();
+
+        assert_eq!(settings.server.port, 9000);
+        assert_eq!(settings.server.host, "127.0.0.1");
+        assert_eq!(settings.database.url, "postgres://file");
+        assert_eq!(settings.database.pool_size, 2);
+        assert_eq!(settings.cors.allowed_origins, ["https://a.example", "https://b.example"]);
+    }
+
+    #[test]
+    fn rejects_invalid_values_by_key() {
+        let file = File::from_str("[database]\nurl = \"postgres://file\"\n", FileFormat::Toml);
+        let err = Settings::build_from("development", file, variables(&[
+            ("APP__DATABASE__POOL_SIZE", "0"),
+            ("APP__AUTH__BCRYPT_COST", "99"),
+        ]))
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.contains("database.pool_size"), "{}", err);
+        assert!(err.contains("auth.bcrypt_cost"), "{}", err);
+    }
+
+    #[test]
+    fn pools_share_pool_size() {
+        for pool_size in [2, 3, 10] {
+            let database = DatabaseSettings { pool_size, ..DatabaseSettings::default() };
+            assert_eq!(database.blocking_pool_size() + database.async_pool_size(), pool_size);
+            assert!(database.blocking_pool_size() >= 1);
+        }
+    }
+
+    #[test]
+    fn reads_switches_and_rate_limits() {
+        let file = File::from_str(
+            "[database]\nurl = \"postgres://file\"\n\
+             [[rate_limit.routes]]\npath = \"/login\"\nalgorithm = \"sliding_window\"\nkey = \"ip\"\nlimit = 20\nwindow_seconds = 60\n",
+            FileFormat::Toml,
+        );
+        let settings = Settings::build_from("development", file, variables(&[
+            ("APP__AUTH__REQUIRE_EMAIL_VERIFICATION", "0"),
+            ("APP__RATE_LIMIT__STORE", "postgres"),
+        ]))
+        .unwrap();
+
+        assert!(!settings.auth.require_email_verification);
+        assert!(!settings.auth.require_admin_2fa);
+        assert_eq!(settings.rate_limit.store, StoreKind::Postgres);
+        assert_eq!(settings.rate_limit.routes[0].path, "/login");
+        assert_eq!(settings.rate_limit.routes[0].policy.limit, 20);
+    }
+
+    #[test]
+    fn requires_a_session_key_outside_development() {
+        let file = || File::from_str("[database]\nurl = \"postgres://file\"\n", FileFormat::Toml);
+
+        let development = Settings::build_from("development", file(), variables(&[])).unwrap();
+        assert!(development.session.key.is_none());
+        let err = Settings::build_from("production", file(), variables(&[]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("session.key"), "{}", err);
+        let err = Settings::build_from("production", file(), variables(&[("APP__SESSION__KEY", "short")]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("at least 64 bytes"), "{}", err);
+        let key = "k".repeat(64);
+        let production = Settings::build_from("production", file(), variables(&[("APP__SESSION__KEY", &key)])).unwrap();
+        assert_eq!(production.environment, "production");
+        assert_eq!(production.session.key.as_deref(), Some(key.as_str()));
+    }
+
+    #[test]
+    fn validates_token_keys() {
+        let file = || File::from_str("[database]\nurl = \"postgres://file\"\n", FileFormat::Toml);
+
+        let settings = Settings::build_from("development", file(), variables(&[
+            ("APP__TOKENS__SECRET", &"s".repeat(32)),
+        ]))
+        .unwrap();
+        assert_eq!(settings.tokens.secret.as_deref().map(str::len), Some(32));
+        let err = Settings::build_from("development", file(), variables(&[
+            ("APP__TOKENS__SECRET", "short"),
+            ("APP__TOKENS__PRIVATE_KEY_FILE", "missing.pem"),
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("tokens.secret"), "{}", err);
+        assert!(err.contains("must be set together"), "{}", err);
+        let err = Settings::build_from("development", file(), variables(&[
+            ("APP__TOKENS__PRIVATE_KEY_FILE", "missing.pem"),
+            ("APP__TOKENS__PUBLIC_KEY_FILE", "missing.pub.pem"),
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("tokens.private_key_file names \"missing.pem\""), "{}", err);
+    }
+
+    #[test]
+    fn shipped_files_are_valid() {
+        for environment in ["development", "production"] {
+            let file = File::with_name(&format!("{}/{}", super::CONFIG_DIR, environment));
+            let settings = Settings::build_from(environment, file, variables(&[
+                ("APP__DATABASE__URL", "postgres://env"),
+                ("APP__SESSION__KEY", &"k".repeat(64)),
+            ]))
+                .unwrap_or_else(|err| panic!("{}: {}", environment, err));
+            assert!(!settings.rate_limit.routes.is_empty(), "{}", environment);
+        }
+    }
+}
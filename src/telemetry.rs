@@ -0,0 +1,262 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+use actix_web::{
+    HttpMessage,
+    error::InternalError,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    Error,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::SdkTracerProvider,
+    Resource,
+};
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
+use crate::settings::{LogFormat, TracingSettings};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest `X-Request-Id` taken from a client, longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// Route of requests that matched no route, as in the request metrics.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+
+/// Keeps the tracer provider alive so the batched spans can be flushed
+/// when the server stops.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+/// Installs the global subscriber: log lines in the configured format, and
+/// spans turned into OpenTelemetry spans, exported over OTLP/HTTP when
+/// `tracing.otlp_endpoint` is set. Also makes W3C `traceparent` the format
+/// trace contexts are read from and written to.
+pub fn init(settings: &TracingSettings) -> Result<Telemetry, anyhow::Error> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let mut provider = SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(settings.service_name.clone()).build());
+    if let Some(endpoint) = &settings.otlp_endpoint {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        provider = provider.with_batch_exporter(exporter);
+    }
+    let provider = provider.build();
+
+    let logs = match settings.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_new(&settings.filter)?)
+        .with(logs)
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(settings.service_name.clone())))
+        .try_init()?;
+
+    Ok(Telemetry { provider })
+}
+
+impl Telemetry {
+    /// Exports the spans still queued.
+    pub fn shutdown(self) {
+        if let Err(err) = self.provider.shutdown() {
+            eprintln!("Failed to flush traces: {}", err);
+        }
+    }
+}
+
+/// Id of the request being served, put in the request extensions by
+/// [`RequestTracing`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The client's `X-Request-Id` if it is a sane one, a new UUID otherwise.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let presented = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+            .filter(|id| id.bytes().all(|byte| byte.is_ascii_graphic()));
+        match presented {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Runs every request in a `request` span tagged with its method, route and
+/// request id, so the spans of its handler and database calls nest under it.
+/// The span continues the trace of an incoming `traceparent` header. The
+/// response carries `X-Request-Id` and the `traceparent` of the span, errors
+/// of inner middleware included. Register it last so it wraps everything.
+#[derive(Clone, Copy, Default)]
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let request_id = RequestId::from_headers(req.headers());
+        let route = req.match_pattern().unwrap_or_else(|| String::from(UNMATCHED_ROUTE));
+        let span = info_span!(
+            "request",
+            otel.name = %format!("{} {}", req.method(), route),
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            method = %req.method(),
+            route = %route,
+            request_id = %request_id.0,
+            status = field::Empty,
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        // Only fails once the span has started, which it has not.
+        let _ = span.set_parent(parent);
+        req.extensions_mut().insert(request_id.clone());
+        let started = Instant::now();
+
+        Box::pin(async move {
+            let res = service.call(req).instrument(span.clone()).await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            span.record("status", status.as_u16());
+            if status.is_server_error() {
+                span.record("otel.status_code", "ERROR");
+            }
+            span.in_scope(|| {
+                info!(elapsed_ms = started.elapsed().as_millis() as u64, "finished request");
+            });
+
+            match res {
+                Ok(mut res) => {
+                    tag_response(res.headers_mut(), &request_id, &span);
+                    Ok(res)
+                }
+                // Errors of inner middleware become responses further out,
+                // so they are answered here to carry the headers too.
+                Err(err) => {
+                    let mut response = err.error_response();
+                    tag_response(response.headers_mut(), &request_id, &span);
+                    Err(InternalError::from_response(err, response).into())
+                }
+            }
+        })
+    }
+}
+
+fn tag_response(headers: &mut HeaderMap, request_id: &RequestId, span: &Span) {
+    if let Ok(value) = HeaderValue::try_from(request_id.0.as_str()) {
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut HeaderInjector(headers))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpMessage, HttpRequest, HttpResponse};
+    use super::{RequestId, RequestTracing, REQUEST_ID_HEADER};
+
+    async fn echo_request_id(req: HttpRequest) -> HttpResponse {
+        let id = req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+        HttpResponse::Ok().body(id)
+    }
+
+    #[actix_web::test]
+    async fn keeps_sane_request_ids_and_replaces_the_rest() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestTracing)
+                .route("/", web::get().to(echo_request_id)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "abc-123")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+        assert_eq!(test::read_body(res).await, "abc-123");
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "has spaces")).to_request();
+        let res = test::call_service(&app, req).await;
+        let generated = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        assert_ne!(generated, "has spaces");
+        assert_eq!(generated.len(), 36);
+        assert_eq!(test::read_body(res).await, generated);
+    }
+}
@@ -0,0 +1,154 @@
+//! Shared setup of the unit tests: an `AppState` over the in-memory
+//! repositories, accounts in it, and an app holding just the services under
+//! test behind the identity and session middleware.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use actix_http::Request;
+use actix_identity::IdentityMiddleware;
+use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, Key},
+    dev::{Service, ServiceResponse},
+    http::{header::HeaderMap, StatusCode},
+    test, web, App, Error,
+};
+use crate::AppState;
+use crate::auth::Credentials;
+use crate::mailer::Mailer;
+use crate::models::User;
+use crate::repository::{MemoryUserRepository, MemoryVideoRepository, UserRepository};
+use crate::settings::Settings;
+use crate::tokens::TokenKeys;
+
+pub const PASSWORD: &str = "correct horse battery staple";
+const TOKEN_SECRET: &[u8] = b"unit-tests-secret-of-32-bytes!!!";
+
+/// The state with the repositories and mail behind it, so tests can set up
+/// and inspect what the handlers see. Token mode is on.
+pub struct TestState {
+    pub state: Arc<AppState>,
+    pub users: Arc<MemoryUserRepository>,
+    pub videos: Arc<MemoryVideoRepository>,
+    pub outbox: Outbox,
+}
+
+impl TestState {
+    pub fn new() -> Self {
+        TestState::with_settings(|_| {})
+    }
+
+    /// Starts from the settings of `AppState::builder`, email verification
+    /// off included.
+    pub fn with_settings(configure: impl FnOnce(&mut Settings)) -> Self {
+        let users = Arc::new(MemoryUserRepository::default());
+        let videos = Arc::new(MemoryVideoRepository::default());
+        let outbox = Outbox::default();
+        let state = AppState::builder()
+            .accounts(users.clone())
+            .videos(videos.clone())
+            .settings(configure)
+            .mailer(outbox.clone())
+            .tokens(TokenKeys::from_secret(TOKEN_SECRET))
+            .build();
+        TestState { state, users, videos, outbox }
+    }
+
+    /// Creates an account with `PASSWORD` and the named role.
+    pub async fn user(&self, email: &str, role: &str) -> User {
+        let creds = Credentials { email: email.to_string(), password: PASSWORD.to_string() };
+        let user = self.users.create(creds, 4).await.unwrap();
+        let role = self.users.find_role(role).await.unwrap().unwrap();
+        self.users.assign_role(user.id, role.id).await.unwrap();
+        user
+    }
+
+    /// An access token for the user.
+    pub fn token(&self, user_id: i32) -> String {
+        self.state.tokens.as_ref().unwrap().issue(user_id).unwrap()
+    }
+
+    /// An app serving what `configure` registers over this state.
+    pub async fn app(
+        &self,
+        configure: impl FnOnce(&mut web::ServiceConfig)
+    ) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+        test::init_service(
+            App::new()
+                .wrap(IdentityMiddleware::default())
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .app_data(web::Data::new(self.state.clone()))
+                .configure(configure),
+        )
+        .await
+    }
+}
+
+/// A response read to the end.
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub cookies: Vec<Cookie<'static>>,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+
+    /// The session cookie, to send along with the next request.
+    pub fn cookie(&self) -> Cookie<'static> {
+        self.cookies.first().expect("no cookie was set").clone()
+    }
+}
+
+/// Sends `req` from an address of its own, so per-IP counters never trip.
+pub async fn call<S, B>(app: &S, req: test::TestRequest) -> Reply
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(rand::random::<u32>())), 40000);
+    let resp = test::call_service(app, req.peer_addr(peer).to_request()).await;
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let cookies = resp.response().cookies().map(Cookie::into_owned).collect();
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    Reply { status, headers, cookies, body }
+}
+
+/// `req` carrying `token` as a bearer token.
+pub fn bearer(req: test::TestRequest, token: &str) -> test::TestRequest {
+    req.insert_header(("Authorization", format!("Bearer {}", token)))
+}
+
+/// Keeps the body of every mail so tests can pick the tokens out of them.
+#[derive(Clone, Default)]
+pub struct Outbox(Arc<Mutex<Vec<String>>>);
+
+impl Mailer for Outbox {
+    fn send(&self, _to: &str, _subject: &str, body: &str) -> Result<(), anyhow::Error> {
+        self.0.lock().unwrap().push(body.to_string());
+        Ok(())
+    }
+}
+
+impl Outbox {
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// The token in the last mail: the link's `token=` parameter, or the
+    /// last word of the first line.
+    pub fn last_token(&self) -> String {
+        let mails = self.0.lock().unwrap();
+        let line = mails.last().expect("no mail was sent").lines().next().unwrap();
+        let token = match line.split_once("token=") {
+            Some((_, rest)) => rest.split(' ').next(),
+            None => line.rsplit(' ').next(),
+        };
+        token.unwrap().to_string()
+    }
+}
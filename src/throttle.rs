@@ -0,0 +1,97 @@
+use actix_web::HttpRequest;
+use crate::errors::AppError;
+
+
+/// What a throttle counter in `login_throttles` is keyed on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottleScope {
+    /// Failed logins for one normalized email, whether or not the account exists
+    Account,
+    /// Failed logins from one client address
+    Ip,
+    /// Sign up attempts from one client address
+    Signup,
+    /// Wrong second factor codes for one account, keyed on the user id
+    SecondFactor,
+}
+
+impl ThrottleScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThrottleScope::Account => "account",
+            ThrottleScope::Ip => "ip",
+            ThrottleScope::Signup => "signup",
+            ThrottleScope::SecondFactor => "second_factor",
+        }
+    }
+
+    pub fn policy(&self) -> ThrottlePolicy {
+        match self {
+            ThrottleScope::Account => ThrottlePolicy {
+                free_attempts: 3,
+                lockout_after: 10,
+                lockout_seconds: 15 * 60,
+                window_seconds: 15 * 60,
+            },
+            ThrottleScope::Ip => ThrottlePolicy {
+                free_attempts: 10,
+                lockout_after: 50,
+                lockout_seconds: 15 * 60,
+                window_seconds: 15 * 60,
+            },
+            ThrottleScope::Signup => ThrottlePolicy {
+                free_attempts: 5,
+                lockout_after: 10,
+                lockout_seconds: 60 * 60,
+                window_seconds: 60 * 60,
+            },
+            ThrottleScope::SecondFactor => ThrottlePolicy {
+                free_attempts: 3,
+                lockout_after: 10,
+                lockout_seconds: 15 * 60,
+                window_seconds: 15 * 60,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottlePolicy {
+    /// Attempts allowed before any delay kicks in
+    pub free_attempts: i32,
+    /// Attempts after which the subject is locked out
+    pub lockout_after: i32,
+    pub lockout_seconds: i32,
+    /// Counters with no attempt for this long start over
+    pub window_seconds: i32,
+}
+
+impl ThrottlePolicy {
+    /// Seconds to wait after `attempts` recorded attempts: nothing for the
+    /// free attempts, then doubling from one second, then the full lockout.
+    pub fn delay_seconds(&self, attempts: i32) -> i32 {
+        if attempts >= self.lockout_after {
+            self.lockout_seconds
+        } else if attempts > self.free_attempts {
+            let exponent = (attempts - self.free_attempts - 1).min(30);
+            (1i32 << exponent).min(self.lockout_seconds)
+        } else {
+            0
+        }
+    }
+}
+
+/// Address of the connected peer. Forwarding headers are ignored on purpose,
+/// since clients could rotate them to dodge the per-IP counter.
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+pub fn too_many_requests(retry_after: i64) -> AppError {
+    AppError::TooManyRequests {
+        detail: String::from("Too many attempts, please try again later"),
+        retry_after,
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs;
+use std::sync::Arc;
+use actix_web::{
+    HttpRequest, HttpResponse, web, post,
+    http::header::AUTHORIZATION,
+};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::AppState;
+use crate::auth::{Credentials, LoginOutcome, check_login};
+use crate::db_actions::normalize_email;
+use crate::errors::AppError;
+use crate::metrics::metrics;
+use crate::models::RefreshOutcome;
+use crate::settings::TokenSettings;
+use crate::throttle::{client_ip, too_many_requests};
+use crate::two_factor::{SecondFactorOutcome, check_second_factor};
+use crate::ultils::utils::{generate_key, hash_token};
+
+/// How long an access token is accepted.
+const ACCESS_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+/// How long an unused refresh token stays valid.
+const REFRESH_TOKEN_TTL_DAYS: i32 = 30;
+
+
+/// Keys for signing and checking access tokens, from the `tokens` settings.
+pub struct TokenKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl TokenKeys {
+    /// `None` when token mode is off. The settings are validated already,
+    /// so only reading and parsing the key files can fail.
+    pub fn from_settings(settings: &TokenSettings) -> Result<Option<Self>, anyhow::Error> {
+        if let (Some(private_path), Some(public_path)) = (&settings.private_key_file, &settings.public_key_file) {
+            return Ok(Some(TokenKeys {
+                algorithm: Algorithm::EdDSA,
+                encoding: EncodingKey::from_ed_pem(&fs::read(private_path)?)?,
+                decoding: DecodingKey::from_ed_pem(&fs::read(public_path)?)?,
+            }));
+        }
+        Ok(settings.secret.as_ref().map(|secret| TokenKeys::from_secret(secret.as_bytes())))
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Self {
+        TokenKeys {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    pub fn issue(&self, user_id: i32) -> Result<String, jsonwebtoken::errors::Error> {
+        let iat = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            iat,
+            exp: iat + ACCESS_TOKEN_TTL_SECONDS,
+        };
+        jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.encoding)
+    }
+
+    /// The user id an access token was issued to, or `None` when the token is
+    /// malformed, forged or expired.
+    pub fn verify(&self, access_token: &str) -> Option<i32> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        jsonwebtoken::decode::<Claims>(access_token, &self.decoding, &validation)
+            .ok()
+            .and_then(|data| data.claims.sub.parse().ok())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// The token of an `Authorization: Bearer` header, if the request has one.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+pub enum TokenRequest {
+    /// Trades an email and password for a first token pair. Accounts with
+    /// two-factor on also need `otp`, a TOTP or recovery code.
+    Password {
+        email: String,
+        password: String,
+        #[serde(default)]
+        otp: Option<String>,
+    },
+    /// Trades a refresh token for a new pair, using it up
+    RefreshToken { refresh_token: String },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct RevokeToken {
+    pub refresh_token: String,
+}
+
+fn token_response(state: &AppState, user_id: i32, refresh_token: String) -> Result<HttpResponse, AppError> {
+    let keys = state.tokens.as_ref().ok_or_else(tokens_disabled)?;
+    let access_token = keys.issue(user_id).map_err(|err| AppError::Internal(err.into()))?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(TokenResponse {
+            access_token,
+            token_type: String::from("Bearer"),
+            expires_in: ACCESS_TOKEN_TTL_SECONDS,
+            refresh_token,
+        }))
+}
+
+fn tokens_disabled() -> AppError {
+    AppError::NotFound(String::from("Token authentication is not enabled"))
+}
+
+#[utoipa::path(
+    request_body = TokenRequest,
+    responses(
+        (
+            status = 200,
+            description = "Issues an access token and a refresh token",
+            body = TokenResponse
+        ),
+        (
+            status = 401,
+            description = "Wrong credentials or two-factor code, or a refresh token that is unknown, expired or reused",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Invalid refresh token")).problem())
+        ),
+        (
+            status = 403,
+            description = "Email address is not verified yet",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 404,
+            description = "Token authentication is not enabled",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 406,
+            description = "Email Provided is not valid",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 429,
+            description = "Too many failed logins for this account or address, see the Retry-After header",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/token")]
+pub async fn token(
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    body: web::Json<TokenRequest>
+)
+-> Result<HttpResponse, AppError> {
+    if state.tokens.is_none() {
+        return Err(tokens_disabled());
+    }
+    let refresh_token = generate_key();
+    let refresh_hash = hash_token(&refresh_token);
+
+    match body.into_inner() {
+        TokenRequest::Password { email, password, otp } => {
+            let email = normalize_email(&email)?;
+            let require_verified = state.settings.auth.require_email_verification;
+            let ip = client_ip(&req);
+            let credentials = Credentials { email, password };
+            let outcome = check_login(state.users.as_ref(), credentials, &ip, state.settings.auth.bcrypt_cost).await?;
+            let outcome = match outcome {
+                LoginOutcome::SecondFactorRequired(user) => match otp {
+                    Some(code) => match check_second_factor(&state, user.id, &code).await? {
+                        SecondFactorOutcome::Accepted => LoginOutcome::Success(user),
+                        SecondFactorOutcome::Rejected => LoginOutcome::SecondFactorRequired(user),
+                        SecondFactorOutcome::Throttled(retry_after) => LoginOutcome::Throttled(retry_after),
+                    },
+                    None => LoginOutcome::SecondFactorRequired(user),
+                },
+                outcome => outcome,
+            };
+            if let LoginOutcome::Success(user) = &outcome {
+                if !require_verified || user.email_verified_at.is_some() {
+                    state.sessions.create_refresh_token(user.id, &generate_key(), &refresh_hash, REFRESH_TOKEN_TTL_DAYS).await?;
+                }
+            }
+
+            match outcome {
+                LoginOutcome::Success(user) if require_verified && user.email_verified_at.is_none() => {
+                    Err(AppError::Forbidden(String::from("Please verify your email before logging in")))
+                }
+                LoginOutcome::Success(user) => {
+                    metrics().logins.inc();
+                    token_response(&state, user.id, refresh_token)
+                }
+                LoginOutcome::SecondFactorRequired(_) => {
+                    Err(AppError::Unauthorized(String::from("A valid two-factor code is required in otp")))
+                }
+                LoginOutcome::Failed => Err(AppError::Unauthorized(String::from("Invalid email or password"))),
+                LoginOutcome::Throttled(retry_after) => Err(too_many_requests(retry_after)),
+            }
+        }
+        TokenRequest::RefreshToken { refresh_token: presented } => {
+            let outcome = state.sessions.rotate_refresh_token(&hash_token(&presented), &refresh_hash, REFRESH_TOKEN_TTL_DAYS).await?;
+
+            match outcome {
+                RefreshOutcome::Rotated(user_id) => token_response(&state, user_id, refresh_token),
+                RefreshOutcome::Reused | RefreshOutcome::Invalid => {
+                    Err(AppError::Unauthorized(String::from("Invalid refresh token")))
+                }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    request_body = RevokeToken,
+    responses(
+        (
+            status = 204,
+            description = "Revokes a refresh token and every token rotated from the same login",
+        ),
+        (
+            status = 404,
+            description = "Token authentication is not enabled",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/token/revoke")]
+pub async fn revoke_token(
+    state: web::Data<Arc<AppState>>,
+    body: web::Json<RevokeToken>
+)
+-> Result<HttpResponse, AppError> {
+    if state.tokens.is_none() {
+        return Err(tokens_disabled());
+    }
+    let token_hash = hash_token(&body.into_inner().refresh_token);
+    state.sessions.revoke_refresh_token(&token_hash).await?;
+
+    // Unknown tokens are not reported, so the endpoint cannot probe for them.
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,458 @@
+use std::sync::Arc;
+use actix_identity::Identity;
+use actix_session::{Session, SessionInsertError};
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, web, post, delete,
+};
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+use utoipa::ToSchema;
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::AuthenticatedUser;
+use crate::metrics::metrics;
+use crate::session_store::remember_device;
+use crate::throttle::{ThrottleScope, too_many_requests};
+use crate::ultils::utils::hash_token;
+
+/// Shown by authenticator apps next to the account name.
+const TOTP_ISSUER: &str = "actix-diesel";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECONDS: i64 = 30;
+/// Steps accepted on either side of the current one, to allow for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+/// 160 bits, the secret size RFC 4226 recommends.
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Characters per half of a recovery code, which reads like `abcde-12345`.
+const RECOVERY_CODE_HALF_LENGTH: usize = 5;
+/// How long a login may wait for its second factor.
+const PENDING_LOGIN_TTL_SECONDS: i64 = 5 * 60;
+const SESSION_PENDING_LOGIN: &str = "pending_2fa";
+
+
+/// A login that passed the password check and waits for `POST /login/2fa`.
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingLogin {
+    user_id: i32,
+    expires_at: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct TwoFactorCode {
+    /// A code from the authenticator app, or an unused recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct TotpEnrollment {
+    /// Base32 secret, for apps that cannot scan the URI
+    pub secret: String,
+    /// `otpauth://` URI to render as a QR code
+    pub otpauth_uri: String,
+}
+
+/// Returned once when two-factor is confirmed, only hashes are stored.
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+pub(crate) enum SecondFactorOutcome {
+    Accepted,
+    Rejected,
+    Throttled(i64),
+}
+
+/// Parks a login that still needs its second factor in the session. The user
+/// is not logged in until `POST /login/2fa` accepts a code.
+pub fn begin_second_factor(
+    session: &Session,
+    user_id: i32
+)
+-> Result<(), SessionInsertError> {
+    session.insert(SESSION_PENDING_LOGIN, PendingLogin {
+        user_id,
+        expires_at: Utc::now().timestamp() + PENDING_LOGIN_TTL_SECONDS,
+    })
+}
+
+fn totp(secret: Vec<u8>, account: &str) -> Result<TOTP, anyhow::Error> {
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP_SECONDS as u64,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account.to_string(),
+    )?)
+}
+
+/// The TOTP of a secret read back from the repository.
+fn stored_totp(secret: String) -> Result<TOTP, anyhow::Error> {
+    totp(Secret::Encoded(secret).to_bytes()?, "")
+}
+
+/// The time step `code` belongs to, if it is valid around the current time.
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let current = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .find(|step| totp.check(code, (step * TOTP_STEP_SECONDS) as u64))
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_HALF_LENGTH * 2)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..RECOVERY_CODE_HALF_LENGTH], &code[RECOVERY_CODE_HALF_LENGTH..])
+        })
+        .collect()
+}
+
+/// Accepts an authenticator code that was not used before, or burns a
+/// recovery code.
+async fn verify_code(
+    state: &AppState,
+    user_id: i32,
+    code: &str
+)
+-> Result<bool, anyhow::Error> {
+    let secret = match state.two_factor.secret(user_id).await? {
+        Some(secret) if secret.confirmed_at.is_some() => secret,
+        _ => return Ok(false),
+    };
+    let code = code.trim();
+    if code.len() == TOTP_DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+        let totp = stored_totp(secret.secret)?;
+        return match matching_step(&totp, code) {
+            Some(step) => state.two_factor.use_step(user_id, step).await,
+            None => Ok(false),
+        };
+    }
+    state.two_factor.use_recovery_code(user_id, &hash_token(&normalize_recovery_code(code))).await
+}
+
+/// Checks a second factor code under the per-account throttle, so the six
+/// digits cannot be brute forced.
+pub(crate) async fn check_second_factor(
+    state: &AppState,
+    user_id: i32,
+    code: &str
+)
+-> Result<SecondFactorOutcome, anyhow::Error> {
+    let subject = user_id.to_string();
+    if let Some(retry_after) = state.users.throttle_remaining(ThrottleScope::SecondFactor, &subject).await? {
+        return Ok(SecondFactorOutcome::Throttled(retry_after));
+    }
+    if verify_code(state, user_id, code).await? {
+        state.users.clear_throttle(ThrottleScope::SecondFactor, &subject).await?;
+        Ok(SecondFactorOutcome::Accepted)
+    } else {
+        state.users.record_attempt(ThrottleScope::SecondFactor, &subject).await?;
+        Ok(SecondFactorOutcome::Rejected)
+    }
+}
+
+#[utoipa::path(
+    request_body = TwoFactorCode,
+    responses(
+        (
+            status = 200,
+            description = "Completes a login that needed a second factor",
+        ),
+        (
+            status = 401,
+            description = "No login is waiting for a second factor, or the code is wrong",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Invalid two-factor code")).problem())
+        ),
+        (
+            status = 429,
+            description = "Too many wrong codes for this account, see the Retry-After header",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[post("/login/2fa")]
+pub async fn login_second_factor(
+    body: web::Json<TwoFactorCode>,
+    state: web::Data<Arc<AppState>>,
+    req: HttpRequest,
+    session: Session
+)
+-> Result<HttpResponse, AppError> {
+    let pending = session
+        .get::<PendingLogin>(SESSION_PENDING_LOGIN)?
+        .filter(|pending| pending.expires_at > Utc::now().timestamp());
+    let Some(pending) = pending else {
+        return Err(AppError::Unauthorized(String::from("No login is waiting for a second factor")));
+    };
+    let code = body.into_inner().code;
+
+    let outcome = match state.users.get(pending.user_id).await? {
+        Some(user) => {
+            let outcome = check_second_factor(&state, user.id, &code).await?;
+            Some((user, outcome))
+        }
+        None => None,
+    };
+
+    match outcome {
+        None => {
+            session.remove(SESSION_PENDING_LOGIN);
+            Err(AppError::Unauthorized(String::from("No login is waiting for a second factor")))
+        }
+        Some((user, SecondFactorOutcome::Accepted)) => {
+            session.remove(SESSION_PENDING_LOGIN);
+            Identity::login(&req.extensions(), user.email)?;
+            remember_device(&session, &req, user.id)?;
+            metrics().logins.inc();
+            Ok(HttpResponse::Ok().body("Back In Action!"))
+        }
+        Some((_, SecondFactorOutcome::Rejected)) => {
+            Err(AppError::Unauthorized(String::from("Invalid two-factor code")))
+        }
+        Some((_, SecondFactorOutcome::Throttled(retry_after))) => Err(too_many_requests(retry_after)),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Starts enrolling an authenticator app, confirm it with a code to turn two-factor on",
+            body = TotpEnrollment
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 409,
+            description = "Two-factor authentication is already enabled",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Conflict(String::from("Two-factor authentication is already enabled")).problem())
+        ),
+    )
+)]
+#[post("/me/2fa")]
+pub async fn enroll_two_factor(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let totp = totp(rand::random::<[u8; SECRET_BYTES]>().to_vec(), &user.user.email)?;
+    let secret = totp.get_secret_base32();
+
+    let started = state.two_factor.start_enrollment(user_id, &secret).await?;
+
+    if !started {
+        return Err(AppError::Conflict(String::from("Two-factor authentication is already enabled")));
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(TotpEnrollment {
+            secret,
+            otpauth_uri: totp.get_url(),
+        }))
+}
+
+#[utoipa::path(
+    request_body = TwoFactorCode,
+    responses(
+        (
+            status = 200,
+            description = "Turns two-factor on and returns recovery codes, which are only shown in this response",
+            body = RecoveryCodes
+        ),
+        (
+            status = 400,
+            description = "No enrollment is pending, or the code is wrong",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Invalid two-factor code")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[post("/me/2fa/confirm")]
+pub async fn confirm_two_factor(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    body: web::Json<TwoFactorCode>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let code = body.into_inner().code;
+
+    let invalid = || AppError::Validation(String::from("Invalid two-factor code"));
+    let secret = match state.two_factor.secret(user_id).await? {
+        Some(secret) if secret.confirmed_at.is_none() => secret,
+        _ => return Err(invalid()),
+    };
+    let totp = stored_totp(secret.secret)?;
+    let Some(step) = matching_step(&totp, code.trim()) else {
+        return Err(invalid());
+    };
+    let codes = generate_recovery_codes();
+    let hashes = codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect();
+    if !state.two_factor.confirm(user_id, step, hashes).await? {
+        return Err(invalid());
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(RecoveryCodes { codes }))
+}
+
+#[utoipa::path(
+    request_body = TwoFactorCode,
+    responses(
+        (
+            status = 204,
+            description = "Turns two-factor off and deletes the recovery codes",
+        ),
+        (
+            status = 400,
+            description = "The code is wrong",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Invalid two-factor code")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 429,
+            description = "Too many wrong codes for this account, see the Retry-After header",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+    )
+)]
+#[delete("/me/2fa")]
+pub async fn disable_second_factor(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    body: web::Json<TwoFactorCode>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let code = body.into_inner().code;
+
+    let outcome = check_second_factor(&state, user_id, &code).await?;
+    if let SecondFactorOutcome::Accepted = outcome {
+        state.two_factor.disable(user_id).await?;
+    }
+
+    match outcome {
+        SecondFactorOutcome::Accepted => Ok(HttpResponse::NoContent().finish()),
+        SecondFactorOutcome::Rejected => Err(AppError::Validation(String::from("Invalid two-factor code"))),
+        SecondFactorOutcome::Throttled(retry_after) => Err(too_many_requests(retry_after)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use chrono::Utc;
+    use totp_rs::TOTP;
+    use crate::auth::login;
+    use crate::models::DEFAULT_ROLE;
+    use crate::test_util::{call, TestState, PASSWORD};
+    use crate::ultils::utils::hash_token;
+    use super::*;
+
+    const EMAIL: &str = "2fa@example.com";
+
+    /// Creates an account with two-factor on, returning its TOTP and
+    /// recovery codes.
+    async fn insert_user(test: &TestState) -> (TOTP, Vec<String>) {
+        let id = test.user(EMAIL, DEFAULT_ROLE).await.id;
+
+        let totp = totp(rand::random::<[u8; SECRET_BYTES]>().to_vec(), EMAIL).unwrap();
+        let codes = generate_recovery_codes();
+        let hashes = codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect();
+        let two_factor = &test.state.two_factor;
+        assert!(two_factor.start_enrollment(id, &totp.get_secret_base32()).await.unwrap());
+        // Confirmed two steps back, so the current code is still unused.
+        let step = Utc::now().timestamp() / TOTP_STEP_SECONDS - 2;
+        assert!(two_factor.confirm(id, step, hashes).await.unwrap());
+        (totp, codes)
+    }
+
+    #[actix_web::test]
+    async fn login_waits_for_a_second_factor_and_rejects_replayed_codes() {
+        let test = TestState::new();
+        let (totp, codes) = insert_user(&test).await;
+        let app = test.app(|cfg| {
+            cfg.service(login).service(login_second_factor);
+        })
+        .await;
+
+        // Each round logs in with the password, then sends `code` along with
+        // the session cookie of the pending login.
+        let mut second_factor = Vec::new();
+        let current_code = totp.generate(Utc::now().timestamp() as u64);
+        for code in ["000000", &current_code, &current_code, &codes[0], &codes[0].to_uppercase()] {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_json(serde_json::json!({ "email": EMAIL, "password": PASSWORD }));
+            let reply = call(&app, req).await;
+            assert_eq!(reply.status, StatusCode::ACCEPTED);
+
+            let req = test::TestRequest::post()
+                .uri("/login/2fa")
+                .cookie(reply.cookie())
+                .set_json(serde_json::json!({ "code": code }));
+            second_factor.push(call(&app, req).await.status);
+        }
+        let req = test::TestRequest::post()
+            .uri("/login/2fa")
+            .set_json(serde_json::json!({ "code": current_code }));
+        let without_login = call(&app, req).await.status;
+
+        assert_eq!(second_factor, [
+            StatusCode::UNAUTHORIZED,
+            StatusCode::OK,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::OK,
+            StatusCode::UNAUTHORIZED,
+        ]);
+        assert_eq!(without_login, StatusCode::UNAUTHORIZED);
+    }
+}
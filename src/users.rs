@@ -0,0 +1,190 @@
+use std::sync::Arc;
+use actix_web::{HttpResponse, web, get, post};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::RequirePermission;
+use crate::models::UserWithVideos;
+use crate::throttle::ThrottleScope;
+
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Fetches a specific user",
+            body = UserWithVideos
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the users:read permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission users:read")).problem())
+        ),
+        (
+            status = 404,
+            description = "User Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Not found")).problem())
+        ),
+    )
+)]
+#[get("/user/{id}", wrap = "RequirePermission(\"users:read\")")]
+pub async fn user_data(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    let user = state.users.get(user_id).await?
+        .ok_or_else(|| AppError::NotFound(String::from("User not found")))?;
+    let info = UserWithVideos {
+        user,
+        liked_videos: state.videos.liked(user_id).await?,
+        watched_videos: state.videos.history(user_id).await?,
+    };
+
+    Ok(HttpResponse::Ok().json(info))
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 204,
+            description = "Clears the failed login lockout of a user",
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the users:write permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission users:write")).problem())
+        ),
+        (
+            status = 404,
+            description = "User Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("User Not Found")).problem())
+        ),
+    )
+)]
+#[post("/user/{id}/unlock", wrap = "RequirePermission(\"users:write\")")]
+pub async fn unlock_user(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    let user = state.users.get(user_id).await?
+        .ok_or_else(|| AppError::NotFound(String::from("User not found")))?;
+    state.users.clear_throttle(ThrottleScope::Account, &user.email).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use crate::models::{ADMIN_ROLE, DEFAULT_ROLE, VideoForm, VideoList, WatchProgress};
+    use crate::repository::{UserRepository, VideoRepository};
+    use crate::test_util::{bearer, call, Reply, TestState};
+    use crate::throttle::ThrottleScope;
+    use super::{unlock_user, user_data};
+
+    async fn send(test: &TestState, token: Option<&str>, req: test::TestRequest) -> Reply {
+        let app = test.app(|cfg| {
+            cfg.service(user_data).service(unlock_user);
+        })
+        .await;
+        let req = match token {
+            Some(token) => bearer(req, token),
+            None => req,
+        };
+        call(&app, req).await
+    }
+
+    fn user_data_of(id: i32) -> test::TestRequest {
+        test::TestRequest::get().uri(&format!("/user/{}", id))
+    }
+
+    #[actix_web::test]
+    async fn user_data_needs_users_read() {
+        let test = TestState::new();
+        let user = test.user("plain@example.com", DEFAULT_ROLE).await;
+
+        let reply = send(&test, None, user_data_of(user.id)).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        let reply = send(&test, Some(&test.token(user.id)), user_data_of(user.id)).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn user_data_lists_liked_and_started_videos() {
+        let test = TestState::new();
+        let admin = test.user("admin@example.com", ADMIN_ROLE).await;
+        let viewer = test.user("viewer@example.com", DEFAULT_ROLE).await;
+        let form = |title: &str| VideoForm {
+            title: title.to_string(),
+            description: String::new(),
+            duration: 600,
+            release_year: None,
+            maturity_rating: None,
+        };
+        let liked = test.videos.create(form("Liked")).await.unwrap();
+        let started = test.videos.create(form("Started")).await.unwrap();
+        test.videos.add_to_list(viewer.id, liked.id, VideoList::Liked).await.unwrap();
+        test.videos.save_progress(viewer.id, started.id, WatchProgress { position: 60, duration: 600 }).await.unwrap();
+
+        let reply = send(&test, Some(&test.token(admin.id)), user_data_of(viewer.id)).await;
+
+        assert_eq!(reply.status, StatusCode::OK);
+        let body = reply.json();
+        assert_eq!(body["email"], "viewer@example.com");
+        assert_eq!(body["liked_videos"][0]["title"], "Liked");
+        assert_eq!(body["watched_videos"][0]["title"], "Started");
+    }
+
+    #[actix_web::test]
+    async fn user_data_answers_not_found_for_missing_users() {
+        let test = TestState::new();
+        let admin = test.user("admin@example.com", ADMIN_ROLE).await;
+
+        let reply = send(&test, Some(&test.token(admin.id)), user_data_of(999)).await;
+
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+        assert_eq!(reply.json()["detail"], "User not found");
+    }
+
+    #[actix_web::test]
+    async fn unlock_user_clears_the_account_lockout() {
+        let test = TestState::new();
+        let admin = test.user("admin@example.com", ADMIN_ROLE).await;
+        let locked = test.user("locked@example.com", DEFAULT_ROLE).await;
+        for _ in 0..=ThrottleScope::Account.policy().free_attempts {
+            test.users.record_attempt(ThrottleScope::Account, "locked@example.com").await.unwrap();
+        }
+        assert!(test.users.throttle_remaining(ThrottleScope::Account, "locked@example.com").await.unwrap().is_some());
+
+        let unlock = test::TestRequest::post().uri(&format!("/user/{}/unlock", locked.id));
+        let reply = send(&test, Some(&test.token(admin.id)), unlock).await;
+
+        assert_eq!(reply.status, StatusCode::NO_CONTENT);
+        assert!(test.users.throttle_remaining(ThrottleScope::Account, "locked@example.com").await.unwrap().is_none());
+    }
+}
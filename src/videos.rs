@@ -0,0 +1,561 @@
+use std::sync::Arc;
+use actix_web::{
+    HttpResponse, web, get, post, put, delete,
+};
+use crate::AppState;
+use crate::errors::AppError;
+use crate::guards::{ApiKeyAuth, AuthenticatedUser, RequirePermission};
+use crate::metrics::metrics;
+use crate::models::{
+    LIBRARY_READ_SCOPE,
+    VideoForm,
+    VideoRef,
+    VideoList,
+    VideoListEntry,
+    WatchProgress
+};
+
+/// How many in-progress videos the continue watching row returns.
+const CONTINUE_WATCHING_LIMIT: i64 = 20;
+
+
+/// The user a library read is for: the logged in user, or the owner of an
+/// API key granted `library:read`. The session wins, so a browser sending a
+/// stale key still works. Only a missing or invalid credential falls through
+/// to the next one; any other failure is the answer.
+fn library_reader(
+    user: Result<AuthenticatedUser, AppError>,
+    api_key: Result<ApiKeyAuth, AppError>
+)
+-> Result<i32, AppError> {
+    let not_logged_in = match user {
+        Ok(user) => return Ok(user.id()),
+        Err(err @ AppError::Unauthorized(_)) => err,
+        Err(err) => return Err(err),
+    };
+    match api_key {
+        Ok(key) if key.has_scope(LIBRARY_READ_SCOPE) => Ok(key.user_id),
+        Ok(_) => Err(AppError::Forbidden(String::from("API key lacks the library:read scope"))),
+        Err(AppError::Unauthorized(_)) => Err(not_logged_in),
+        Err(err) => Err(err),
+    }
+}
+
+async fn add_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: VideoRef,
+    list: VideoList
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let created = state.videos.add_to_list(user_id, video.video_id, list).await?;
+
+    match created {
+        VideoListEntry::Liked(liked_vid) => {
+            metrics().likes.inc();
+            Ok(HttpResponse::Created().json(liked_vid))
+        }
+        VideoListEntry::Watched(watched_vid) => {
+            metrics().watches.inc();
+            Ok(HttpResponse::Created().json(watched_vid))
+        }
+    }
+}
+
+async fn remove_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: VideoRef,
+    list: VideoList
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = user.id();
+    let deleted = state.videos.remove_from_list(user_id, video.video_id, list).await?;
+
+    if deleted == 0 {
+        Err(AppError::NotFound(String::from("Video not found")))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+#[utoipa::path(
+    request_body = VideoRef,
+    responses(
+        (
+            status = 201,
+            description = "Likes a video for the logged in user",
+            body = LikedVideos
+        ),
+        (
+            status = 409,
+            description = "Video is already liked",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Conflict(String::from("Video is already liked")).problem())
+        ),
+        (
+            status = 404,
+            description = "Video is not in the catalog",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[post("/me/liked")]
+pub async fn like_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: web::Json<VideoRef>
+)
+-> Result<HttpResponse, AppError> {
+    add_video(state, user, video.into_inner(), VideoList::Liked).await
+}
+
+#[utoipa::path(
+    security((), ("api_key" = [])),
+    responses(
+        (
+            status = 200,
+            description = "Fetches the liked videos of the logged in user",
+            body = [Video]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[get("/me/liked")]
+pub async fn liked_videos(
+    state: web::Data<Arc<AppState>>,
+    user: Result<AuthenticatedUser, AppError>,
+    api_key: Result<ApiKeyAuth, AppError>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = library_reader(user, api_key)?;
+    let videos = state.videos.liked(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(videos))
+}
+
+#[utoipa::path(
+    request_body = VideoRef,
+    responses(
+        (
+            status = 204,
+            description = "Removes a video from the liked videos of the logged in user",
+        ),
+        (
+            status = 404,
+            description = "Video was not liked",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+    )
+)]
+#[delete("/me/liked")]
+pub async fn unlike_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: web::Json<VideoRef>
+)
+-> Result<HttpResponse, AppError> {
+    remove_video(state, user, video.into_inner(), VideoList::Liked).await
+}
+
+#[utoipa::path(
+    request_body = VideoRef,
+    responses(
+        (
+            status = 201,
+            description = "Marks a video as watched for the logged in user",
+            body = WatchedVideos
+        ),
+        (
+            status = 404,
+            description = "Video is not in the catalog",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[post("/me/watched")]
+pub async fn watch_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: web::Json<VideoRef>
+)
+-> Result<HttpResponse, AppError> {
+    add_video(state, user, video.into_inner(), VideoList::Watched).await
+}
+
+#[utoipa::path(
+    security((), ("api_key" = [])),
+    responses(
+        (
+            status = 200,
+            description = "Fetches the watched videos of the logged in user",
+            body = [Video]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[get("/me/watched")]
+pub async fn watched_videos(
+    state: web::Data<Arc<AppState>>,
+    user: Result<AuthenticatedUser, AppError>,
+    api_key: Result<ApiKeyAuth, AppError>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = library_reader(user, api_key)?;
+    let videos = state.videos.watched(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(videos))
+}
+
+#[utoipa::path(
+    request_body = WatchProgress,
+    params(
+        ("video_id" = i32, Path, description = "Catalog id of the video being played")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Saves the playback position of the logged in user",
+            body = WatchedVideos
+        ),
+        (
+            status = 400,
+            description = "Position or duration out of range",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+        ),
+        (
+            status = 404,
+            description = "Video is not in the catalog",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+    )
+)]
+#[put("/me/watched/{video_id}")]
+pub async fn update_watch_progress(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    path: web::Path<i32>,
+    progress: web::Json<WatchProgress>
+)
+-> Result<HttpResponse, AppError> {
+    let progress = progress.into_inner();
+    if !progress.is_valid() {
+        return Err(AppError::Validation(String::from("Position must be between 0 and the duration")));
+    }
+    let user_id = user.id();
+    let vid_id = path.into_inner();
+    let watched = state.videos.save_progress(user_id, vid_id, progress).await?;
+
+    Ok(HttpResponse::Ok().json(watched))
+}
+
+#[utoipa::path(
+    security((), ("api_key" = [])),
+    responses(
+        (
+            status = 200,
+            description = "Fetches the unfinished videos of the logged in user, most recent first",
+            body = [ContinueWatching]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+    )
+)]
+#[get("/me/continue-watching")]
+pub async fn continue_watching(
+    state: web::Data<Arc<AppState>>,
+    user: Result<AuthenticatedUser, AppError>,
+    api_key: Result<ApiKeyAuth, AppError>
+)
+-> Result<HttpResponse, AppError> {
+    let user_id = library_reader(user, api_key)?;
+    let videos = state.videos.continue_watching(user_id, CONTINUE_WATCHING_LIMIT).await?;
+
+    Ok(HttpResponse::Ok().json(videos))
+}
+
+#[utoipa::path(
+    request_body = VideoRef,
+    responses(
+        (
+            status = 204,
+            description = "Removes a video from the watched videos of the logged in user",
+        ),
+        (
+            status = 404,
+            description = "Video was not watched",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+    )
+)]
+#[delete("/me/watched")]
+pub async fn unwatch_video(
+    state: web::Data<Arc<AppState>>,
+    user: AuthenticatedUser,
+    video: web::Json<VideoRef>
+)
+-> Result<HttpResponse, AppError> {
+    remove_video(state, user, video.into_inner(), VideoList::Watched).await
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 200,
+            description = "Fetches the whole video catalog",
+            body = [Video]
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the videos:read permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission videos:read")).problem())
+        ),
+    )
+)]
+#[get("/videos", wrap = "RequirePermission(\"videos:read\")")]
+pub async fn list_videos(
+    state: web::Data<Arc<AppState>>
+)
+-> Result<HttpResponse, AppError> {
+    let videos = state.videos.list().await?;
+
+    Ok(HttpResponse::Ok().json(videos))
+}
+
+#[utoipa::path(
+    request_body = VideoForm,
+    responses(
+        (
+            status = 201,
+            description = "Adds a video to the catalog",
+            body = Video
+        ),
+        (
+            status = 400,
+            description = "Empty title, negative duration or a maturity rating over 10 characters",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Title must not be empty")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the videos:write permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission videos:write")).problem())
+        ),
+    )
+)]
+#[post("/videos", wrap = "RequirePermission(\"videos:write\")")]
+pub async fn create_video(
+    state: web::Data<Arc<AppState>>,
+    form: web::Json<VideoForm>
+)
+-> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    form.validate().map_err(AppError::Validation)?;
+    let video = state.videos.create(form).await?;
+
+    Ok(HttpResponse::Created().json(video))
+}
+
+#[utoipa::path(
+    request_body = VideoForm,
+    responses(
+        (
+            status = 200,
+            description = "Replaces a catalog entry",
+            body = Video
+        ),
+        (
+            status = 400,
+            description = "Empty title, negative duration or a maturity rating over 10 characters",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Validation(String::from("Title must not be empty")).problem())
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the videos:write permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission videos:write")).problem())
+        ),
+        (
+            status = 404,
+            description = "Video Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+    )
+)]
+#[put("/videos/{id}", wrap = "RequirePermission(\"videos:write\")")]
+pub async fn update_video(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>,
+    form: web::Json<VideoForm>
+)
+-> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let form = form.into_inner();
+    form.validate().map_err(AppError::Validation)?;
+    let video = state.videos.update(id, form).await?;
+
+    match video {
+        Some(video) => Ok(HttpResponse::Ok().json(video)),
+        None => Err(AppError::NotFound(String::from("Video not found"))),
+    }
+}
+
+#[utoipa::path(
+    responses(
+        (
+            status = 204,
+            description = "Removes a video from the catalog along with its likes and watches",
+        ),
+        (
+            status = 401,
+            description = "Not logged in",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Unauthorized(String::from("Not logged in")).problem())
+        ),
+        (
+            status = 403,
+            description = "Missing the videos:write permission",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::Forbidden(String::from("Missing permission videos:write")).problem())
+        ),
+        (
+            status = 404,
+            description = "Video Not Found",
+            body = ProblemDetails,
+            content_type = "application/problem+json",
+            example = json!(AppError::NotFound(String::from("Video not found")).problem())
+        ),
+    )
+)]
+#[delete("/videos/{id}", wrap = "RequirePermission(\"videos:write\")")]
+pub async fn delete_video(
+    state: web::Data<Arc<AppState>>,
+    path: web::Path<i32>
+)
+-> Result<HttpResponse, AppError> {
+    let id = path.into_inner();
+    let deleted = state.videos.delete(id).await?;
+
+    if deleted == 0 {
+        Err(AppError::NotFound(String::from("Video not found")))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use crate::errors::AppError;
+    use crate::guards::ApiKeyAuth;
+    use crate::models::LIBRARY_READ_SCOPE;
+    use super::library_reader;
+
+    fn reader_key() -> Result<ApiKeyAuth, AppError> {
+        Ok(ApiKeyAuth { key_id: 1, user_id: 7, scopes: vec![String::from(LIBRARY_READ_SCOPE)] })
+    }
+
+    fn unauthorized<T>() -> Result<T, AppError> {
+        Err(AppError::Unauthorized(String::from("Not logged in")))
+    }
+
+    fn internal<T>() -> Result<T, AppError> {
+        Err(AppError::Internal(anyhow::Error::msg("connection refused")))
+    }
+
+    fn status(reader: Result<i32, AppError>) -> StatusCode {
+        reader.unwrap_err().status_code()
+    }
+
+    #[test]
+    fn an_api_key_stands_in_for_a_missing_login() {
+        assert_eq!(library_reader(unauthorized(), reader_key()).unwrap(), 7);
+        assert_eq!(status(library_reader(unauthorized(), unauthorized())), StatusCode::UNAUTHORIZED);
+        let no_scope = Ok(ApiKeyAuth { key_id: 1, user_id: 7, scopes: Vec::new() });
+        assert_eq!(status(library_reader(unauthorized(), no_scope)), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn failures_other_than_a_missing_credential_are_not_hidden() {
+        assert_eq!(status(library_reader(internal(), reader_key())), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(status(library_reader(unauthorized(), internal())), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use actix_web::{http::StatusCode, test};
+use serde_json::{json, Value};
+use actix_diesel::{
+    db_actions::{create_password_reset, record_attempt, throttle_remaining},
+    errors::ProblemDetails,
+    throttle::ThrottleScope,
+    ultils::utils::hash_token,
+};
+use common::{spawn_app, CookieJar, PASSWORD, USER_ROLE};
+
+const NEW_PASSWORD: &str = "a brand new passphrase";
+
+#[actix_web::test]
+async fn sign_up_creates_an_account() {
+    let Some(app) = spawn_app().await else { return };
+
+    let res = app.sign_up("New.User@Example.com", PASSWORD).await;
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let user: Value = test::read_body_json(res).await;
+    assert_eq!(user["email"], "new.user@example.com");
+    assert!(user.get("password_hash").is_none());
+}
+
+#[actix_web::test]
+async fn sign_up_rejects_a_taken_email() {
+    let Some(app) = spawn_app().await else { return };
+    app.sign_up("taken@example.com", PASSWORD).await;
+
+    let res = app.sign_up("TAKEN@example.com", PASSWORD).await;
+
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let problem: ProblemDetails = test::read_body_json(res).await;
+    assert_eq!(problem.detail, "An account with this email already exists");
+}
+
+#[actix_web::test]
+async fn sign_up_rejects_an_invalid_email() {
+    let Some(app) = spawn_app().await else { return };
+
+    let res = app.sign_up("not an email", PASSWORD).await;
+
+    assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+}
+
+#[actix_web::test]
+async fn login_starts_a_session() {
+    let Some(app) = spawn_app().await else { return };
+    app.sign_up("login@example.com", PASSWORD).await;
+    let mut jar = CookieJar::default();
+
+    let res = app.log_in(&mut jar, "login@example.com", PASSWORD).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(!jar.is_empty());
+    let res = app.send(&mut jar, test::TestRequest::get().uri("/me/sessions")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn login_rejects_a_wrong_password() {
+    let Some(app) = spawn_app().await else { return };
+    app.sign_up("wrong@example.com", PASSWORD).await;
+    let mut jar = CookieJar::default();
+
+    let res = app.log_in(&mut jar, "wrong@example.com", "not the password").await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let problem: ProblemDetails = test::read_body_json(res).await;
+    assert_eq!(problem.detail, "Invalid email or password");
+}
+
+#[actix_web::test]
+async fn login_rejects_an_unknown_account() {
+    let Some(app) = spawn_app().await else { return };
+    let mut jar = CookieJar::default();
+
+    let res = app.log_in(&mut jar, "nobody@example.com", PASSWORD).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn logout_ends_the_session() {
+    let Some(app) = spawn_app().await else { return };
+    app.sign_up("logout@example.com", PASSWORD).await;
+    let mut jar = CookieJar::default();
+    app.log_in(&mut jar, "logout@example.com", PASSWORD).await;
+
+    let res = app.send(&mut jar, test::TestRequest::post().uri("/logout")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.send(&mut jar, test::TestRequest::post().uri("/logout")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = app.send(&mut jar, test::TestRequest::get().uri("/me/sessions")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn logout_requires_a_session() {
+    let Some(app) = spawn_app().await else { return };
+
+    let res = app.send(&mut CookieJar::default(), test::TestRequest::post().uri("/logout")).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn password_reset_ends_existing_sessions() {
+    let Some(app) = spawn_app().await else { return };
+    let (id, mut jar) = app.user_with_role(USER_ROLE).await;
+    let res = app.send(&mut jar, test::TestRequest::get().uri("/me/sessions")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    create_password_reset(&mut app.db.pool.get().unwrap(), id, &hash_token("reset-token"), 30).unwrap();
+
+    let req = test::TestRequest::post()
+        .uri("/password/reset")
+        .set_json(json!({ "token": "reset-token", "password": NEW_PASSWORD }));
+    let res = app.send(&mut CookieJar::default(), req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.send(&mut jar, test::TestRequest::get().uri("/me/sessions")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn password_reset_lifts_the_login_lockout() {
+    let Some(app) = spawn_app().await else { return };
+    let res = app.sign_up("locked@example.com", PASSWORD).await;
+    let user: Value = test::read_body_json(res).await;
+    let id = user["id"].as_i64().unwrap() as i32;
+    let mut conn = app.db.pool.get().unwrap();
+    for _ in 0..ThrottleScope::Account.policy().lockout_after {
+        record_attempt(&mut conn, ThrottleScope::Account, "locked@example.com").unwrap();
+    }
+    assert!(throttle_remaining(&mut conn, ThrottleScope::Account, "locked@example.com").unwrap().is_some());
+    create_password_reset(&mut conn, id, &hash_token("reset-token"), 30).unwrap();
+    drop(conn);
+
+    let req = test::TestRequest::post()
+        .uri("/password/reset")
+        .set_json(json!({ "token": "reset-token", "password": NEW_PASSWORD }));
+    let res = app.send(&mut CookieJar::default(), req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.log_in(&mut CookieJar::default(), "locked@example.com", NEW_PASSWORD).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
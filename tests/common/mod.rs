@@ -0,0 +1,199 @@
+//! Shared setup of the integration tests. Every test gets a schema of its
+//! own in the database of `DATABASE_URL`, migrated from scratch and dropped
+//! afterwards, and talks to the same `App` the server runs. Tests return
+//! early when `DATABASE_URL` is not set.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    cookie::{time::Duration, Cookie, Key},
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test, Error,
+};
+use diesel::prelude::*;
+use dotenv::dotenv;
+use serde_json::json;
+use actix_diesel::{
+    AppState, DbPool, connect,
+    app::build_app,
+    migrations::run_migrations,
+    rate_limit::{MemoryStore, RateLimitConfig, RateLimiter},
+    schema::users,
+    settings::DatabaseSettings,
+    ultils::utils::generate_key,
+};
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Role ids seeded by the migrations.
+pub const USER_ROLE: i32 = 1;
+pub const MODERATOR_ROLE: i32 = 2;
+pub const ADMIN_ROLE: i32 = 3;
+
+/// A freshly migrated schema, dropped with everything in it on drop.
+pub struct TestDb {
+    pub pool: DbPool,
+    /// Points at the schema, for pools of other kinds
+    pub settings: DatabaseSettings,
+    database_url: String,
+    schema: String,
+}
+
+impl TestDb {
+    pub fn create() -> Option<Self> {
+        dotenv().ok();
+        let database_url = env::var("DATABASE_URL").ok()?;
+        let schema = format!("test_{}", generate_key().to_lowercase());
+        let mut conn = PgConnection::establish(&database_url).expect("Failed to connect to DATABASE_URL");
+        diesel::sql_query(format!("CREATE SCHEMA {}", schema))
+            .execute(&mut conn)
+            .expect("Failed to create test schema");
+
+        // Unqualified names, the migrations' included, resolve to the schema.
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        let settings = DatabaseSettings {
+            url: format!("{}{}options=-csearch_path%3D{}", database_url, separator, schema),
+            pool_size: 3,
+            run_migrations: true,
+        };
+        let pool = connect(&settings);
+        let db = TestDb { pool, settings, database_url, schema };
+        run_migrations(&mut db.pool.get().unwrap()).expect("Failed to migrate test schema");
+        Some(db)
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if let Ok(mut conn) = PgConnection::establish(&self.database_url) {
+            let _ = diesel::sql_query(format!("DROP SCHEMA {} CASCADE", self.schema)).execute(&mut conn);
+        }
+    }
+}
+
+/// The cookies a client was handed, sent back with its next requests.
+#[derive(Default)]
+pub struct CookieJar {
+    cookies: HashMap<String, Cookie<'static>>,
+}
+
+impl CookieJar {
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    fn store<B>(&mut self, res: &ServiceResponse<B>) {
+        for cookie in res.response().cookies() {
+            if cookie.value().is_empty() || cookie.max_age() == Some(Duration::ZERO) {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies.insert(cookie.name().to_string(), cookie.into_owned());
+            }
+        }
+    }
+
+    fn attach(&self, mut req: test::TestRequest) -> test::TestRequest {
+        for cookie in self.cookies.values() {
+            req = req.cookie(cookie.clone());
+        }
+        req
+    }
+}
+
+pub struct TestApp<S> {
+    pub service: S,
+    pub state: Arc<AppState>,
+    pub db: TestDb,
+}
+
+/// Builds the app over a new schema. Email verification is off so accounts
+/// can log in right after signing up, and bcrypt runs at its lowest cost.
+pub async fn spawn_app() -> Option<TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>> {
+    spawn_app_with_limits(RateLimitConfig::default()).await
+}
+
+/// `spawn_app` with the rate limits of `config`.
+pub async fn spawn_app_with_limits(
+    config: RateLimitConfig
+) -> Option<TestApp<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>>> {
+    let db = TestDb::create()?;
+    let state = AppState::builder().postgres(&db.settings, db.pool.clone()).build();
+    let rate_limiter = RateLimiter::new(config, Arc::new(MemoryStore::default()));
+    let service = test::init_service(build_app(state.clone(), Key::generate(), rate_limiter)).await;
+
+    Some(TestApp { service, state, db })
+}
+
+impl<S, B> TestApp<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    /// Sends a request with the cookies of `jar` and keeps the ones it gets
+    /// back.
+    pub async fn send(&self, jar: &mut CookieJar, req: test::TestRequest) -> ServiceResponse<B> {
+        let res = test::call_service(&self.service, jar.attach(req).to_request()).await;
+        jar.store(&res);
+        res
+    }
+
+    pub async fn sign_up(&self, email: &str, password: &str) -> ServiceResponse<B> {
+        let req = test::TestRequest::post()
+            .uri("/signup")
+            .set_json(json!({ "email": email, "password": password }));
+        self.send(&mut CookieJar::default(), req).await
+    }
+
+    pub async fn log_in(&self, jar: &mut CookieJar, email: &str, password: &str) -> ServiceResponse<B> {
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(json!({ "email": email, "password": password }));
+        self.send(jar, req).await
+    }
+
+    /// Signs up a new account with `PASSWORD`, gives it `role_id` and logs
+    /// it in. Returns its id and the jar holding its session.
+    pub async fn user_with_role(&self, role_id: i32) -> (i32, CookieJar) {
+        let email = format!("user-{}@example.com", generate_key().to_lowercase());
+        let res = self.sign_up(&email, PASSWORD).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let user: serde_json::Value = test::read_body_json(res).await;
+        let id = user["id"].as_i64().unwrap() as i32;
+
+        diesel::update(users::table.find(id))
+            .set(users::role_id.eq(role_id))
+            .execute(&mut self.db.pool.get().unwrap())
+            .unwrap();
+
+        let mut jar = CookieJar::default();
+        let res = self.log_in(&mut jar, &email, PASSWORD).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        (id, jar)
+    }
+
+    /// Adds a 100 second video to the catalog through `moderator`, who needs
+    /// `videos:write`. Returns its id.
+    pub async fn catalog_video(&self, moderator: &mut CookieJar, title: &str) -> i64 {
+        let req = test::TestRequest::post()
+            .uri("/videos")
+            .set_json(json!({ "title": title, "description": "", "duration": 100 }));
+        let res = self.send(moderator, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let video: serde_json::Value = test::read_body_json(res).await;
+        video["id"].as_i64().unwrap()
+    }
+
+    /// Ids of the videos a `GET` of `uri` lists, in order.
+    pub async fn listed_ids(&self, jar: &mut CookieJar, uri: &str) -> Vec<i64> {
+        let res = self.send(jar, test::TestRequest::get().uri(uri)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let videos: Vec<serde_json::Value> = test::read_body_json(res).await;
+        videos.iter().map(|video| video["id"].as_i64().unwrap()).collect()
+    }
+}
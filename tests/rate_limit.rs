@@ -0,0 +1,79 @@
+mod common;
+
+use actix_web::{http::StatusCode, test};
+use diesel::prelude::*;
+use serde_json::{json, Value};
+use actix_diesel::db_actions::{apply_rate_limit, sweep_rate_limits};
+use actix_diesel::rate_limit::{Algorithm, KeySource, RateLimitConfig, RatePolicy, RoutePolicy};
+use actix_diesel::schema::rate_limits;
+use common::{spawn_app_with_limits, CookieJar, TestDb, USER_ROLE};
+
+/// One request per minute on `GET /me/liked`, counted per API key.
+fn one_per_api_key() -> RateLimitConfig {
+    RateLimitConfig {
+        routes: vec![RoutePolicy {
+            path: String::from("/me/liked"),
+            methods: vec![String::from("GET")],
+            policy: RatePolicy {
+                algorithm: Algorithm::SlidingWindow,
+                key: KeySource::ApiKey,
+                limit: 1,
+                window_seconds: 60,
+            },
+        }],
+        ..RateLimitConfig::default()
+    }
+}
+
+fn liked_with_key(key: &str) -> test::TestRequest {
+    test::TestRequest::get().uri("/me/liked").insert_header(("X-API-Key", key))
+}
+
+#[actix_web::test]
+async fn made_up_api_keys_share_the_ip_bucket() {
+    let Some(app) = spawn_app_with_limits(one_per_api_key()).await else { return };
+
+    let res = app.send(&mut CookieJar::default(), liked_with_key("made-up-key-1")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = app.send(&mut CookieJar::default(), liked_with_key("made-up-key-2")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn a_valid_api_key_gets_a_bucket_of_its_own() {
+    let Some(app) = spawn_app_with_limits(one_per_api_key()).await else { return };
+    let (_, mut jar) = app.user_with_role(USER_ROLE).await;
+    let req = test::TestRequest::post()
+        .uri("/me/api-keys")
+        .set_json(json!({ "name": "reader", "scopes": ["library:read"] }));
+    let res = app.send(&mut jar, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = test::read_body_json(res).await;
+    let key = body["key"].as_str().unwrap().to_string();
+
+    let res = app.send(&mut CookieJar::default(), liked_with_key(&key)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.send(&mut CookieJar::default(), liked_with_key("made-up-key")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = app.send(&mut CookieJar::default(), liked_with_key(&key)).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_web::test]
+async fn the_postgres_store_sweeps_expired_counters() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.pool.get().unwrap();
+    let short = RatePolicy { algorithm: Algorithm::TokenBucket, key: KeySource::Ip, limit: 5, window_seconds: 1 };
+    let long = RatePolicy { window_seconds: 60, ..short.clone() };
+    let now = 1_000_000;
+    apply_rate_limit(&mut conn, "short", &short, now).unwrap();
+    apply_rate_limit(&mut conn, "long", &long, now).unwrap();
+
+    assert_eq!(sweep_rate_limits(&mut conn, now + 1_000).unwrap(), 0);
+    assert_eq!(sweep_rate_limits(&mut conn, now + 2_000).unwrap(), 1);
+    let left: Vec<String> = rate_limits::table.select(rate_limits::key).load(&mut conn).unwrap();
+    assert_eq!(left, ["long"]);
+}
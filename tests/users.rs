@@ -0,0 +1,47 @@
+mod common;
+
+use actix_web::{http::StatusCode, test};
+use serde_json::Value;
+use common::{spawn_app, CookieJar, ADMIN_ROLE, MODERATOR_ROLE, USER_ROLE};
+
+#[actix_web::test]
+async fn user_data_requires_a_login() {
+    let Some(app) = spawn_app().await else { return };
+    let (id, _) = app.user_with_role(USER_ROLE).await;
+
+    let req = test::TestRequest::get().uri(&format!("/user/{}", id));
+    let res = app.send(&mut CookieJar::default(), req).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn user_data_requires_the_users_read_permission() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+    let (moderator_id, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+
+    for jar in [&mut user, &mut moderator] {
+        let req = test::TestRequest::get().uri(&format!("/user/{}", moderator_id));
+        let res = app.send(jar, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
+
+#[actix_web::test]
+async fn user_data_answers_admins() {
+    let Some(app) = spawn_app().await else { return };
+    let (id, _) = app.user_with_role(USER_ROLE).await;
+    let (_, mut admin) = app.user_with_role(ADMIN_ROLE).await;
+
+    let req = test::TestRequest::get().uri(&format!("/user/{}", id));
+    let res = app.send(&mut admin, req).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["id"], id);
+    assert_eq!(body["liked_videos"], Value::Array(Vec::new()));
+
+    let res = app.send(&mut admin, test::TestRequest::get().uri("/user/999999")).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use actix_web::{http::StatusCode, test};
+use serde_json::{json, Value};
+use common::{spawn_app, CookieJar, MODERATOR_ROLE, USER_ROLE};
+
+fn new_video() -> test::TestRequest {
+    test::TestRequest::post().uri("/videos").set_json(json!({
+        "title": "Night of the Living Tests",
+        "description": "Black and white",
+        "duration": 5760,
+        "release_year": 1968,
+        "maturity_rating": "R"
+    }))
+}
+
+#[actix_web::test]
+async fn create_video_requires_a_login() {
+    let Some(app) = spawn_app().await else { return };
+
+    let res = app.send(&mut CookieJar::default(), new_video()).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn create_video_requires_the_videos_write_permission() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+
+    let res = app.send(&mut user, new_video()).await;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn create_video_adds_it_to_the_catalog() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+
+    let res = app.send(&mut moderator, new_video()).await;
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let video: Value = test::read_body_json(res).await;
+    assert_eq!(video["title"], "Night of the Living Tests");
+
+    let res = app.send(&mut moderator, test::TestRequest::get().uri("/videos")).await;
+    let videos: Vec<Value> = test::read_body_json(res).await;
+    assert!(videos.iter().any(|listed| listed["id"] == video["id"]));
+}
+
+#[actix_web::test]
+async fn create_video_rejects_an_invalid_body() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+
+    let req = test::TestRequest::post().uri("/videos").set_json(json!({ "title": "No duration" }));
+    let res = app.send(&mut moderator, req).await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn create_and_update_reject_an_invalid_video() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+    let res = app.send(&mut moderator, new_video()).await;
+    let video: Value = test::read_body_json(res).await;
+    let invalid = json!({
+        "title": " ",
+        "description": "",
+        "duration": -1,
+        "maturity_rating": "NOT-RATED-YET"
+    });
+
+    let req = test::TestRequest::post().uri("/videos").set_json(&invalid);
+    let res = app.send(&mut moderator, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let problem: Value = test::read_body_json(res).await;
+    let detail = problem["detail"].as_str().unwrap();
+    assert!(detail.contains("Title"), "{}", detail);
+    assert!(detail.contains("Duration"), "{}", detail);
+    assert!(detail.contains("Maturity rating"), "{}", detail);
+
+    let req = test::TestRequest::put().uri(&format!("/videos/{}", video["id"])).set_json(&invalid);
+    let res = app.send(&mut moderator, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+fn progress(video_id: i64, position: i32, duration: i32) -> test::TestRequest {
+    test::TestRequest::put()
+        .uri(&format!("/me/watched/{}", video_id))
+        .set_json(json!({ "position": position, "duration": duration }))
+}
+
+#[actix_web::test]
+async fn watch_progress_is_saved_and_completes_near_the_end() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+    let video = app.catalog_video(&mut moderator, "Halfway There").await;
+
+    let res = app.send(&mut user, progress(video, 40, 100)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let watched: Value = test::read_body_json(res).await;
+    assert_eq!(watched["position"], 40);
+    assert_eq!(watched["completed"], false);
+
+    let res = app.send(&mut user, progress(video, 95, 100)).await;
+    let watched: Value = test::read_body_json(res).await;
+    assert_eq!(watched["completed"], true);
+
+    let res = app.send(&mut user, progress(video, i32::MAX, i32::MAX)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let watched: Value = test::read_body_json(res).await;
+    assert_eq!(watched["completed"], true);
+
+    let res = app.send(&mut user, progress(video, 101, 100)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = app.send(&mut user, progress(video + 1000, 1, 100)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = app.send(&mut CookieJar::default(), progress(video, 1, 100)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn continue_watching_lists_unfinished_videos_most_recent_first() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+    let first = app.catalog_video(&mut moderator, "First").await;
+    let second = app.catalog_video(&mut moderator, "Second").await;
+    let finished = app.catalog_video(&mut moderator, "Finished").await;
+
+    app.send(&mut user, progress(first, 10, 100)).await;
+    app.send(&mut user, progress(second, 10, 100)).await;
+    app.send(&mut user, progress(finished, 99, 100)).await;
+    app.send(&mut user, progress(first, 20, 100)).await;
+
+    let res = app.send(&mut user, test::TestRequest::get().uri("/me/continue-watching")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let rows: Vec<Value> = test::read_body_json(res).await;
+    let order: Vec<i64> = rows.iter().map(|row| row["video"]["id"].as_i64().unwrap()).collect();
+    assert_eq!(order, [first, second]);
+    assert_eq!(rows[0]["progress"]["position"], 20);
+}
+
+fn video_ref(method: test::TestRequest, uri: &str, video_id: i64) -> test::TestRequest {
+    method.uri(uri).set_json(json!({ "video_id": video_id }))
+}
+
+#[actix_web::test]
+async fn liking_a_video_twice_is_a_conflict() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+    let video = app.catalog_video(&mut moderator, "Liked Once").await;
+
+    let res = app.send(&mut user, video_ref(test::TestRequest::post(), "/me/liked", video)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = app.send(&mut user, video_ref(test::TestRequest::post(), "/me/liked", video)).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(app.listed_ids(&mut user, "/me/liked").await, [video]);
+
+    let res = app.send(&mut user, video_ref(test::TestRequest::delete(), "/me/liked", video)).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert!(app.listed_ids(&mut user, "/me/liked").await.is_empty());
+    let res = app.send(&mut user, video_ref(test::TestRequest::delete(), "/me/liked", video)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn liking_needs_a_login_and_a_catalog_video() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+
+    let res = app.send(&mut CookieJar::default(), video_ref(test::TestRequest::post(), "/me/liked", 1)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = app.send(&mut user, video_ref(test::TestRequest::post(), "/me/liked", 999_999)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn watched_videos_are_listed_until_removed() {
+    let Some(app) = spawn_app().await else { return };
+    let (_, mut moderator) = app.user_with_role(MODERATOR_ROLE).await;
+    let (_, mut user) = app.user_with_role(USER_ROLE).await;
+    let video = app.catalog_video(&mut moderator, "Seen It").await;
+
+    let res = app.send(&mut user, video_ref(test::TestRequest::post(), "/me/watched", video)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let watched: Value = test::read_body_json(res).await;
+    assert_eq!(watched["completed"], true);
+    let res = app.send(&mut user, video_ref(test::TestRequest::post(), "/me/watched", video)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(app.listed_ids(&mut user, "/me/watched").await, [video]);
+
+    let res = app.send(&mut user, video_ref(test::TestRequest::delete(), "/me/watched", video)).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert!(app.listed_ids(&mut user, "/me/watched").await.is_empty());
+}